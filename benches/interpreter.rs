@@ -0,0 +1,51 @@
+// Compares the interpreter decoding every instruction it runs, the one that
+// keeps them decoded and the JIT. Run with `cargo bench`.
+use std::time::Instant;
+
+use c8rs::c8::{Chip8, Config};
+use c8rs::jit::Backend;
+
+const INSTRUCTIONS: u32 = 20_000_000;
+
+// A bit of everything: arithmetic, BCD, loads and sprites, in a loop.
+const PROGRAM: [u8; 22] = [
+  0x60, 0x00, // 200: V0 = 0
+  0x70, 0x01, // 202: V0 += 1
+  0x81, 0x04, // 204: V1 += V0
+  0x82, 0x13, // 206: V2 ^= V1
+  0xA3, 0x00, // 208: I = 300
+  0xF2, 0x33, // 20A: BCD of V2 at I
+  0xF2, 0x65, // 20C: Load V0-V2 from I
+  0xD0, 0x15, // 20E: Draw a sprite at V0, V1
+  0x30, 0x00, // 210: Skip if V0 = 0
+  0x12, 0x02, // 212: Jump to 202
+  0x12, 0x00  // 214: Jump to 200
+];
+
+// Instructions per second.
+fn run(backend: Backend, decode_cache: bool) -> f64 {
+  let mut chip8 = Chip8::with_config(Config { decode_cache, ..Config::default() });
+  chip8.load_bytes(&PROGRAM);
+  let keys = [false; 16];
+
+  let start = Instant::now();
+  let mut count = 0;
+  while count < INSTRUCTIONS {
+    count += match backend {
+      Backend::Interpreter => chip8.fde_loop(&keys),
+      Backend::Jit => chip8.run_block(&keys)
+    };
+  }
+  count as f64 / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+  let runs = [
+    ("decoding every time", Backend::Interpreter, false),
+    ("decode cache", Backend::Interpreter, true),
+    ("jit", Backend::Jit, true)
+  ];
+  for (name, backend, decode_cache) in runs {
+    println!("{:<20} {:>7.1} million instructions per second", name, run(backend, decode_cache) / 1e6);
+  }
+}
@@ -0,0 +1,17 @@
+#![no_main]
+
+// cargo fuzz run differential
+//
+// Random programs and key presses, run on Chip8 and on the reference
+// interpreter in src/reference.rs. The first register, byte of memory or
+// pixel they disagree on is the crash.
+
+use libfuzzer_sys::fuzz_target;
+
+use c8rs_fuzz::Input;
+
+fuzz_target!(|input: Input| {
+  if let Err(divergence) = c8rs_fuzz::run(&input) {
+    panic!("{}", divergence);
+  }
+});
@@ -0,0 +1,199 @@
+// Runs random programs on Chip8 and on the reference interpreter side by
+// side, and finds the first instruction after which they disagree.
+
+pub mod reference;
+
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use arbitrary::{Arbitrary, Unstructured};
+
+use c8rs::c8::{Chip8, Config, Fault, Quirks};
+use c8rs::{SCREEN_COLUMNS, SCREEN_LINES};
+
+use reference::Reference;
+
+// Instructions run per input.
+const STEPS: usize = 1000;
+
+#[derive(Debug)]
+pub struct Input {
+  // One bit per quirk.
+  pub quirks: u8,
+  // Instructions per frame. The timers count down and vblank comes at the
+  // end of every frame.
+  pub frame_length: u8,
+  // The keys held down (bit k is key k) for each instruction, over and over.
+  pub keys: [u16; 8],
+  // Loaded at 0x200, and cut down to what fits.
+  pub program: Vec<u8>
+}
+
+// The program is whatever's left, byte for byte, so that the fuzzer mutates
+// instructions directly.
+impl<'a> Arbitrary<'a> for Input {
+  fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Input> {
+    Ok(Input {
+      quirks: u.arbitrary()?,
+      frame_length: u.arbitrary()?,
+      keys: u.arbitrary()?,
+      program: u.bytes(u.len())?.to_vec()
+    })
+  }
+}
+
+impl Input {
+  fn quirks(&self) -> (Quirks, reference::Quirks) {
+    let bit = |n: u8| self.quirks & 1 << n != 0;
+    let quirks = Quirks { shift: bit(0), load_store: bit(1), jump: bit(2), logic: bit(3), clip: bit(4), vblank: bit(5) };
+    let reference = reference::Quirks {
+      shift: quirks.shift,
+      load_store: quirks.load_store,
+      jump: quirks.jump,
+      logic: quirks.logic,
+      clip: quirks.clip,
+      vblank: quirks.vblank
+    };
+    (quirks, reference)
+  }
+}
+
+// Where the two went different ways.
+#[derive(Debug)]
+pub struct Divergence {
+  pub step: usize,
+  pub pc: u16,
+  pub instruction: u16,
+  pub what: String
+}
+
+impl fmt::Display for Divergence {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "After instruction {} ({:04X} at {:03X}): {}", self.step, self.instruction, self.pc, self.what)
+  }
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+  payload.downcast_ref::<&str>().map(|message| message.to_string())
+    .or_else(|| payload.downcast_ref::<String>().cloned())
+    .unwrap_or_else(|| "a panic".to_string())
+}
+
+// The first thing Chip8 (on the left) and the reference (on the right) don't
+// agree on: registers first, then memory, then the display.
+fn compare(chip8: &Chip8, reference: &Reference) -> Option<String> {
+  if chip8.pc() != reference.pc {
+    return Some(format!("PC is {:03X}, not {:03X}", chip8.pc(), reference.pc));
+  }
+  for (r, (left, right)) in chip8.registers().iter().zip(reference.v.iter()).enumerate() {
+    if left != right {
+      return Some(format!("V{:X} is {:02X}, not {:02X}", r, left, right));
+    }
+  }
+  if chip8.index() != reference.i {
+    return Some(format!("I is {:03X}, not {:03X}", chip8.index(), reference.i));
+  }
+  if chip8.stack() != reference.stack {
+    return Some(format!("The stack is {:03X?}, not {:03X?}", chip8.stack(), reference.stack));
+  }
+  if (chip8.dt(), chip8.st()) != (reference.dt, reference.st) {
+    return Some(format!("DT and ST are {:02X} {:02X}, not {:02X} {:02X}", chip8.dt(), chip8.st(), reference.dt, reference.st));
+  }
+  if chip8.memory() != &reference.memory {
+    let address = (0..0x1000).find(|address| chip8.memory()[*address] != reference.memory[*address]).unwrap();
+    return Some(format!("Memory at {:03X} is {:02X}, not {:02X}", address, chip8.memory()[address], reference.memory[address]));
+  }
+  for line in 0..SCREEN_LINES {
+    let pixels = reference.display[line].iter().enumerate().fold(0u64, |pixels, (column, on)| pixels | (*on as u64) << column);
+    let wrong = chip8.get_display().line(line) ^ pixels;
+    if wrong != 0 {
+      let column = wrong.trailing_zeros() as usize;
+      return Some(format!("The pixel at {}, {} is {}", column, line, if reference.display[line][column] { "off" } else { "on" }));
+    }
+  }
+  None
+}
+
+// Runs input on both, comparing them after every instruction.
+pub fn run(input: &Input) -> Result<(), Divergence> {
+  let (quirks, reference_quirks) = input.quirks();
+  let program = &input.program[..input.program.len().min(0x1000 - 0x200)];
+  let mut chip8 = Chip8::with_config(Config { quirks, seed: Some(0), ..Config::default() });
+  chip8.load_bytes(program);
+
+  let mut display = [[false; SCREEN_COLUMNS]; SCREEN_LINES];
+  for (line, pixels) in display.iter_mut().enumerate() {
+    for (column, pixel) in pixels.iter_mut().enumerate() {
+      *pixel = chip8.get_display().pixel(line, column);
+    }
+  }
+  let mut reference = Reference::new(*chip8.memory(), display, reference_quirks);
+  let frame_length = input.frame_length.max(1) as usize;
+
+  // Panics are divergences, not shown.
+  let hook = panic::take_hook();
+  panic::set_hook(Box::new(|_| {}));
+  let result = (|| {
+    for step in 0..STEPS {
+      let keys = input.keys[step % input.keys.len()];
+      let mut pressed = [false; 16];
+      for (key, down) in pressed.iter_mut().enumerate() {
+        *down = keys & 1 << key != 0;
+      }
+
+      let pc = reference.pc;
+      let instruction = (reference.memory[pc as usize & 0xfff] as u16) << 8 | reference.memory[(pc as usize + 1) & 0xfff] as u16;
+      let divergence = |what: String| Divergence { step, pc, instruction, what };
+
+      let ran = panic::catch_unwind(AssertUnwindSafe(|| chip8.fde_loop(&pressed)));
+      // Cxkk is random, so the reference gets whatever number Chip8 got. It
+      // still has to fit in the mask.
+      let random = chip8.registers()[(instruction >> 8 & 0xf) as usize];
+      let faulted = reference.step(keys, random);
+      if let Err(payload) = ran {
+        return Err(divergence(format!("Chip8 panicked: {}", panic_message(&*payload))));
+      }
+      match (chip8.fault(), faulted) {
+        (None, Ok(())) => {},
+        (Some(Fault::StackOverflow), Err(reference::Fault::StackOverflow)) => return Ok(()),
+        (Some(Fault::StackUnderflow), Err(reference::Fault::StackUnderflow)) => return Ok(()),
+        (fault, Ok(())) => return Err(divergence(format!("Chip8 stopped with {:?}, the reference went on", fault))),
+        (fault, Err(reference_fault)) => return Err(divergence(format!("Chip8 stopped with {:?}, the reference with {:?}", fault, reference_fault)))
+      }
+
+      if (step + 1) % frame_length == 0 {
+        chip8.update_timers();
+        chip8.vblank();
+        reference.tick_timers();
+        reference.vblank();
+      }
+      if let Some(what) = compare(&chip8, &reference) {
+        return Err(divergence(what));
+      }
+    }
+    Ok(())
+  })();
+  panic::set_hook(hook);
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use rand::{Rng, SeedableRng};
+  use rand::rngs::StdRng;
+
+  #[test]
+  fn random_inputs_agree() {
+    let mut rng = StdRng::seed_from_u64(49);
+    for _ in 0..300 {
+      let bytes: Vec<u8> = (0..rng.gen_range(0..0x400)).map(|_| rng.gen()).collect();
+      if let Ok(input) = Input::arbitrary(&mut Unstructured::new(&bytes)) {
+        if let Err(divergence) = run(&input) {
+          panic!("{}\n{:?}", divergence, input);
+        }
+      }
+    }
+  }
+}
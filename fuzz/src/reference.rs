@@ -0,0 +1,241 @@
+// A chip8 written straight from the spec, as plainly as possible, to check
+// the emulator against. It does the same as Chip8 with the default Config:
+// the quirks can be switched, but the memory map, font address and stack
+// depth can't. Nothing here is shared with the emulator, so the same mistake
+// would have to be made twice to go unnoticed.
+
+pub const WIDTH: usize = 64;
+pub const HEIGHT: usize = 32;
+pub const STACK_DEPTH: usize = 16;
+
+// Where the small font is, followed by the big one.
+const FONT_ADDRESS: usize = 0x000;
+const BIG_FONT_ADDRESS: usize = FONT_ADDRESS + 5 * 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+  pub shift: bool,
+  pub load_store: bool,
+  pub jump: bool,
+  pub logic: bool,
+  pub clip: bool,
+  pub vblank: bool
+}
+
+// Instructions that can't go on, which Chip8 stops with a fault on too.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+  StackOverflow,
+  StackUnderflow
+}
+
+#[derive(Clone)]
+pub struct Reference {
+  pub memory: [u8; 0x1000],
+  pub v: [u8; 16],
+  pub i: u16,
+  pub pc: u16,
+  pub stack: Vec<u16>,
+  pub dt: u8,
+  pub st: u8,
+  pub display: [[bool; WIDTH]; HEIGHT],
+  pub quirks: Quirks,
+  drawn_this_frame: bool
+}
+
+impl Reference {
+  // Starts at 0x200, with memory and the display as given (the font is in
+  // memory already).
+  pub fn new(memory: [u8; 0x1000], display: [[bool; WIDTH]; HEIGHT], quirks: Quirks) -> Reference {
+    Reference {
+      memory,
+      v: [0; 16],
+      i: 0,
+      pc: 0x200,
+      stack: Vec::new(),
+      dt: 0,
+      st: 0,
+      display,
+      quirks,
+      drawn_this_frame: false
+    }
+  }
+
+  fn read(&self, address: usize) -> u8 {
+    self.memory[address % 0x1000]
+  }
+
+  fn write(&mut self, address: usize, value: u8) {
+    self.memory[address % 0x1000] = value;
+  }
+
+  pub fn vblank(&mut self) {
+    self.drawn_this_frame = false;
+  }
+
+  pub fn tick_timers(&mut self) {
+    self.dt = self.dt.saturating_sub(1);
+    self.st = self.st.saturating_sub(1);
+  }
+
+  // Runs the instruction at PC with keys held down (bit k is key k).
+  // random is what Cxkk gets, before the mask.
+  //
+  // One arm per opcode, like the spec's table, even where a skip's condition
+  // could go in a match guard.
+  #[allow(clippy::collapsible_match)]
+  pub fn step(&mut self, keys: u16, random: u8) -> Result<(), Fault> {
+    let pc = self.pc as usize;
+    let high = self.read(pc);
+    let low = self.read(pc + 1);
+    let instruction = (high as u16) << 8 | low as u16;
+    self.pc += 2;
+
+    let x = (high & 0xf) as usize;
+    let y = (low >> 4) as usize;
+    let n = low & 0xf;
+    let kk = low;
+    let nnn = instruction & 0xfff;
+
+    match high >> 4 {
+      0x0 => match instruction {
+        0x00E0 => self.display = [[false; WIDTH]; HEIGHT],
+        0x00EE => self.pc = self.stack.pop().ok_or(Fault::StackUnderflow)?,
+        _ => {}
+      },
+      0x1 => self.pc = nnn,
+      0x2 => {
+        if self.stack.len() == STACK_DEPTH {
+          return Err(Fault::StackOverflow);
+        }
+        self.stack.push(self.pc);
+        self.pc = nnn;
+      },
+      0x3 => if self.v[x] == kk { self.pc += 2 },
+      0x4 => if self.v[x] != kk { self.pc += 2 },
+      0x5 if n == 0 => if self.v[x] == self.v[y] { self.pc += 2 },
+      0x6 => self.v[x] = kk,
+      0x7 => self.v[x] = self.v[x].wrapping_add(kk),
+      0x8 => self.arithmetic(x, y, n),
+      0x9 if n == 0 => if self.v[x] != self.v[y] { self.pc += 2 },
+      0xA => self.i = nnn,
+      0xB => {
+        let offset = if self.quirks.jump { self.v[x] } else { self.v[0] };
+        self.pc = nnn + offset as u16;
+      },
+      0xC => self.v[x] = random & kk,
+      0xD => self.draw(x, y, n),
+      0xE => match kk {
+        0x9E => if keys & 1 << (self.v[x] & 0xf) != 0 { self.pc += 2 },
+        0xA1 => if keys & 1 << (self.v[x] & 0xf) == 0 { self.pc += 2 },
+        _ => {}
+      },
+      0xF => match kk {
+        0x07 => self.v[x] = self.dt,
+        0x0A => match (0..16).find(|key| keys & 1 << key != 0) {
+          Some(key) => self.v[x] = key as u8,
+          None => self.pc -= 2
+        },
+        0x15 => self.dt = self.v[x],
+        0x18 => self.st = self.v[x],
+        0x1E => {
+          let sum = self.i as u32 + self.v[x] as u32;
+          self.i = sum.min(0xffff) as u16;
+          if sum > 0xfff {
+            self.v[0xf] = 1;
+          }
+        },
+        0x29 => self.i = (FONT_ADDRESS + (self.v[x] & 0xf) as usize * 5) as u16,
+        0x30 => self.i = (BIG_FONT_ADDRESS + (self.v[x] & 0xf) as usize * 10) as u16,
+        0x33 => {
+          let value = self.v[x];
+          let i = self.i as usize;
+          self.write(i, value / 100);
+          self.write(i + 1, value / 10 % 10);
+          self.write(i + 2, value % 10);
+        },
+        0x55 => {
+          for r in 0..=x {
+            self.write(self.i as usize + r, self.v[r]);
+          }
+          if !self.quirks.load_store {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+          }
+        },
+        0x65 => {
+          for r in 0..=x {
+            self.v[r] = self.read(self.i as usize + r);
+          }
+          if !self.quirks.load_store {
+            self.i = self.i.wrapping_add(x as u16 + 1);
+          }
+        },
+        // F002 and Fx3A only change the sound.
+        _ => {}
+      },
+      _ => {}
+    }
+    // PC goes around the end of memory, like every other address.
+    self.pc &= 0xfff;
+    Ok(())
+  }
+
+  fn arithmetic(&mut self, x: usize, y: usize, n: u8) {
+    let (vx, vy) = (self.v[x], self.v[y]);
+    // The result goes in Vx, and then the flag in VF.
+    let (result, flag) = match n {
+      0x0 => (vy, None),
+      0x1 => (vx | vy, if self.quirks.logic { Some(0) } else { None }),
+      0x2 => (vx & vy, if self.quirks.logic { Some(0) } else { None }),
+      0x3 => (vx ^ vy, if self.quirks.logic { Some(0) } else { None }),
+      0x4 => (vx.wrapping_add(vy), Some((vx as u16 + vy as u16 > 0xff) as u8)),
+      0x5 => (vx.wrapping_sub(vy), Some((vx >= vy) as u8)),
+      0x6 => {
+        let source = if self.quirks.shift { vx } else { vy };
+        (source >> 1, Some(source & 1))
+      },
+      0x7 => (vy.wrapping_sub(vx), Some((vy >= vx) as u8)),
+      0xE => {
+        let source = if self.quirks.shift { vx } else { vy };
+        (source << 1, Some(source >> 7))
+      },
+      _ => return
+    };
+    self.v[x] = result;
+    if let Some(flag) = flag {
+      self.v[0xf] = flag;
+    }
+  }
+
+  fn draw(&mut self, x: usize, y: usize, n: u8) {
+    if self.quirks.vblank {
+      if self.drawn_this_frame {
+        self.pc -= 2;
+        return;
+      }
+      self.drawn_this_frame = true;
+    }
+
+    let left = self.v[x] as usize % WIDTH;
+    let top = self.v[y] as usize % HEIGHT;
+    self.v[0xf] = 0;
+    for row in 0..n as usize {
+      if self.quirks.clip && top + row >= HEIGHT {
+        break;
+      }
+      let sprite = self.read(self.i as usize + row);
+      for bit in 0..8 {
+        if self.quirks.clip && left + bit >= WIDTH {
+          break;
+        }
+        if sprite & 0x80 >> bit != 0 {
+          let pixel = &mut self.display[(top + row) % HEIGHT][(left + bit) % WIDTH];
+          if *pixel {
+            self.v[0xf] = 1;
+          }
+          *pixel = !*pixel;
+        }
+      }
+    }
+  }
+}
@@ -0,0 +1,446 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write, stdout};
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+
+use crate::TIMER_HZ;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AudioBackend {
+  // Ring the terminal bell whenever the tone starts.
+  Bell,
+  // Pipe raw samples into an external player (aplay by default).
+  Pipe,
+  // Write the samples into a WAV file.
+  Wav,
+  // No sound at all.
+  None
+}
+
+// Something that can play (or store) 16-bit mono samples.
+pub trait AudioSink {
+  fn play(&mut self, samples: &[i16]) -> io::Result<()>;
+
+  // Called once no more samples are coming.
+  fn finish(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+// Generates a square wave, keeping track of its phase so that consecutive
+// chunks of samples join without clicks.
+pub struct SquareWave {
+  frequency: f32,
+  amplitude: f32,
+  sample_rate: u32,
+  phase: f32
+}
+
+// volume goes from 0.0 (silent) to 1.0 (full scale).
+fn amplitude(volume: f32) -> f32 {
+  volume.clamp(0.0, 1.0) * i16::MAX as f32
+}
+
+impl SquareWave {
+  pub fn new(frequency: f32, volume: f32, sample_rate: u32) -> SquareWave {
+    SquareWave {
+      frequency,
+      amplitude: amplitude(volume),
+      sample_rate,
+      phase: 0.0
+    }
+  }
+
+  // Appends count samples to out. When the tone is off, the samples are
+  // silent and the phase is reset, so every beep starts the same way.
+  pub fn generate(&mut self, active: bool, count: usize, out: &mut Vec<i16>) {
+    if !active {
+      self.phase = 0.0;
+      out.extend(std::iter::repeat_n(0, count));
+      return;
+    }
+
+    let step = self.frequency / self.sample_rate as f32;
+    for _ in 0..count {
+      let sample = if self.phase < 0.5 { self.amplitude } else { -self.amplitude };
+      out.push(sample as i16);
+      self.phase = (self.phase + step).fract();
+    }
+  }
+}
+
+// Rate, in bits per second, at which XO-CHIP plays the audio pattern for a
+// given pitch register value. 64 is 4000Hz, and every 48 steps double it.
+pub fn pattern_rate(pitch: u8) -> f64 {
+  4000.0 * 2f64.powf((pitch as f64 - 64.0) / 48.0)
+}
+
+const PATTERN_BITS: f64 = 128.0;
+
+// Plays the XO-CHIP 128-bit audio pattern. Set bits are high and clear bits
+// are low, and the pattern loops for as long as the sound timer is running.
+//
+// The pattern rate has nothing to do with the output sample rate (and can go
+// above it), so every output sample is the average of the pattern over the
+// time that sample covers. This is a box filter, which is enough to keep the
+// aliasing of a 1-bit signal down.
+pub struct PatternSynth {
+  amplitude: f32,
+  sample_rate: u32,
+  // Position in the pattern, in bits.
+  position: f64
+}
+
+impl PatternSynth {
+  pub fn new(volume: f32, sample_rate: u32) -> PatternSynth {
+    PatternSynth {
+      amplitude: amplitude(volume),
+      sample_rate,
+      position: 0.0
+    }
+  }
+
+  pub fn generate(&mut self, active: bool, pattern: &[u8; 0x10], pitch: u8, count: usize, out: &mut Vec<i16>) {
+    if !active {
+      self.position = 0.0;
+      out.extend(std::iter::repeat_n(0, count));
+      return;
+    }
+
+    let step = pattern_rate(pitch) / self.sample_rate as f64;
+    for _ in 0..count {
+      let mut remaining = step;
+      let mut sum = 0.0;
+      while remaining > 1e-9 {
+        let bit = self.position as usize % PATTERN_BITS as usize;
+        let level = if pattern[bit / 8] & (0x80 >> (bit % 8)) != 0 { 1.0 } else { -1.0 };
+
+        // Stay in this bit until it ends, or the sample does.
+        let span = (self.position.floor() + 1.0 - self.position).min(remaining);
+        sum += level * span;
+        self.position += span;
+        remaining -= span;
+      }
+      self.position %= PATTERN_BITS;
+
+      out.push((sum / step * self.amplitude as f64).round() as i16);
+    }
+  }
+}
+
+// Turns sound timer ticks into samples and hands them to a sink.
+pub struct Audio {
+  tone: SquareWave,
+  pattern: PatternSynth,
+  sink: Box<dyn AudioSink>,
+  sample_rate: u32,
+  // The sample rate isn't a multiple of TIMER_HZ, so the samples that don't
+  // fit in a tick are carried over to the next one.
+  sample_clock: u64,
+  buffer: Vec<i16>
+}
+
+impl Audio {
+  pub fn new(sample_rate: u32, frequency: f32, volume: f32, sink: Box<dyn AudioSink>) -> Audio {
+    Audio {
+      tone: SquareWave::new(frequency, volume, sample_rate),
+      pattern: PatternSynth::new(volume, sample_rate),
+      sink,
+      sample_rate,
+      sample_clock: 0,
+      buffer: Vec::new()
+    }
+  }
+
+  // Generates the samples of one timer tick. sound_on is whatever
+  // Chip8::update_timers returned, and pattern is Chip8::audio_pattern.
+  //
+  // When the sink fails (say, the player quit), the error is returned and
+  // the sound goes nowhere from then on.
+  pub fn tick(&mut self, sound_on: bool, pattern: Option<(&[u8; 0x10], u8)>) -> io::Result<()> {
+    self.sample_clock += self.sample_rate as u64;
+    let count = (self.sample_clock / TIMER_HZ) as usize;
+    self.sample_clock %= TIMER_HZ;
+
+    self.buffer.clear();
+    match pattern {
+      Some((pattern, pitch)) => self.pattern.generate(sound_on, pattern, pitch, count, &mut self.buffer),
+      None => self.tone.generate(sound_on, count, &mut self.buffer)
+    }
+    let played = self.sink.play(&self.buffer);
+    if played.is_err() {
+      self.sink = Box::new(NullSink);
+    }
+    played
+  }
+
+  pub fn finish(&mut self) -> io::Result<()> {
+    self.sink.finish()
+  }
+}
+
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+  fn play(&mut self, _samples: &[i16]) -> io::Result<()> {
+    Ok(())
+  }
+}
+
+// Writes BEL to the terminal at the start of every tone. This is the best a
+// terminal can do, and what c8rs used to do on every tick.
+#[derive(Default)]
+pub struct BellSink {
+  was_playing: bool
+}
+
+impl AudioSink for BellSink {
+  fn play(&mut self, samples: &[i16]) -> io::Result<()> {
+    let playing = samples.iter().any(|&sample| sample != 0);
+    if playing && !self.was_playing {
+      let mut stdout = stdout();
+      write!(stdout, "\x07")?;
+      stdout.flush()?;
+    }
+
+    self.was_playing = playing;
+    Ok(())
+  }
+}
+
+// Streams raw signed 16-bit little-endian samples into the stdin of a player
+// such as aplay or pacat.
+pub struct PipeSink {
+  child: Child
+}
+
+pub fn default_audio_command(sample_rate: u32) -> String {
+  format!("aplay -q -t raw -f S16_LE -c 1 -r {}", sample_rate)
+}
+
+impl PipeSink {
+  pub fn spawn(command_line: &str) -> io::Result<PipeSink> {
+    let mut words = command_line.split_whitespace();
+    let program = words.next()
+      .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty audio command"))?;
+
+    let child = Command::new(program)
+      .args(words)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::null())
+      .stderr(Stdio::null())
+      .spawn()?;
+
+    Ok(PipeSink { child })
+  }
+}
+
+impl AudioSink for PipeSink {
+  fn play(&mut self, samples: &[i16]) -> io::Result<()> {
+    let stdin = self.child.stdin.as_mut().unwrap();
+    let bytes: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+    stdin.write_all(&bytes)
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    // Closing stdin lets the player drain its buffer and exit.
+    drop(self.child.stdin.take());
+    self.child.wait().map(|_| ())
+  }
+}
+
+// Writes a 16-bit mono PCM WAV file. The sizes in the header aren't known
+// until the end, so they're patched in by finish().
+pub struct WavSink<W: Write + Seek> {
+  writer: W,
+  sample_rate: u32,
+  data_bytes: u32
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+impl WavSink<BufWriter<File>> {
+  pub fn create(path: &Path, sample_rate: u32) -> io::Result<WavSink<BufWriter<File>>> {
+    WavSink::new(BufWriter::new(File::create(path)?), sample_rate)
+  }
+}
+
+impl<W: Write + Seek> WavSink<W> {
+  pub fn new(mut writer: W, sample_rate: u32) -> io::Result<WavSink<W>> {
+    write_wav_header(&mut writer, sample_rate, 0)?;
+    Ok(WavSink { writer, sample_rate, data_bytes: 0 })
+  }
+}
+
+fn write_wav_header<W: Write>(writer: &mut W, sample_rate: u32, data_bytes: u32) -> io::Result<()> {
+  let channels: u16 = 1;
+  let bits_per_sample: u16 = 16;
+  let block_align = channels * bits_per_sample / 8;
+  let byte_rate = sample_rate * block_align as u32;
+
+  writer.write_all(b"RIFF")?;
+  writer.write_all(&(WAV_HEADER_SIZE - 8 + data_bytes).to_le_bytes())?;
+  writer.write_all(b"WAVE")?;
+  writer.write_all(b"fmt ")?;
+  writer.write_all(&16u32.to_le_bytes())?;  // Size of the fmt chunk
+  writer.write_all(&1u16.to_le_bytes())?;   // PCM
+  writer.write_all(&channels.to_le_bytes())?;
+  writer.write_all(&sample_rate.to_le_bytes())?;
+  writer.write_all(&byte_rate.to_le_bytes())?;
+  writer.write_all(&block_align.to_le_bytes())?;
+  writer.write_all(&bits_per_sample.to_le_bytes())?;
+  writer.write_all(b"data")?;
+  writer.write_all(&data_bytes.to_le_bytes())
+}
+
+impl<W: Write + Seek> AudioSink for WavSink<W> {
+  fn play(&mut self, samples: &[i16]) -> io::Result<()> {
+    for sample in samples {
+      self.writer.write_all(&sample.to_le_bytes())?;
+    }
+    self.data_bytes += (samples.len() * 2) as u32;
+    Ok(())
+  }
+
+  fn finish(&mut self) -> io::Result<()> {
+    self.writer.seek(SeekFrom::Start(0))?;
+    write_wav_header(&mut self.writer, self.sample_rate, self.data_bytes)?;
+    self.writer.seek(SeekFrom::End(0))?;
+    self.writer.flush()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::c8::Chip8;
+  use std::convert::TryInto;
+  use std::fs;
+
+  fn read_samples(wav: &[u8]) -> Vec<i16> {
+    wav[WAV_HEADER_SIZE as usize..]
+      .chunks(2)
+      .map(|bytes| i16::from_le_bytes([bytes[0], bytes[1]]))
+      .collect()
+  }
+
+  #[test]
+  fn square_wave_has_the_requested_frequency() {
+    let mut tone = SquareWave::new(440.0, 0.5, DEFAULT_SAMPLE_RATE);
+    let mut samples = Vec::new();
+    tone.generate(true, DEFAULT_SAMPLE_RATE as usize, &mut samples);
+
+    let sign_changes = samples.windows(2).filter(|pair| (pair[0] > 0) != (pair[1] > 0)).count();
+    // Two sign changes per period, give or take the one we're in the middle of.
+    assert!((879..=881).contains(&sign_changes), "{} sign changes", sign_changes);
+    assert!(samples.iter().all(|&sample| sample.abs() == (0.5 * i16::MAX as f32) as i16));
+  }
+
+  #[test]
+  fn sound_timer_is_written_to_wav() {
+    let path = std::env::temp_dir().join(format!("c8rs-sound-timer-{}.wav", std::process::id()));
+
+    // LD V0, 5; LD ST, V0; JP 0x204
+    let mut chip8 = Chip8::new();
+    chip8.load_bytes(&[0x60, 0x05, 0xF0, 0x18, 0x12, 0x04]);
+    let sink = WavSink::create(&path, DEFAULT_SAMPLE_RATE).unwrap();
+    let mut audio = Audio::new(DEFAULT_SAMPLE_RATE, 440.0, 0.25, Box::new(sink));
+
+    for _ in 0..20 {
+      chip8.fde_loop(&[false; 16]);
+      audio.tick(chip8.update_timers(), chip8.audio_pattern()).unwrap();
+    }
+    audio.finish().unwrap();
+
+    let wav = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+
+    // 20 ticks at TIMER_HZ are 20 * 88.2 samples.
+    let samples = read_samples(&wav);
+    assert_eq!(samples.len(), 1764);
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(u32::from_le_bytes(wav[4..8].try_into().unwrap()), 36 + 1764 * 2);
+    assert_eq!(u32::from_le_bytes(wav[40..44].try_into().unwrap()), 1764 * 2);
+
+    // ST is set by the second instruction and then beeps for 5 ticks: from
+    // the end of the first tick (88 samples) to the end of the sixth one
+    // (floor(6 * 88.2) = 529 samples).
+    let first_sound = samples.iter().position(|&sample| sample != 0).unwrap();
+    let last_sound = samples.iter().rposition(|&sample| sample != 0).unwrap();
+    assert_eq!(first_sound, 88);
+    assert_eq!(last_sound, 528);
+    assert!(samples[first_sound..=last_sound].iter().all(|&sample| sample != 0));
+  }
+
+  struct BrokenSink;
+
+  impl AudioSink for BrokenSink {
+    fn play(&mut self, _samples: &[i16]) -> io::Result<()> {
+      Err(io::Error::new(io::ErrorKind::BrokenPipe, "the player quit"))
+    }
+  }
+
+  #[test]
+  fn sinks_that_fail_are_dropped() {
+    let mut audio = Audio::new(DEFAULT_SAMPLE_RATE, 440.0, 0.5, Box::new(BrokenSink));
+    assert_eq!(audio.tick(true, None).unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+    assert!(audio.tick(true, None).is_ok());
+    assert!(audio.finish().is_ok());
+  }
+
+  #[test]
+  fn pattern_rate_doubles_every_48_steps() {
+    assert_eq!(pattern_rate(64), 4000.0);
+    assert_eq!(pattern_rate(112), 8000.0);
+    assert_eq!(pattern_rate(16), 2000.0);
+  }
+
+  #[test]
+  fn pattern_is_played_at_its_rate() {
+    // 0b10101010... at 4000 bits per second is a 2000Hz square wave, which
+    // changes sign 4000 times a second whatever the output rate is.
+    for &sample_rate in &[44100, 48000] {
+      let mut synth = PatternSynth::new(1.0, sample_rate);
+      let mut samples = Vec::new();
+      synth.generate(true, &[0xAA; 0x10], 64, sample_rate as usize, &mut samples);
+
+      let sign_changes = samples.windows(2).filter(|pair| (pair[0] > 0) != (pair[1] > 0)).count();
+      assert!((3990..=4010).contains(&sign_changes), "{} sign changes at {}Hz", sign_changes, sample_rate);
+    }
+  }
+
+  #[test]
+  fn pattern_is_averaged_over_each_sample() {
+    // At pitch 64 and 48kHz, every output sample covers 1/12 of a bit, so a
+    // pattern of all ones is a flat line.
+    let mut synth = PatternSynth::new(1.0, 48000);
+    let mut samples = Vec::new();
+    synth.generate(true, &[0xFF; 0x10], 64, 100, &mut samples);
+    assert!(samples.iter().all(|&sample| sample == i16::MAX));
+
+    // At the highest pitch a sample covers more than one bit, so alternating
+    // bits average out to almost nothing.
+    let mut synth = PatternSynth::new(1.0, 44100);
+    let mut samples = Vec::new();
+    synth.generate(true, &[0xAA; 0x10], 255, 100, &mut samples);
+    assert!(samples.iter().all(|&sample| sample.abs() < i16::MAX / 2), "{:?}", samples);
+  }
+
+  #[test]
+  fn xo_chip_audio_instructions_feed_the_synth() {
+    // LD I, 0x20A; AUDIO; LD V1, 0x70; PITCH V1; JP 0x208; followed by the pattern.
+    let mut program = vec![0xA2, 0x0A, 0xF0, 0x02, 0x61, 0x70, 0xF1, 0x3A, 0x12, 0x08];
+    program.extend_from_slice(&[0xF0; 0x10]);
+
+    let mut chip8 = Chip8::new();
+    chip8.load_bytes(&program);
+    assert_eq!(chip8.audio_pattern(), None);
+
+    for _ in 0..4 {
+      chip8.fde_loop(&[false; 16]);
+    }
+    assert_eq!(chip8.audio_pattern(), Some((&[0xF0; 0x10], 0x70)));
+  }
+}
@@ -0,0 +1,333 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use serde_json::json;
+
+use crate::{SCREEN_LINES, SCREEN_COLUMNS, FRAME_HZ, TIMER_HZ};
+use crate::c8::{Chip8, Config, Quirks};
+use crate::database::{self, RomDatabase};
+use crate::framebuffer::Framebuffer;
+use crate::jit::Backend;
+use crate::loader::{self, PROGRAM_START};
+use crate::op::Op;
+use crate::timing::FixedClock;
+
+// Running lots of ROMs headless (no terminal, no keys pressed), as fast as
+// possible, to see how they end up.
+
+// Why a run stopped.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+  // The program jumped to itself, or waits for a key (Fx0A), which nobody
+  // will press. Sprites waiting for the next frame, with the vblank quirk,
+  // don't count.
+  Halted,
+  // It couldn't be loaded, or it crashed the emulator (a stack overflow...).
+  Error(String),
+  // It ran every instruction it was given.
+  CycleLimit
+}
+
+impl ExitReason {
+  pub fn label(&self) -> &'static str {
+    match self {
+      ExitReason::Halted => "halted",
+      ExitReason::Error(_) => "error",
+      ExitReason::CycleLimit => "cycle limit"
+    }
+  }
+}
+
+pub struct BatchOptions {
+  pub cycles: u64,       // Instructions each ROM runs, at most
+  pub jobs: usize,       // ROMs run at the same time
+  pub backend: Backend,
+  pub database: RomDatabase
+}
+
+pub struct RunResult {
+  pub path: String,
+  // Unique among the results, for the files written for this one.
+  pub name: String,
+  pub sha1: Option<String>,
+  pub title: Option<String>,
+  pub exit: ExitReason,
+  pub instructions: u64,
+  // The display when it stopped.
+  pub display: Option<Framebuffer>
+}
+
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+  payload.downcast_ref::<&str>().map(|message| message.to_string())
+    .or_else(|| payload.downcast_ref::<String>().cloned())
+    .unwrap_or_else(|| "the emulator panicked".to_string())
+}
+
+// Runs chip8 until it halts or has run cycles instructions, with the timers
+// and frames going at rate instructions per second. instructions counts
+// the ones that ran, even when one of them panics.
+fn run(chip8: &mut Chip8, cycles: u64, rate: u64, backend: Backend, instructions: &mut u64) -> ExitReason {
+  let keys = [false; 16];
+  let mut clock = FixedClock::default();
+
+  while *instructions < cycles {
+    let pc = chip8.pc();
+    let count = match backend {
+      Backend::Interpreter => chip8.fde_loop(&keys),
+      Backend::Jit => chip8.run_block(&keys)
+    } as u64;
+    *instructions += count;
+    if let Some(fault) = chip8.fault() {
+      return ExitReason::Error(fault.to_string());
+    }
+
+    let (frames, timer_ticks) = clock.run(count, rate);
+    for _ in 0..frames {
+      chip8.vblank();
+    }
+    for _ in 0..timer_ticks {
+      chip8.update_timers();
+    }
+
+    // Sprites repeat themselves to wait for the next frame, with the vblank
+    // quirk. Anything else that stays put is stuck for good.
+    let waiting_for_frame = matches!(Op::decode(chip8.next_instruction()), Op::Drw(..));
+    if chip8.pc() == pc && count == 1 && !waiting_for_frame {
+      return ExitReason::Halted;
+    }
+  }
+  ExitReason::CycleLimit
+}
+
+fn run_rom(path: &str, options: &BatchOptions) -> RunResult {
+  let mut result = RunResult {
+    path: path.to_string(),
+    name: Path::new(path).file_stem().map_or(path.to_string(), |stem| stem.to_string_lossy().into_owned()),
+    sha1: None,
+    title: None,
+    exit: ExitReason::CycleLimit,
+    instructions: 0,
+    display: None
+  };
+
+  let rom = match loader::load(path, None, PROGRAM_START) {
+    Ok(rom) => rom,
+    Err(e) => {
+      result.exit = ExitReason::Error(e.to_string());
+      return result;
+    }
+  };
+  result.sha1 = Some(database::sha1(&rom.bytes));
+
+  // Cartridges say how they want to be run, and the database knows about
+  // the rest.
+  let entry = options.database.lookup(&rom.bytes);
+  result.title = entry.and_then(|entry| entry.title.clone());
+  let rom_options = rom.options.as_ref().or_else(|| entry.map(|entry| &entry.options));
+  let quirks = rom_options.map_or(Quirks::default(), |options| options.quirks);
+  let rate = rom_options.and_then(|options| options.tickrate)
+    .map_or(TIMER_HZ, |tickrate| tickrate as u64 * FRAME_HZ);
+
+  // The same seed every time, so that runs can be compared.
+  let mut chip8 = Chip8::with_config(Config { quirks, seed: Some(0), ..Config::default() });
+  chip8.load_bytes(&rom.bytes);
+
+  let mut instructions = 0;
+  let ran = panic::catch_unwind(AssertUnwindSafe(|| run(&mut chip8, options.cycles, rate, options.backend, &mut instructions)));
+  result.exit = ran.unwrap_or_else(|payload| ExitReason::Error(panic_message(&*payload)));
+  result.instructions = instructions;
+  result.display = Some(*chip8.get_display());
+  result
+}
+
+// Runs every ROM in roms, options.jobs at a time. The results are in the
+// same order as roms.
+pub fn run_batch(roms: &[String], options: &BatchOptions) -> Vec<RunResult> {
+  let next = AtomicUsize::new(0);
+  let results = Mutex::new(Vec::with_capacity(roms.len()));
+
+  // Crashes end up in the report, with the message run_rom takes from the
+  // panic. The panic hook is left alone, since it's shared with every other
+  // thread of the process.
+  thread::scope(|scope| {
+    for _ in 0..options.jobs.max(1) {
+      scope.spawn(|| loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        if index >= roms.len() {
+          break;
+        }
+        let result = run_rom(&roms[index], options);
+        results.lock().unwrap().push((index, result));
+      });
+    }
+  });
+
+  let mut results = results.into_inner().unwrap();
+  results.sort_by_key(|(index, _)| *index);
+  let mut results: Vec<RunResult> = results.into_iter().map(|(_, result)| result).collect();
+
+  // ROMs with the same name in different directories get a number.
+  let mut taken = std::collections::HashSet::new();
+  for result in results.iter_mut() {
+    let mut name = result.name.clone();
+    let mut number = 2;
+    while !taken.insert(name.clone()) {
+      name = format!("{}-{}", result.name, number);
+      number += 1;
+    }
+    result.name = name;
+  }
+  results
+}
+
+// Pixels of the PNG per display pixel.
+const PNG_SCALE: usize = 8;
+
+fn write_png(path: &Path, display: &Framebuffer) -> io::Result<()> {
+  let (width, height) = (SCREEN_COLUMNS * PNG_SCALE, SCREEN_LINES * PNG_SCALE);
+  let mut encoder = png::Encoder::new(BufWriter::new(File::create(path)?), width as u32, height as u32);
+  encoder.set_color(png::ColorType::Grayscale);
+  encoder.set_depth(png::BitDepth::Eight);
+
+  let pixels: Vec<u8> = (0..height)
+    .flat_map(|y| (0..width).map(move |x| (y, x)))
+    .map(|(y, x)| if display.pixel(y / PNG_SCALE, x / PNG_SCALE) { 0xFF } else { 0x00 })
+    .collect();
+  encoder.write_header()?.write_image_data(&pixels)?;
+  Ok(())
+}
+
+fn escape_html(text: &str) -> String {
+  text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+// Writes report.json and report.html to out, with a PNG and a text file of
+// the last frame of every ROM.
+pub fn write_report(results: &[RunResult], out: &Path) -> io::Result<()> {
+  fs::create_dir_all(out)?;
+
+  let mut entries = Vec::new();
+  let mut rows = String::new();
+  for result in results {
+    let (png, text) = match &result.display {
+      Some(display) => {
+        let png = format!("{}.png", result.name);
+        let text = format!("{}.txt", result.name);
+        write_png(&out.join(&png), display)?;
+        fs::write(out.join(&text), display.to_text())?;
+        (Some(png), Some(text))
+      },
+      None => (None, None)
+    };
+    let error = match &result.exit {
+      ExitReason::Error(message) => Some(message.clone()),
+      _ => None
+    };
+
+    entries.push(json!({
+      "path": result.path,
+      "title": result.title,
+      "sha1": result.sha1,
+      "exit": result.exit.label(),
+      "error": error,
+      "instructions": result.instructions,
+      "png": png,
+      "text": text
+    }));
+
+    let image = png.as_ref().map_or(String::new(), |png| format!("<img src=\"{}\" width=\"256\">", escape_html(png)));
+    let frame = result.display.as_ref().map_or(String::new(), |display| display.to_text());
+    rows += &format!(
+      "<tr><td>{}<br><small>{}</small></td><td>{}</td><td>{}</td><td>{}</td><td><pre>{}</pre></td></tr>\n",
+      escape_html(&result.path),
+      escape_html(result.title.as_deref().unwrap_or("")),
+      escape_html(&error.map_or(result.exit.label().to_string(), |error| format!("error: {}", error))),
+      result.instructions,
+      image,
+      frame
+    );
+  }
+
+  let report = json!({
+    "version": env!("CARGO_PKG_VERSION"),
+    "roms": entries
+  });
+  fs::write(out.join("report.json"), serde_json::to_string_pretty(&report)?)?;
+
+  let html = format!(
+    "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>c8rs {} batch report</title>\n\
+     <style>td {{ vertical-align: top; padding: 4px 8px; }} pre {{ font-size: 6px; line-height: 6px; }}</style></head>\n\
+     <body>\n<table>\n<tr><th>ROM</th><th>Exit</th><th>Instructions</th><th>Last frame</th><th></th></tr>\n{}</table>\n</body>\n</html>\n",
+    env!("CARGO_PKG_VERSION"),
+    rows
+  );
+  fs::write(out.join("report.html"), html)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::c8::{Fault, MAX_STACK_DEPTH};
+
+  fn options() -> BatchOptions {
+    BatchOptions { cycles: 10_000, jobs: 2, backend: Backend::Interpreter, database: RomDatabase::default() }
+  }
+
+  #[test]
+  fn runs_end_for_the_right_reason() {
+    let dir = std::env::temp_dir().join(format!("c8rs-batch-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let rom = |name: &str, bytes: &[u8]| {
+      let path = dir.join(name);
+      fs::write(&path, bytes).unwrap();
+      path.to_string_lossy().into_owned()
+    };
+    let roms = [
+      rom("halts.ch8", &[0x00, 0xE0, 0x12, 0x02]),
+      rom("loops.ch8", &[0x70, 0x01, 0x12, 0x00]),
+      rom("recurses.ch8", &[0x00, 0xE0, 0x22, 0x00]),
+      dir.join("missing.ch8").to_string_lossy().into_owned()
+    ];
+
+    let results = run_batch(&roms, &options());
+    assert_eq!(results[0].exit, ExitReason::Halted);
+    assert_eq!(results[0].instructions, 2);
+    assert_eq!(results[1].exit, ExitReason::CycleLimit);
+    assert_eq!(results[1].instructions, 10_000);
+    assert_eq!(results[2].exit, ExitReason::Error(Fault::StackOverflow.to_string()));
+    // The call that overflowed counts too.
+    assert_eq!(results[2].instructions, 2 * (MAX_STACK_DEPTH as u64 + 1));
+    assert!(results[3].display.is_none());
+
+    write_report(&results, &dir.join("out")).unwrap();
+    let report: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join("out/report.json")).unwrap()).unwrap();
+    assert_eq!(report["roms"][0]["exit"], "halted");
+    assert!(dir.join("out/halts.png").is_file());
+    assert_eq!(fs::read_to_string(dir.join("out/halts.txt")).unwrap(), Framebuffer::new().to_text());
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn only_waits_that_never_end_are_halts() {
+    let run_bytes = |quirks: Quirks, bytes: &[u8]| {
+      let mut chip8 = Chip8::with_config(Config { quirks, ..Config::default() });
+      chip8.load_bytes(bytes);
+      let mut instructions = 0;
+      (run(&mut chip8, 1000, TIMER_HZ, Backend::Interpreter, &mut instructions), instructions)
+    };
+
+    // No keys are ever pressed, so Fx0A waits for good.
+    assert_eq!(run_bytes(Quirks::default(), &[0xF0, 0x0A]), (ExitReason::Halted, 1));
+
+    // A sprite waiting for the next frame is drawn once it comes.
+    let vblank = Quirks { vblank: true, ..Quirks::default() };
+    let (exit, _) = run_bytes(vblank, &[0xD0, 0x01, 0xD0, 0x01, 0x12, 0x00]);
+    assert_eq!(exit, ExitReason::CycleLimit);
+  }
+}
@@ -1,29 +1,126 @@
-use std::path::Path;
-use std::fs;
-use rand;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use std::fmt;
 
 use crate::{SCREEN_LINES, SCREEN_COLUMNS};
+use crate::cdp1802::Cdp1802;
+use crate::dirty::DirtyTracker;
+use crate::framebuffer::{self, Framebuffer};
+use crate::font::{Font, FONT_SIZE, SMALL_FONT_SIZE};
+use crate::jit::{Compiled, Jit};
+use crate::op::Op;
+use crate::timing::{self, Timing, VipClock};
 
-const FONT: [u8; 5*16] = [
-  0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-  0x20, 0x60, 0x20, 0x20, 0x70, // 1
-  0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-  0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-  0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-  0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-  0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-  0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-  0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-  0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-  0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-  0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-  0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-  0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-  0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-  0xF0, 0x80, 0xF0, 0x80, 0x80  // F
-];
-
-const FONT_LOCATION: usize = 0x0000;
+// Behaviours chip8 variants disagree on. The names (and what true means) are
+// the ones Octo uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+  pub shift: bool,       // 8xy6/8xyE shift Vx in place, ignoring Vy
+  pub load_store: bool,  // Fx55/Fx65 leave I untouched
+  pub jump: bool,        // Bnnn jumps to nnn + Vx, x being the top nibble of nnn
+  pub logic: bool,       // 8xy1/8xy2/8xy3 reset VF
+  pub clip: bool,        // Sprites are clipped at the edges instead of wrapping
+  pub vblank: bool       // Dxyn waits for the next frame before drawing
+}
+
+// By default, this interpreter makes shifts act on Vx alone, leaves I
+// untouched in Fx55/Fx65, jumps to nnn + V0 in Bnnn and clips sprites at the
+// edges of the screen.
+impl Default for Quirks {
+  fn default() -> Quirks {
+    Quirks {
+      shift: true,
+      load_store: true,
+      jump: false,
+      logic: false,
+      clip: true,
+      vblank: false
+    }
+  }
+}
+
+impl Quirks {
+  // Name shown in the status line.
+  pub fn label(&self) -> &'static str {
+    if *self == Quirks::default() { "default" } else { "custom" }
+  }
+}
+
+// Where the interpreter keeps its own state.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryMap {
+  // Outside of the program's memory, where programs can't get at it.
+  Separate,
+  // Where the COSMAC VIP interpreter kept it, for programs that peek and
+  // poke it: the stack grows down from 0xECF, V0-VF are at 0xEF0 and the
+  // display is at 0xF00, 8 bytes per line.
+  Vip
+}
+
+// What stops a program for good. The instruction that faulted isn't run,
+// and PC stays on it until the machine is reset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+  StackOverflow,
+  StackUnderflow,
+  // The 0nnn subroutine at this address ran for MACHINE_CODE_STEPS
+  // instructions without returning.
+  MachineCodeTimeout(u16)
+}
+
+impl fmt::Display for Fault {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Fault::StackOverflow => write!(f, "Stack overflow: too many nested calls"),
+      Fault::StackUnderflow => write!(f, "Stack underflow: returning from outside of a subroutine"),
+      Fault::MachineCodeTimeout(address) => write!(f, "The machine code subroutine at 0x{:03X} never returned", address)
+    }
+  }
+}
+
+const VIP_STACK_TOP: usize = 0xED0;
+const VIP_REGISTERS: usize = 0xEF0;
+const VIP_DISPLAY: usize = 0xF00;
+
+pub const MAX_STACK_DEPTH: usize = 16;
+
+// How many 1802 instructions a 0nnn subroutine gets to run before it's
+// deemed stuck.
+const MACHINE_CODE_STEPS: usize = 1_000_000;
+
+// How the machine is put together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+  pub load_address: u16, // Where programs are loaded
+  pub entry: u16,        // Where execution starts
+  pub quirks: Quirks,
+  pub font: Font,
+  pub font_address: u16, // Where the small font goes, followed by the big one
+  pub memory_map: MemoryMap,
+  pub stack_depth: usize, // Nested calls allowed, up to MAX_STACK_DEPTH
+  pub machine_code: bool, // 0nnn runs 1802 machine code, instead of nothing
+  pub timing: Timing,
+  pub decode_cache: bool, // Keep decoded instructions until memory changes
+  pub seed: Option<u64>  // For Cxkk, to get the same numbers every run
+}
+
+impl Default for Config {
+  fn default() -> Config {
+    Config {
+      load_address: 0x200,
+      entry: 0x200,
+      quirks: Quirks::default(),
+      font: Font::default(),
+      font_address: 0x000,
+      memory_map: MemoryMap::Separate,
+      stack_depth: MAX_STACK_DEPTH,
+      machine_code: false,
+      timing: Timing::Fixed,
+      decode_cache: true,
+      seed: None
+    }
+  }
+}
 
 pub struct Chip8 {
   ram: [u8; 0x1000],     // 4kiB of RAM
@@ -35,40 +132,116 @@ pub struct Chip8 {
   dt: u8,                // DT, delay timer
   st: u8,                // ST, sound timer
 
-  stack: [u16; 0x100],   // 256-word deep stack
-  display: [[bool; SCREEN_COLUMNS]; SCREEN_LINES] // Display is 64px wide by 32px tall
+  stack: [u16; MAX_STACK_DEPTH], // Unused with the VIP memory map
+  display: Framebuffer,  // Display is 64px wide by 32px tall
+  dirty: DirtyTracker,   // Pixels changed since the last take_dirty()
+
+                         // XO-CHIP audio
+  pattern: Option<[u8; 0x10]>, // 128 1-bit samples, loaded by F002
+  pitch: u8,             // Playback rate of the pattern, set by Fx3A
+
+  decoded: [Option<Op>; 0x800], // The instruction at every even address
+  jit: Jit,              // For run_block
+  rng: StdRng,           // For Cxkk
+  config: Config,
+  drawn_this_frame: bool, // For the vblank quirk
+  fault: Option<Fault>,  // Why the program stopped, if it did
+
+                         // VIP timing
+  clock: VipClock,
+  machine_code_cycles: u32, // Taken by the last 0nnn
+  interrupts: u32        // Not taken by take_interrupts yet
+}
+
+impl Default for Chip8 {
+  fn default() -> Chip8 {
+    Chip8::new()
+  }
 }
 
 impl Chip8 {
   pub fn new() -> Chip8 {
+    Chip8::with_config(Config::default())
+  }
+
+  pub fn with_config(config: Config) -> Chip8 {
     let mut m_c8 = Chip8 {
       ram: [0x00; 0x1000],
       v: [0x00; 0x10],
       i:  0x0000,
-      pc: 0x0200,
+      pc: config.entry,
       sp: 0x00,
       dt: 0x00,
       st: 0x00,
-      stack: [0x0000; 0x100],
-      display: [[true; SCREEN_COLUMNS]; SCREEN_LINES]
+      stack: [0x0000; MAX_STACK_DEPTH],
+      display: Framebuffer::lit(),
+      dirty: DirtyTracker::all(),
+      pattern: None,
+      pitch: 64,
+      decoded: [None; 0x800],
+      jit: Jit::default(),
+      rng: match config.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy()
+      },
+      config,
+      drawn_this_frame: false,
+      fault: None,
+      clock: VipClock::default(),
+      machine_code_cycles: 0,
+      interrupts: 0
     };
 
     m_c8.load_font();
+    if m_c8.config.memory_map == MemoryMap::Vip {
+      m_c8.store_display();
+    }
 
     m_c8
   }
 
+  // Puts the CPU back in the state it starts in, like the reset button of a
+  // real machine. Memory is left alone.
+  pub fn reset(&mut self) {
+    self.v = [0x00; 0x10];
+    self.i = 0x0000;
+    self.pc = self.config.entry;
+    self.sp = 0x00;
+    self.dt = 0x00;
+    self.st = 0x00;
+    self.stack = [0x0000; MAX_STACK_DEPTH];
+    self.pattern = None;
+    self.pitch = 64;
+    self.drawn_this_frame = false;
+    self.fault = None;
+    // VIP timing starts over with a new frame, too.
+    self.clock = VipClock::default();
+    self.machine_code_cycles = 0;
+    self.interrupts = 0;
+    self.clear_display();
+  }
+
+  // The font goes at 0x000 unless configured otherwise (some interpreters
+  // put it at 0x050).
   pub fn load_font(&mut self) {
-    // I load the font at address 0x000.
-    // TODO: Maybe it's better to load it at 0x050.
-    for (i, item) in FONT.iter().enumerate() {
-      self.ram[i + FONT_LOCATION] = *item;
-    }
+    let small = self.config.font_address as usize;
+    let big = self.big_font_address();
+    self.ram[small..small + SMALL_FONT_SIZE].copy_from_slice(&self.config.font.small);
+    self.ram[big..big + self.config.font.big.len()].copy_from_slice(&self.config.font.big);
+    self.forget_decoded(small, FONT_SIZE);
+  }
+
+  fn big_font_address(&self) -> usize {
+    self.config.font_address as usize + SMALL_FONT_SIZE
   }
 
   // Returns true if screen should beep
   pub fn update_timers(&mut self) -> bool {
     let should_beep = self.st > 0;
+    // With VIP timing, the interrupt counts the timers down instead.
+    if self.config.timing == Timing::Vip {
+      return should_beep;
+    }
 
     self.st = self.st.saturating_sub(1);
     self.dt = self.dt.saturating_sub(1);
@@ -76,291 +249,1169 @@ impl Chip8 {
     should_beep
   }
 
+  pub fn quirks(&self) -> Quirks {
+    self.config.quirks
+  }
+
+  pub fn set_quirks(&mut self, quirks: Quirks) {
+    self.config.quirks = quirks;
+  }
+
+  // Called at the end of every frame.
+  pub fn vblank(&mut self) {
+    self.drawn_this_frame = false;
+  }
+
+  pub fn pc(&self) -> u16 {
+    self.pc
+  }
+
+  pub fn registers(&self) -> &[u8; 0x10] {
+    &self.v
+  }
+
+  pub fn index(&self) -> u16 {
+    self.i
+  }
+
+  pub fn sp(&self) -> u8 {
+    self.sp
+  }
+
+  pub fn dt(&self) -> u8 {
+    self.dt
+  }
+
+  pub fn st(&self) -> u8 {
+    self.st
+  }
+
+  // The return addresses of the subroutines being run, the innermost last.
+  pub fn stack(&self) -> Vec<u16> {
+    (0..self.sp as usize).map(|level| match self.config.memory_map {
+      MemoryMap::Separate => self.stack[level],
+      MemoryMap::Vip => {
+        let address = VIP_STACK_TOP - 2 * (level + 1);
+        (self.ram[address] as u16) << 8 | self.ram[address + 1] as u16
+      }
+    }).collect()
+  }
+
+  // These change the machine's state from outside, like a debugger does.
+
+  pub fn set_register(&mut self, x: usize, value: u8) {
+    self.v[x] = value;
+    if self.config.memory_map == MemoryMap::Vip {
+      self.ram[VIP_REGISTERS + x] = value;
+      self.forget_decoded(VIP_REGISTERS + x, 1);
+    }
+  }
+
+  pub fn set_index(&mut self, i: u16) {
+    self.i = i;
+  }
+
+  pub fn set_pc(&mut self, pc: u16) {
+    self.pc = pc & 0xfff;
+  }
+
+  // Return addresses below sp are kept, whatever they are.
+  pub fn set_sp(&mut self, sp: u8) {
+    self.sp = sp.min(self.config.stack_depth as u8);
+  }
+
+  pub fn set_timers(&mut self, dt: u8, st: u8) {
+    self.dt = dt;
+    self.st = st;
+  }
+
+  // Copies bytes into memory at address, wrapping around the end of it.
+  pub fn write_memory(&mut self, address: u16, bytes: &[u8]) {
+    for (offset, byte) in bytes.iter().enumerate() {
+      self.ram[(address as usize + offset) & 0xfff] = *byte;
+    }
+    self.wrote_memory(address as usize & 0xfff, bytes.len());
+  }
+
+  // The same, to put a new machine in a given state and run an instruction
+  // from there: Chip8::new().with_register(1, 0x30).with_index(0x300).
+
+  pub fn with_register(mut self, x: usize, value: u8) -> Chip8 {
+    self.set_register(x, value);
+    self
+  }
+
+  pub fn with_index(mut self, i: u16) -> Chip8 {
+    self.set_index(i);
+    self
+  }
+
+  pub fn with_pc(mut self, pc: u16) -> Chip8 {
+    self.set_pc(pc);
+    self
+  }
+
+  pub fn with_timers(mut self, dt: u8, st: u8) -> Chip8 {
+    self.set_timers(dt, st);
+    self
+  }
+
+  pub fn with_memory(mut self, address: u16, bytes: &[u8]) -> Chip8 {
+    self.write_memory(address, bytes);
+    self
+  }
+
+  // Replaces the stack with return addresses, the innermost last.
+  pub fn with_stack(mut self, addresses: &[u16]) -> Chip8 {
+    self.sp = 0;
+    for address in addresses {
+      self.st_push(*address).expect("more return addresses than the stack holds");
+    }
+    self
+  }
+
+  pub fn with_display(mut self, display: Framebuffer) -> Chip8 {
+    for line in 0..SCREEN_LINES {
+      self.dirty.mark_line(line, self.display.line(line) ^ display.line(line));
+    }
+    self.display = display;
+    if self.config.memory_map == MemoryMap::Vip {
+      self.store_display();
+    }
+    self
+  }
+
+  // Returns the instruction at PC, which is the one the next fde_loop will
+  // execute.
+  pub fn next_instruction(&self) -> u16 {
+    let pc = self.pc as usize;
+    (self.ram[pc & 0xfff] as u16) << 8 | self.ram[(pc + 1) & 0xfff] as u16
+  }
+
+  // Returns the XO-CHIP audio pattern and its pitch, once a program has
+  // loaded one with F002. Until then, the sound timer plays a plain tone.
+  pub fn audio_pattern(&self) -> Option<(&[u8; 0x10], u8)> {
+    self.pattern.as_ref().map(|pattern| (pattern, self.pitch))
+  }
+
   // In this stack, the SP points to the next, unfilled,
   // position in the stack.
-  fn st_push(&mut self, value: u16) {
-    self.stack[self.sp as usize] = value;
-    // This bottom line will overflow and error out when
-    // the result of this operation is greater then 0x100.
+  fn st_push(&mut self, value: u16) -> Result<(), Fault> {
+    if self.sp as usize >= self.config.stack_depth {
+      return Err(Fault::StackOverflow);
+    }
+    match self.config.memory_map {
+      MemoryMap::Separate => self.stack[self.sp as usize] = value,
+      MemoryMap::Vip => {
+        let address = VIP_STACK_TOP - 2 * (self.sp as usize + 1);
+        self.ram[address] = (value >> 8) as u8;
+        self.ram[address + 1] = value as u8;
+        self.forget_decoded(address, 2);
+      }
+    }
     self.sp += 1;
+    Ok(())
   }
-  fn st_pop(&mut self) -> u16 {
+  fn st_pop(&mut self) -> Result<u16, Fault> {
+    if self.sp == 0 {
+      return Err(Fault::StackUnderflow);
+    }
     self.sp -= 1;
-    self.stack[self.sp as usize]
+    Ok(match self.config.memory_map {
+      MemoryMap::Separate => self.stack[self.sp as usize],
+      MemoryMap::Vip => {
+        let address = VIP_STACK_TOP - 2 * (self.sp as usize + 1);
+        (self.ram[address] as u16) << 8 | self.ram[address + 1] as u16
+      }
+    })
+  }
+
+  // Why the program stopped, if it did. Nothing runs until a reset.
+  pub fn fault(&self) -> Option<Fault> {
+    self.fault
+  }
+
+  // Writes to memory can change the instructions decoded there and, with the
+  // VIP memory map, the registers and the display, which live there.
+  fn wrote_memory(&mut self, start: usize, length: usize) {
+    self.forget_decoded(start, length);
+    if self.config.memory_map != MemoryMap::Vip {
+      return;
+    }
+    let touches = |from: usize, to: usize| start < to && start + length > from;
+
+    if touches(VIP_REGISTERS, VIP_REGISTERS + 0x10) {
+      self.v.copy_from_slice(&self.ram[VIP_REGISTERS..VIP_REGISTERS + 0x10]);
+    }
+    if touches(VIP_DISPLAY, 0x1000) {
+      for line in 0..SCREEN_LINES {
+        let bytes = &self.ram[VIP_DISPLAY + line * SCREEN_COLUMNS / 8..][..SCREEN_COLUMNS / 8];
+        let pixels = bytes.iter().enumerate()
+          .fold(0, |pixels, (byte, value)| pixels | framebuffer::byte_pixels(*value) << (8 * byte));
+        self.dirty.mark_line(line, self.display.line(line) ^ pixels);
+        self.display.set_line(line, pixels);
+      }
+    }
+  }
+
+  // Calls the 1802 subroutine at address, set up the way the VIP interpreter
+  // left things: R3 is the program counter, X is 2 and R2 points below the
+  // stack, R5 is the chip8 PC, R6 and R7 point at Vx and Vy of the 0nnn
+  // instruction (always V0 here), RA is I and RB the display page. The
+  // subroutine returns with SEP R4 (D4).
+  //
+  // Meant for the VIP memory map, where the registers and the display are in
+  // memory for the subroutine to play with. With the separate one, they
+  // aren't, and whatever the program keeps at those addresses stays there.
+  fn run_machine_code(&mut self, address: u16) -> Result<(), Fault> {
+    if self.config.memory_map == MemoryMap::Vip {
+      self.ram[VIP_REGISTERS..VIP_REGISTERS + 0x10].copy_from_slice(&self.v);
+    }
+
+    let mut cpu = Cdp1802::new();
+    cpu.p = 3;
+    cpu.x = 2;
+    cpu.r[2] = (VIP_STACK_TOP - 2 * self.sp as usize - 1) as u16;
+    cpu.r[3] = address;
+    cpu.r[5] = self.pc;
+    cpu.r[6] = VIP_REGISTERS as u16;
+    cpu.r[7] = VIP_REGISTERS as u16;
+    cpu.r[8] = (self.dt as u16) << 8 | self.st as u16;
+    cpu.r[0xA] = self.i;
+    cpu.r[0xB] = VIP_DISPLAY as u16;
+
+    let mut steps = 0;
+    while cpu.p != 4 {
+      if steps == MACHINE_CODE_STEPS {
+        // Whatever it wrote to memory stays there.
+        self.wrote_memory(0, self.ram.len());
+        return Err(Fault::MachineCodeTimeout(address));
+      }
+      self.machine_code_cycles += cpu.step(&mut self.ram);
+      steps += 1;
+    }
+
+    self.i = cpu.r[0xA] & 0x0fff;
+    self.dt = (cpu.r[8] >> 8) as u8;
+    self.st = cpu.r[8] as u8;
+    self.wrote_memory(0, self.ram.len());
+    Ok(())
   }
 
-  pub fn fde_loop(&mut self, pressed_keys: &[bool; 16]) {
-    // =======      Fetch       =======
-    let instruction_bytes = &self.ram[(self.pc as usize)..((self.pc+2) as usize)];
-    let instruction = (instruction_bytes[0] as u16) << 8 | instruction_bytes[1] as u16;
+  // Copies the display into memory, for the VIP memory map.
+  fn store_display(&mut self) {
+    for line in 0..SCREEN_LINES {
+      let pixels = self.display.line(line);
+      for byte in 0..SCREEN_COLUMNS / 8 {
+        self.ram[VIP_DISPLAY + line * SCREEN_COLUMNS / 8 + byte] = ((pixels >> (8 * byte)) as u8).reverse_bits();
+      }
+    }
+    self.forget_decoded(VIP_DISPLAY, 0x1000 - VIP_DISPLAY);
+  }
+
+  // Executes an instruction, and returns how long it took: always 1 with
+  // fixed timing, and a number of machine cycles with VIP timing (counting
+  // the ones the interrupt took in the meantime). After a fault, nothing runs
+  // and this returns 0.
+  pub fn fde_loop(&mut self, pressed_keys: &[bool; 16]) -> u32 {
+    if self.fault.is_some() {
+      return 0;
+    }
+    if self.config.timing == Timing::Fixed {
+      self.execute(pressed_keys);
+      return 1;
+    }
+
+    let instruction = self.next_instruction();
+    let (pc, v) = (self.pc, self.v);
+    // Dxyn waits for the interrupt, so that it draws at the start of a frame.
+    let wait = if instruction & 0xf000 == 0xD000 { self.clock.until_interrupt() } else { 0 };
+    self.machine_code_cycles = 0;
+    self.execute(pressed_keys);
+
+    let skipped = self.pc == (pc + 4) & 0xfff;
+    let cycles = wait + timing::vip_cycles(instruction, &v, skipped) + self.machine_code_cycles;
+    let interrupts = self.clock.run(cycles);
+    for _ in 0..interrupts {
+      self.dt = self.dt.saturating_sub(1);
+      self.st = self.st.saturating_sub(1);
+    }
+    self.interrupts += interrupts;
+    cycles + interrupts * timing::STOLEN_CYCLES
+  }
+
+  // Executes a block of instructions compiled in one go, for speed, and
+  // returns how many ran. The result is the same as running them with
+  // fde_loop. Not for VIP timing, where this falls back on fde_loop and
+  // returns what it does.
+  pub fn run_block(&mut self, pressed_keys: &[bool; 16]) -> u32 {
+    if self.config.timing == Timing::Vip || self.fault.is_some() {
+      return self.fde_loop(pressed_keys);
+    }
+
+    let address = self.pc as usize & 0xfff;
+    let block = match self.jit.block(address) {
+      Some(block) => block,
+      None => {
+        let memory_map = self.config.memory_map;
+        self.jit.compile(&self.ram, address, |op| Chip8::compile_op(op, memory_map))
+      }
+    };
+    if block.is_empty() {
+      // Right at the end of memory, where only the interpreter goes.
+      return self.fde_loop(pressed_keys);
+    }
+
+    self.jit.take_invalidated();
+    // A block that wrote over itself (or any other) stops there, in case
+    // what comes next changed. So does one that faulted.
+    block.run(self, pressed_keys, |chip8| !chip8.jit.take_invalidated() && chip8.fault.is_none())
+  }
+
+  // Turns op into a closure for the JIT. The simplest, most common
+  // instructions get one of their own, and the rest go through step_op.
+  fn compile_op(op: Op, memory_map: MemoryMap) -> Compiled {
+    if memory_map == MemoryMap::Vip {
+      // The registers are copied to memory after every instruction.
+      return Box::new(move |chip8: &mut Chip8, pressed_keys: &[bool; 16]| chip8.step_op(op, pressed_keys));
+    }
 
+    match op {
+      Op::Jp(address) => Box::new(move |chip8: &mut Chip8, _: &[bool; 16]| chip8.pc = address),
+      Op::LdByte(x, kk) => Box::new(move |chip8: &mut Chip8, _: &[bool; 16]| {
+        chip8.v[x as usize] = kk;
+        chip8.move_pc(2);
+      }),
+      Op::AddByte(x, kk) => Box::new(move |chip8: &mut Chip8, _: &[bool; 16]| {
+        chip8.v[x as usize] = chip8.v[x as usize].wrapping_add(kk);
+        chip8.move_pc(2);
+      }),
+      Op::LdReg(x, y) => Box::new(move |chip8: &mut Chip8, _: &[bool; 16]| {
+        chip8.v[x as usize] = chip8.v[y as usize];
+        chip8.move_pc(2);
+      }),
+      Op::LdI(address) => Box::new(move |chip8: &mut Chip8, _: &[bool; 16]| {
+        chip8.i = address;
+        chip8.move_pc(2);
+      }),
+      _ => Box::new(move |chip8: &mut Chip8, pressed_keys: &[bool; 16]| chip8.step_op(op, pressed_keys))
+    }
+  }
+
+  pub fn memory(&self) -> &[u8; 0x1000] {
+    &self.ram
+  }
+
+  // Returns how many times the 60 Hz interrupt came since the last call, with
+  // VIP timing. Each one is a frame.
+  pub fn take_interrupts(&mut self) -> u32 {
+    std::mem::take(&mut self.interrupts)
+  }
+
+  // Moves PC on (or back) by offset bytes. Like the fetch, it goes around
+  // the end of memory.
+  fn move_pc(&mut self, offset: i16) {
+    self.pc = self.pc.wrapping_add(offset as u16) & 0xfff;
+  }
+
+  // Stops the program at the instruction being executed.
+  fn stop(&mut self, fault: Fault) {
+    self.move_pc(-2);
+    self.fault = Some(fault);
+  }
+
+  fn execute(&mut self, pressed_keys: &[bool; 16]) {
+    // ======= Fetch & Decode =======
+    let op = self.decode_next();
+    self.step_op(op, pressed_keys);
+  }
+
+  // Executes an instruction that was already fetched and decoded.
+  pub(crate) fn step_op(&mut self, op: Op, pressed_keys: &[bool; 16]) {
     //eprint!("\x1b[{};{}H[c8] {:?}", 21, 0, pressed_keys);
 
-    self.pc += 2;
+    self.move_pc(2);
 
-    let x: usize = ((instruction & 0x0f00) >> 8) as usize;
-    let y: usize = ((instruction & 0x00f0) >> 4) as usize;
-    let kk: u8 = (instruction & 0x00ff) as u8;
-    
-    // ======= Decode & Execute =======
-    match instruction & 0xf000 {
-      0x0000 => {
-        match instruction {
-          0x00E0 => {     // 00E0: CLS
-            self.clear_display();
-          },
-          0x00EE => {     // 00EE: RET
-            self.pc = self.st_pop();
-          },
-          _ => {          // 0nnn: SYS addr
-            // TODO. This instruction is not very important.
+    // =======     Execute      =======
+    match op {
+      Op::Cls => {        // 00E0: CLS
+        self.clear_display();
+      },
+      Op::Ret => {        // 00EE: RET
+        match self.st_pop() {
+          Ok(address) => self.pc = address,
+          Err(fault) => self.stop(fault)
+        }
+      },
+      Op::Sys(address) => { // 0nnn: SYS addr
+        if self.config.machine_code {
+          if let Err(fault) = self.run_machine_code(address) {
+            self.stop(fault);
           }
         }
       },
-      0x1000 => {         // 1nnn: JP addr
-        self.pc = instruction & 0x0fff;
+      Op::Jp(address) => { // 1nnn: JP addr
+        self.pc = address;
       },
-      0x2000 => {         // 2nnn: CALL addr
-        self.st_push(self.pc);
-        self.pc = instruction & 0x0fff;
+      Op::Call(address) => { // 2nnn: CALL addr
+        match self.st_push(self.pc) {
+          Ok(()) => self.pc = address,
+          Err(fault) => self.stop(fault)
+        }
       },
-      0x3000 => {         // 3xkk:  SE Vx, byte
-        if self.v[x] == kk {
-          self.pc += 2;
+      Op::SeByte(x, kk) => { // 3xkk: SE Vx, byte
+        if self.v[x as usize] == kk {
+          self.move_pc(2);
         }
       },
-      0x4000 => {         // 3xkk:  SNE Vx, byte
-        if self.v[x] != kk {
-          self.pc += 2;
+      Op::SneByte(x, kk) => { // 4xkk: SNE Vx, byte
+        if self.v[x as usize] != kk {
+          self.move_pc(2);
         }
       },
-      0x5000 => {         // 5xy0: SE Vx, Vy
-        if instruction & 0x000f == 0x0000 {
-          if self.v[x] == self.v[y] {
-            self.pc += 2;
-          }
-        } else {
-          // Unknown instruction
+      Op::SeReg(x, y) => { // 5xy0: SE Vx, Vy
+        if self.v[x as usize] == self.v[y as usize] {
+          self.move_pc(2);
         }
       },
-      0x6000 => {         // 6xkk: LD Vx, byte
-        self.v[x] = kk;
+      Op::LdByte(x, kk) => { // 6xkk: LD Vx, byte
+        self.v[x as usize] = kk;
       },
-      0x7000 => {         // 7xkk: ADD Vx, byte
+      Op::AddByte(x, kk) => { // 7xkk: ADD Vx, byte
         // NOTE: This ADD instruction DOES NOT affect the carry bit in VF.
+        let x = x as usize;
         self.v[x] = self.v[x].wrapping_add(kk);
       },
-      0x8000 => {
-        match instruction & 0x000f {
-          0x0000 => {     // 8xy0: LD Vx, Vy
-            self.v[x] = self.v[y];
-          },
-          0x0001 => {     // 8xy1: OR Vx, Vy
-            self.v[x] |= self.v[y];
-          },
-          0x0002 => {     // 8xy2: AND Vx, Vy
-            self.v[x] &= self.v[y];
-          },
-          0x0003 => {     // 8xy3: XOR Vx, Vy
-            self.v[x] ^= self.v[y];
-          },
-          0x0004 => {     // 8xy4: ADD Vx, Vy
-            // NOTE: This ADD instruction DOES affect the carry bit in VF.
-            let carry: bool;
-            (self.v[x], carry) = self.v[x].carrying_add(self.v[y], false);
-            self.v[0xf] = carry as u8;
-          },
-          0x0005 => {     // 8xy5: SUB Vx, Vy
-            let borrow: bool;
-    
-            (self.v[x], borrow) = self.v[x].borrowing_sub(self.v[y], false);
-            self.v[0xf] = !borrow as u8;
-          },
-          0x0006 => {     // 8xy6: SHR Vx
-            // Vx >>= Vx.
-            let carry = self.v[x] & 0x01;
-            self.v[x] >>= 1;
-            self.v[0xf] = carry;
-          },
-          0x0007 => {     // 8xy7: SUBN Vx, Vy
-            let borrow: bool;
-    
-            (self.v[x], borrow) = self.v[y].borrowing_sub(self.v[x], false);
-
-            self.v[0xf] = !borrow as u8;
-          },
-          0x000E => {     // 8xyE: SHL Vx
-            // Vx <<= Vx.
-            let carry = (self.v[x] & 0x80) >> 7;
-            self.v[x] <<= 1;
-            self.v[0xf] = carry;
-          },
-          _ => {} // Only these instructions exist, if we reach this point, there's a problem somewhere.
+      Op::LdReg(x, y) => { // 8xy0: LD Vx, Vy
+        self.v[x as usize] = self.v[y as usize];
+      },
+      Op::Or(x, y) => {   // 8xy1: OR Vx, Vy
+        self.v[x as usize] |= self.v[y as usize];
+        if self.config.quirks.logic {
+          self.v[0xf] = 0;
+        }
+      },
+      Op::And(x, y) => {  // 8xy2: AND Vx, Vy
+        self.v[x as usize] &= self.v[y as usize];
+        if self.config.quirks.logic {
+          self.v[0xf] = 0;
+        }
+      },
+      Op::Xor(x, y) => {  // 8xy3: XOR Vx, Vy
+        self.v[x as usize] ^= self.v[y as usize];
+        if self.config.quirks.logic {
+          self.v[0xf] = 0;
         }
       },
-      0x9000 => {
-                  // 9xy0: SNE Vx, Vy
-        if instruction & 0x000f == 0x0000 {
+      Op::AddReg(x, y) => { // 8xy4: ADD Vx, Vy
+        // NOTE: This ADD instruction DOES affect the carry bit in VF.
+        let (x, y) = (x as usize, y as usize);
+        let carry: bool;
+        (self.v[x], carry) = self.v[x].carrying_add(self.v[y], false);
+        self.v[0xf] = carry as u8;
+      },
+      Op::Sub(x, y) => {  // 8xy5: SUB Vx, Vy
+        let (x, y) = (x as usize, y as usize);
+        let borrow: bool;
 
-          if self.v[x] != self.v[y] {
-            self.pc += 2;
-          }
-        } else {
-          // Unknown instruction
+        (self.v[x], borrow) = self.v[x].borrowing_sub(self.v[y], false);
+        self.v[0xf] = !borrow as u8;
+      },
+      Op::Shr(x, y) => {  // 8xy6: SHR Vx
+        // Vx >>= Vx, or Vx = Vy >> 1 without the shift quirk.
+        let (x, y) = (x as usize, y as usize);
+        if !self.config.quirks.shift {
+          self.v[x] = self.v[y];
         }
+        let carry = self.v[x] & 0x01;
+        self.v[x] >>= 1;
+        self.v[0xf] = carry;
+      },
+      Op::Subn(x, y) => { // 8xy7: SUBN Vx, Vy
+        let (x, y) = (x as usize, y as usize);
+        let borrow: bool;
 
+        (self.v[x], borrow) = self.v[y].borrowing_sub(self.v[x], false);
+
+        self.v[0xf] = !borrow as u8;
+      },
+      Op::Shl(x, y) => {  // 8xyE: SHL Vx
+        // Vx <<= Vx, or Vx = Vy << 1 without the shift quirk.
+        let (x, y) = (x as usize, y as usize);
+        if !self.config.quirks.shift {
+          self.v[x] = self.v[y];
+        }
+        let carry = (self.v[x] & 0x80) >> 7;
+        self.v[x] <<= 1;
+        self.v[0xf] = carry;
+      },
+      Op::SneReg(x, y) => { // 9xy0: SNE Vx, Vy
+        if self.v[x as usize] != self.v[y as usize] {
+          self.move_pc(2);
+        }
       },
-      0xA000 => {          // Annn: LD I, addr
-        self.i = instruction & 0x0fff;
+      Op::LdI(address) => { // Annn: LD I, addr
+        self.i = address;
       },
-      0xB000 => {          // Bnnn: JP V0, addr
-        // Quirky, jumps to nnn + V0
-        self.pc = instruction & 0x0fff + (self.v[0x0] as u16);
+      Op::JpV0(address, x) => { // Bnnn: JP V0, addr
+        // Jumps to nnn + V0, or to nnn + Vx with the jump quirk.
+        let offset = if self.config.quirks.jump { self.v[x as usize] } else { self.v[0x0] };
+        self.pc = (address + offset as u16) & 0xfff;
       },
-      0xC000 => {          // Cxkk: RND Vx, byte
-        self.v[x] = rand::random::<u8>() & kk;
+      Op::Rnd(x, kk) => { // Cxkk: RND Vx, byte
+        self.v[x as usize] = self.rng.gen::<u8>() & kk;
       },
-      0xD000 => {          // Dxyn: DRW Vx, Vy, nibble
+      Op::Drw(x, y, nibble) => { // Dxyn: DRW Vx, Vy, nibble
         // I'm using cx and cy here as to differentiate these two values I'm
         // grabbing from the registers from the x and y values I got from the
         // opcode.
-        let nibble = instruction & 0x000f;
+
+        // With the vblank quirk, only one sprite is drawn per frame. The
+        // instruction is retried until the next one starts.
+        if self.config.quirks.vblank {
+          if self.drawn_this_frame {
+            self.move_pc(-2);
+            return;
+          }
+          self.drawn_this_frame = true;
+        }
 
         // Grab the x and y coordinates from Vx and Vy.
-        let cx = self.v[x] % (SCREEN_COLUMNS as u8);
-        let cy = self.v[y] % (SCREEN_LINES as u8);
+        let cx = self.v[x as usize] % (SCREEN_COLUMNS as u8);
+        let cy = self.v[y as usize] % (SCREEN_LINES as u8);
         
         self.v[0xf] = 0;
         
-        let sprite_rowdata = &self.ram[(self.i as usize) .. ((self.i+nibble) as usize)]; 
-        for i in 0..(nibble as u8) {  // For i in each row.
-          let rowdata = sprite_rowdata[i as usize];
-          let new_cy = (cy+i) as usize;
-         
-          // If we're over the border of the screen, stop drawing.
-          if new_cy >= SCREEN_LINES {
-            break;
-          }
-
-          for j in 0..8 {     // For each bit (1 bit per column).
-            let new_cx = (cx+j) as usize;
+        // The sprite wraps around the end of memory, like Fx33/Fx55 do.
+        for row in 0..nibble as usize {
+          let byte = self.ram[(self.i as usize + row) & 0xfff];
+          let mut line = cy as usize + row;
 
-            // If we're over the border of the screen, stop drawing
-            if new_cx >= SCREEN_COLUMNS {
+          // If we're over the border of the screen, stop drawing (or wrap
+          // around, without the clip quirk).
+          if line >= SCREEN_LINES {
+            if self.config.quirks.clip {
               break;
             }
-            
-            // We write bits from the left of the byte to the right of the byte.
-            // Thus, we first need to mask 0b10000000 (0x80), then 0b01000000, ...
-            let mask_bit = (rowdata & (0x80 >> j)) != 0;
-            if self.display[new_cy][new_cx] == true && mask_bit == true {
-              self.v[0xf] = 1;
-            }
+            line %= SCREEN_LINES;
+          }
 
-            self.display[new_cy][new_cx] ^= mask_bit;
+          // Columns past the right edge are shifted out of the line, or come
+          // back in on the left without the clip quirk.
+          let pixels = framebuffer::byte_pixels(byte);
+          let pixels = if self.config.quirks.clip { pixels << cx } else { pixels.rotate_left(cx as u32) };
+          if self.display.xor_line(line, pixels) {
+            self.v[0xf] = 1;
           }
+          self.dirty.mark_line(line, pixels);
+        }
+        if self.config.memory_map == MemoryMap::Vip {
+          self.store_display();
         }
       },
-      0xE000 => {
-        //eprint!("\x1b[{};{}H[c8] Waiting for key {}", 19, 0, x);
-        match instruction & 0x00ff {
-          0x009E => {      // Ex9E: SKP Vx
-            if pressed_keys[self.v[x] as usize] {
-              self.pc += 2;
-            }
-          },
-          0x00A1 => {      // ExA1: SKNP Vx
-            if !pressed_keys[self.v[x] as usize] {
-              self.pc += 2;
-            }
-          },
-          // Unknown instruction.
-          _ => {}
+      Op::Skp(x) => {     // Ex9E: SKP Vx
+        // Only the low nibble counts, like in the original interpreters.
+        if pressed_keys[(self.v[x as usize] & 0x0f) as usize] {
+          self.move_pc(2);
         }
       },
-      0xF000 => {
-        match instruction & 0x00ff {
-          0x0007 => {      // Fx07: LD Vx, DT
-            self.v[x] = self.dt;
-          },
-          0x000A => {      // Fx0A: LD Vx, K
-            // In order to block until a key is pressed, if no key is pressed, I decrement the PC
-            // in order to execute this instruction in the next CPU cycle.
-            let pressed_key = pressed_keys.iter()
-              .enumerate()
-              .find_map(|(key_index, is_pressed)| if *is_pressed { Some(key_index) } else { None });
-            match pressed_key {
-              Some(key_index) => self.v[x] = key_index as u8,
-              None => self.pc -= 2
-            }
+      Op::Sknp(x) => {    // ExA1: SKNP Vx
+        if !pressed_keys[(self.v[x as usize] & 0x0f) as usize] {
+          self.move_pc(2);
+        }
+      },
+      Op::Audio => {      // F002: AUDIO (XO-CHIP)
+        // Loads the 16 bytes at I into the audio pattern buffer.
+        let mut pattern = [0; 0x10];
+        for (i, byte) in pattern.iter_mut().enumerate() {
+          *byte = self.ram[(self.i as usize + i) & 0xfff];
+        }
+        self.pattern = Some(pattern);
+      },
+      Op::LdVxDt(x) => {  // Fx07: LD Vx, DT
+        self.v[x as usize] = self.dt;
+      },
+      Op::LdVxK(x) => {   // Fx0A: LD Vx, K
+        // In order to block until a key is pressed, if no key is pressed, I decrement the PC
+        // in order to execute this instruction in the next CPU cycle.
+        let pressed_key = pressed_keys.iter()
+          .enumerate()
+          .find_map(|(key_index, is_pressed)| if *is_pressed { Some(key_index) } else { None });
+        match pressed_key {
+          Some(key_index) => self.v[x as usize] = key_index as u8,
+          None => self.move_pc(-2)
+        }
 
-          },
-          0x0015 => {      // Fx15: LD DT, Vx
-            self.dt = self.v[x];
-          },
-          0x0018 => {      // Fx18: LD ST, Vx
-            self.st = self.v[x];
-          },
-          0x001E => {      // Fx1E: ADD I, Vx
-            self.i = self.i.saturating_add(self.v[x] as u16);
-            
-            // Set VF to 1 if I "overflows" from 0FFF to above 1000 (outside normal addressing
-            // range).
-            if self.i > 0xFFF {
-              self.v[0xf] = 1;
-            }
-          },
-          0x0029 => {      // Fx29: LD F, Vx
-            self.i = ((FONT_LOCATION as u8) + self.v[x]*5) as u16;
-          },
-          0x0033 => {      // Fx33: LD B, Vx
-            self.ram[self.i as usize] = self.v[x] / 100;
-            self.ram[(self.i+1) as usize] = self.v[x] % 100 / 10;
-            self.ram[(self.i+2) as usize] = self.v[x] % 10 / 1;
-          },
-          // NOTE: I do not increment I in there instructions. Older games may require that
-          // behaviour.
-          0x0055 => {      // Fx55: LD [I], Vx
-            for i in 0..x+1 {
-              self.ram[self.i as usize + i] = self.v[i];
-            }
-          },
-          0x0065 => {      // Fx65: LD Vx, [I]
-            for i in 0..x+1 as usize {
-              self.v[i] = self.ram[self.i as usize + i];
-            }
-          },
+      },
+      Op::LdDtVx(x) => {  // Fx15: LD DT, Vx
+        self.dt = self.v[x as usize];
+      },
+      Op::LdStVx(x) => {  // Fx18: LD ST, Vx
+        self.st = self.v[x as usize];
+      },
+      Op::AddI(x) => {    // Fx1E: ADD I, Vx
+        self.i = self.i.saturating_add(self.v[x as usize] as u16);
+        
+        // Set VF to 1 if I "overflows" from 0FFF to above 1000 (outside normal addressing
+        // range).
+        if self.i > 0xFFF {
+          self.v[0xf] = 1;
+        }
+      },
+      Op::LdF(x) => {     // Fx29: LD F, Vx
+        // Only the low nibble counts, like in the original interpreters.
+        self.i = self.config.font_address + (self.v[x as usize] & 0x0f) as u16 * 5;
+      },
+      Op::LdHf(x) => {    // Fx30: LD HF, Vx (SUPER-CHIP)
+        self.i = (self.big_font_address() + (self.v[x as usize] & 0x0f) as usize * 10) as u16;
+      },
+      Op::LdB(x) => {     // Fx33: LD B, Vx
+        let value = self.v[x as usize];
+        let i = self.i as usize & 0xfff;
+        self.ram[i] = value / 100;
+        self.ram[(i+1) & 0xfff] = value % 100 / 10;
+        self.ram[(i+2) & 0xfff] = value % 10;
+        self.wrote_memory(i, 3);
+      },
+      Op::Pitch(x) => {   // Fx3A: PITCH Vx (XO-CHIP)
+        self.pitch = self.v[x as usize];
+      },
+      // NOTE: I is only incremented in these instructions without the
+      // load/store quirk. Older games may require that behaviour.
+      Op::LdIVx(x) => {   // Fx55: LD [I], Vx
+        let x = x as usize;
+        for i in 0..x+1 {
+          self.ram[(self.i as usize + i) & 0xfff] = self.v[i];
+        }
+        self.wrote_memory(self.i as usize & 0xfff, x + 1);
+        if !self.config.quirks.load_store {
+          self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+      },
+      Op::LdVxI(x) => {   // Fx65: LD Vx, [I]
+        let x = x as usize;
+        for i in 0..x+1 {
+          self.v[i] = self.ram[(self.i as usize + i) & 0xfff];
+        }
+        if !self.config.quirks.load_store {
+          self.i = self.i.wrapping_add(x as u16 + 1);
+        }
+      },
+      Op::Unknown(_) => {}
+    }
 
+    if self.config.memory_map == MemoryMap::Vip {
+      self.ram[VIP_REGISTERS..VIP_REGISTERS + 0x10].copy_from_slice(&self.v);
+      self.forget_decoded(VIP_REGISTERS, 0x10);
+    }
+  }
 
-          // Unknown instruction.
-          _ => {}
-        }
+  // Decodes the instruction at PC, or takes it from the cache when it was
+  // decoded before. Only instructions at even addresses are cached.
+  fn decode_next(&mut self) -> Op {
+    let pc = self.pc as usize & 0xfff;
+    if !self.config.decode_cache || pc & 1 != 0 {
+      return Op::decode(self.next_instruction());
+    }
 
-      },
+    match self.decoded[pc >> 1] {
+      Some(op) => op,
+      None => {
+        let op = Op::decode(self.next_instruction());
+        self.decoded[pc >> 1] = Some(op);
+        op
+      }
+    }
+  }
 
-      // This shouldn't be executed, I've tested for all the possible combinations above.
-      _ => {}
+  // Drops the decoded instructions (and compiled blocks) that overlap the
+  // length bytes at start, after they change.
+  fn forget_decoded(&mut self, start: usize, length: usize) {
+    if length == 0 {
+      return;
+    }
+    let start = start & 0xfff;
+    self.jit.invalidate(start, length.min(0x1000 - start));
+    if start + length > 0x1000 {
+      // Wrapped around the end of memory.
+      self.jit.invalidate(0, start + length - 0x1000);
+    }
+    // An instruction at an even address also covers the odd one after it.
+    let first = start >> 1;
+    let last = ((start + length - 1) & 0xfff) >> 1;
+    if first <= last {
+      self.decoded[first..=last].fill(None);
+    } else {
+      // Wrapped around the end of memory.
+      self.decoded[first..].fill(None);
+      self.decoded[..=last].fill(None);
     }
   }
   
-  pub fn load_file(&mut self, path: &Path) {
-    let contents = fs::read(path).unwrap();
-    
+  // Copies a program into memory, at the load address. Use loader::load to
+  // get one from a file, which also makes sure it fits.
+  pub fn load_bytes(&mut self, contents: &[u8]) {
+    let start = self.config.load_address as usize;
     for (i, item) in contents.iter().enumerate() {
-      self.ram[i+0x200] = *item;
+      self.ram[start + i] = *item;
     }
+    self.wrote_memory(start, contents.len());
   }
 
-  pub fn get_display(&self) -> &[[bool; SCREEN_COLUMNS]; SCREEN_LINES] {
+  pub fn get_display(&self) -> &Framebuffer {
     &self.display
   }
 
+  // Returns the pixels that changed since the last call, and starts tracking
+  // changes from scratch.
+  pub fn take_dirty(&mut self) -> DirtyTracker {
+    std::mem::take(&mut self.dirty)
+  }
+
   pub fn clear_display(&mut self) {
-    for i in 0..self.display.len() {
-      for j in 0..self.display[0].len() {
-        self.display[i][j] = false;
-      }
+    for line in 0..SCREEN_LINES {
+      self.dirty.mark_line(line, self.display.line(line));
+    }
+    self.display = Framebuffer::new();
+    if self.config.memory_map == MemoryMap::Vip {
+      self.store_display();
     }
   }
 }
 
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn jump_adds_v0_to_the_address() {
+    // B300 with V0 = 0x10 goes to 0x310.
+    let mut chip8 = Chip8::new();
+    chip8.ram[0x200] = 0xB3;
+    chip8.ram[0x201] = 0x00;
+    chip8.v[0] = 0x10;
+    chip8.fde_loop(&[false; 16]);
+    assert_eq!(chip8.pc, 0x310);
+  }
+
+  #[test]
+  fn pc_goes_around_the_end_of_memory() {
+    // Memory full of 0000 (SYS, which does nothing) runs on from 0xFFE to
+    // 0x000, with the font out of the way.
+    for jit in [false, true] {
+      let mut chip8 = Chip8::with_config(Config { font_address: 0x050, ..Config::default() }).with_pc(0xFFC);
+      for expected in [0xFFE, 0x000, 0x002] {
+        if jit { chip8.run_block(&[false; 16]); } else { chip8.fde_loop(&[false; 16]); }
+        assert_eq!(chip8.pc, expected);
+      }
+    }
+
+    // So do skips, and Bnnn past 0xFFF.
+    let chip8 = step(Chip8::new().with_pc(0xFFE), 0x3000);
+    assert_eq!(chip8.pc, 0x002);
+    let chip8 = step(Chip8::new().with_register(0, 0x20), 0xBFF0);
+    assert_eq!(chip8.pc, 0x010);
+  }
+
+  #[test]
+  fn digits_point_into_the_font_wherever_it_goes() {
+    let mut chip8 = Chip8::with_config(Config { font_address: 0x050, ..Config::default() });
+    chip8.load_font();
+    assert_eq!(chip8.ram[0x050..0x050 + SMALL_FONT_SIZE], Font::default().small);
+    assert_eq!(chip8.ram[0x0A0..0x0A0 + 10], Font::default().big[..10]);
+
+    // Only the low nibble of Vx picks the digit.
+    let chip8 = step(chip8.with_register(3, 0xFA), 0xF329);
+    assert_eq!(chip8.i, 0x050 + 0xA * 5);
+    let chip8 = step(chip8.with_register(3, 0x07), 0xF330);
+    assert_eq!(chip8.i, 0x0A0 + 0x7 * 10);
+  }
+
+  #[test]
+  fn self_modifying_code_runs_what_was_written() {
+    for decode_cache in [false, true] {
+      let mut chip8 = Chip8::with_config(Config { decode_cache, ..Config::default() });
+      chip8.load_bytes(&[
+        0x60, 0x63, // V0 = 63
+        0x61, 0x07, // V1 = 07
+        0xA2, 0x0C, // I = 20C
+        0x22, 0x0C, // Call 20C, which sets V3 to 42
+        0xF1, 0x55, // Overwrite it with 63 07
+        0x22, 0x0C, // Call it again, which now sets V3 to 07
+        0x63, 0x42,
+        0x00, 0xEE
+      ]);
+      for _ in 0..9 {
+        chip8.fde_loop(&[false; 16]);
+      }
+      assert_eq!(chip8.registers()[3], 0x07);
+    }
+  }
+
+  #[test]
+  fn sprites_clip_or_wrap_at_the_right_edge() {
+    for clip in [true, false] {
+      let quirks = Quirks { clip, ..Quirks::default() };
+      let mut chip8 = Chip8::with_config(Config { quirks, ..Config::default() });
+      chip8.clear_display();
+      chip8.load_bytes(&[
+        0x60, 0x3C, // V0 = 60
+        0xA2, 0x08, // I = 208
+        0xD0, 0x11, // Draw 1 row at V0, V1
+        0xD0, 0x11, // And again, to collide
+        0xFF, 0x00
+      ]);
+      for _ in 0..3 {
+        chip8.fde_loop(&[false; 16]);
+      }
+
+      let display = chip8.get_display();
+      assert!((60..64).all(|column| display.pixel(0, column)));
+      assert_eq!((0..4).all(|column| display.pixel(0, column)), !clip);
+      assert!(!display.pixel(0, 4) && !display.pixel(1, 60));
+
+      chip8.fde_loop(&[false; 16]);
+      assert_eq!(chip8.get_display(), &Framebuffer::new());
+      assert_eq!(chip8.registers()[0xf], 1);
+    }
+  }
+
+  // Everything an instruction can change, besides memory and the display.
+  #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+  struct State {
+    v: [u8; 0x10],
+    i: u16,
+    pc: u16,
+    sp: u8,
+    dt: u8,
+    st: u8
+  }
+
+  fn state(chip8: &Chip8) -> State {
+    State { v: chip8.v, i: chip8.i, pc: chip8.pc, sp: chip8.sp, dt: chip8.dt, st: chip8.st }
+  }
+
+  // Runs instruction, put at PC, and nothing else.
+  fn step(chip8: Chip8, instruction: u16) -> Chip8 {
+    let pc = chip8.pc;
+    let mut chip8 = chip8.with_memory(pc, &[(instruction >> 8) as u8, instruction as u8]);
+    chip8.fde_loop(&[false; 16]);
+    chip8
+  }
+
+  // An instruction, the registers it starts with, and the ones it changes.
+  // Everything else has to stay the same, but for PC, which moves on.
+  struct Case {
+    instruction: u16,
+    before: &'static [(usize, u8)],
+    after: &'static [(usize, u8)]
+  }
+
+  fn check_cases(quirks: Quirks, cases: &[Case]) {
+    for case in cases {
+      let mut chip8 = Chip8::with_config(Config { quirks, ..Config::default() })
+        .with_index(0x345)
+        .with_timers(7, 9);
+      for (x, value) in case.before {
+        chip8 = chip8.with_register(*x, *value);
+      }
+
+      let mut expected = state(&chip8);
+      expected.pc += 2;
+      for (x, value) in case.after {
+        expected.v[*x] = *value;
+      }
+      assert_eq!(state(&step(chip8, case.instruction)), expected, "{:04X} with {:02X?}", case.instruction, case.before);
+    }
+  }
+
+  #[test]
+  fn arithmetic_and_logic() {
+    check_cases(Quirks::default(), &[
+      // 8xy0
+      Case { instruction: 0x8120, before: &[(2, 0x42)], after: &[(1, 0x42)] },
+      Case { instruction: 0x8F10, before: &[(1, 0x42)], after: &[(0xF, 0x42)] },
+      // 8xy1, 8xy2 and 8xy3 leave VF alone
+      Case { instruction: 0x8121, before: &[(1, 0xF0), (2, 0x0F), (0xF, 0x55)], after: &[(1, 0xFF)] },
+      Case { instruction: 0x8F11, before: &[(1, 0x0F), (0xF, 0xF0)], after: &[(0xF, 0xFF)] },
+      Case { instruction: 0x8122, before: &[(1, 0xFC), (2, 0x3F), (0xF, 0x55)], after: &[(1, 0x3C)] },
+      Case { instruction: 0x8F12, before: &[(1, 0x3F), (0xF, 0xFC)], after: &[(0xF, 0x3C)] },
+      Case { instruction: 0x8123, before: &[(1, 0xFF), (2, 0x0F), (0xF, 0x55)], after: &[(1, 0xF0)] },
+      Case { instruction: 0x8F13, before: &[(1, 0xFF), (0xF, 0x0F)], after: &[(0xF, 0xF0)] },
+      // 8xy4: VF is the carry, even when the sum goes there
+      Case { instruction: 0x8124, before: &[(1, 0x10), (2, 0x20), (0xF, 0x55)], after: &[(1, 0x30), (0xF, 0)] },
+      Case { instruction: 0x8124, before: &[(1, 0xFF), (2, 0x02)], after: &[(1, 0x01), (0xF, 1)] },
+      Case { instruction: 0x8124, before: &[(1, 0x80), (2, 0x80)], after: &[(1, 0x00), (0xF, 1)] },
+      Case { instruction: 0x8F14, before: &[(1, 0x01), (0xF, 0xFF)], after: &[(0xF, 1)] },
+      Case { instruction: 0x8F14, before: &[(1, 0x01), (0xF, 0x01)], after: &[(0xF, 0)] },
+      Case { instruction: 0x81F4, before: &[(1, 0xFF), (0xF, 0x01)], after: &[(1, 0x00), (0xF, 1)] },
+      // 8xy5: VF is 1 when nothing is borrowed
+      Case { instruction: 0x8125, before: &[(1, 0x30), (2, 0x10)], after: &[(1, 0x20), (0xF, 1)] },
+      Case { instruction: 0x8125, before: &[(1, 0x10), (2, 0x30), (0xF, 1)], after: &[(1, 0xE0), (0xF, 0)] },
+      Case { instruction: 0x8125, before: &[(1, 0x30), (2, 0x30)], after: &[(1, 0x00), (0xF, 1)] },
+      Case { instruction: 0x8F15, before: &[(1, 0x10), (0xF, 0x30)], after: &[(0xF, 1)] },
+      Case { instruction: 0x8F15, before: &[(1, 0x30), (0xF, 0x10)], after: &[(0xF, 0)] },
+      // 8xy6: VF is the bit shifted out
+      Case { instruction: 0x8126, before: &[(1, 0x05), (2, 0xFF)], after: &[(1, 0x02), (0xF, 1)] },
+      Case { instruction: 0x8126, before: &[(1, 0x04), (0xF, 1)], after: &[(1, 0x02), (0xF, 0)] },
+      Case { instruction: 0x8F06, before: &[(0xF, 0x02)], after: &[(0xF, 0)] },
+      Case { instruction: 0x8F06, before: &[(0xF, 0x03)], after: &[(0xF, 1)] },
+      // 8xy7: Vx = Vy - Vx
+      Case { instruction: 0x8127, before: &[(1, 0x10), (2, 0x30)], after: &[(1, 0x20), (0xF, 1)] },
+      Case { instruction: 0x8127, before: &[(1, 0x30), (2, 0x10), (0xF, 1)], after: &[(1, 0xE0), (0xF, 0)] },
+      Case { instruction: 0x8127, before: &[(1, 0x30), (2, 0x30)], after: &[(1, 0x00), (0xF, 1)] },
+      Case { instruction: 0x8F17, before: &[(1, 0x30), (0xF, 0x10)], after: &[(0xF, 1)] },
+      Case { instruction: 0x8F17, before: &[(1, 0x10), (0xF, 0x30)], after: &[(0xF, 0)] },
+      // 8xyE
+      Case { instruction: 0x812E, before: &[(1, 0x81), (2, 0x00)], after: &[(1, 0x02), (0xF, 1)] },
+      Case { instruction: 0x812E, before: &[(1, 0x40), (0xF, 1)], after: &[(1, 0x80), (0xF, 0)] },
+      Case { instruction: 0x8F0E, before: &[(0xF, 0x80)], after: &[(0xF, 1)] },
+      Case { instruction: 0x8F0E, before: &[(0xF, 0x40)], after: &[(0xF, 0)] }
+    ]);
+  }
+
+  #[test]
+  fn arithmetic_and_logic_quirks() {
+    let quirks = Quirks { shift: false, logic: true, ..Quirks::default() };
+    check_cases(quirks, &[
+      // Logic resets VF, after the result goes there
+      Case { instruction: 0x8121, before: &[(1, 0xF0), (2, 0x0F), (0xF, 0x55)], after: &[(1, 0xFF), (0xF, 0)] },
+      Case { instruction: 0x8F12, before: &[(1, 0xFF), (0xF, 0xFF)], after: &[(0xF, 0)] },
+      Case { instruction: 0x8123, before: &[(1, 0xFF), (2, 0x0F), (0xF, 0x55)], after: &[(1, 0xF0), (0xF, 0)] },
+      // Shifts take Vy
+      Case { instruction: 0x8126, before: &[(1, 0xFF), (2, 0x05)], after: &[(1, 0x02), (0xF, 1)] },
+      Case { instruction: 0x812E, before: &[(1, 0xFF), (2, 0x40)], after: &[(1, 0x80), (0xF, 0)] },
+      Case { instruction: 0x8F2E, before: &[(2, 0x81)], after: &[(0xF, 1)] },
+      Case { instruction: 0x81F6, before: &[(0xF, 0x03)], after: &[(1, 0x01), (0xF, 1)] }
+    ]);
+  }
+
+  #[test]
+  fn jump_with_offset() {
+    for (jump, target) in [(false, 0x355), (true, 0x347)] {
+      let quirks = Quirks { jump, ..Quirks::default() };
+      let chip8 = Chip8::with_config(Config { quirks, ..Config::default() })
+        .with_register(0, 0x10)
+        .with_register(3, 0x02);
+      let mut expected = state(&chip8);
+      expected.pc = target;
+      assert_eq!(state(&step(chip8, 0xB345)), expected);
+    }
+  }
+
+  #[test]
+  fn add_to_index_overflow() {
+    for (i, vx, sum, vf) in [(0x0FFE, 0x01, 0x0FFF, 0x55), (0x0FFF, 0x01, 0x1000, 1), (0x0FF0, 0xFF, 0x10EF, 1), (0x0000, 0x00, 0x0000, 0x55)] {
+      let chip8 = Chip8::new().with_index(i).with_register(1, vx).with_register(0xF, 0x55);
+      let mut expected = state(&chip8);
+      expected.i = sum;
+      expected.v[0xF] = vf;
+      expected.pc += 2;
+      assert_eq!(state(&step(chip8, 0xF11E)), expected, "I = {:03X}, V1 = {:02X}", i, vx);
+    }
+
+    // VF can be what's added, and then holds the flag.
+    let chip8 = step(Chip8::new().with_index(0x0FFF).with_register(0xF, 0x01), 0xFF1E);
+    assert_eq!((chip8.index(), chip8.registers()[0xF]), (0x1000, 1));
+  }
+
+  #[test]
+  fn load_and_store_past_the_top_of_index() {
+    // Fx1E saturates I at FFFF, and Fx55/Fx65 then wrap it around.
+    let quirks = Quirks { load_store: false, ..Quirks::default() };
+    for instruction in [0xF155, 0xF165] {
+      let chip8 = Chip8::with_config(Config { quirks, ..Config::default() }).with_index(0xFFFF);
+      assert_eq!(step(chip8, instruction).index(), 0x0001, "{:04X}", instruction);
+    }
+  }
+
+  #[test]
+  fn binary_coded_decimal() {
+    for (value, digits) in [(0, [0, 0, 0]), (9, [0, 0, 9]), (10, [0, 1, 0]), (99, [0, 9, 9]), (100, [1, 0, 0]), (199, [1, 9, 9]), (255, [2, 5, 5])] {
+      let chip8 = Chip8::new().with_index(0x300).with_register(1, value);
+      let mut expected = state(&chip8);
+      expected.pc += 2;
+
+      let chip8 = step(chip8, 0xF133);
+      assert_eq!(state(&chip8), expected);
+      assert_eq!(chip8.memory()[0x300..0x303], digits, "{}", value);
+      assert_eq!(chip8.memory()[0x303], 0);
+    }
+
+    // The digits wrap around the end of memory.
+    let chip8 = step(Chip8::new().with_index(0xFFF).with_register(1, 123), 0xF133);
+    assert_eq!((chip8.memory()[0xFFF], chip8.memory()[0x000], chip8.memory()[0x001]), (1, 2, 3));
+  }
+
+  // A machine with a blank display, and a 4 row sprite at 0x300.
+  fn sprite_machine(quirks: Quirks) -> Chip8 {
+    Chip8::with_config(Config { quirks, ..Config::default() })
+      .with_display(Framebuffer::new())
+      .with_memory(0x300, &[0xF0, 0x90, 0x90, 0xF0])
+      .with_index(0x300)
+  }
+
+  #[test]
+  fn sprites_clip_or_wrap_at_the_bottom_edge() {
+    for clip in [true, false] {
+      // Coordinates wrap around first, so this is x = 2, y = 30.
+      let chip8 = sprite_machine(Quirks { clip, ..Quirks::default() })
+        .with_register(0, 66)
+        .with_register(1, 62)
+        .with_register(0xF, 1);
+      let mut expected = state(&chip8);
+      expected.pc += 2;
+      expected.v[0xF] = 0;
+
+      let chip8 = step(chip8, 0xD014);
+      assert_eq!(state(&chip8), expected);
+      let display = chip8.get_display();
+      assert_eq!((display.line(30), display.line(31)), (0x3C, 0x24));
+      assert_eq!((display.line(0), display.line(1)), if clip { (0, 0) } else { (0x24, 0x3C) });
+      assert_eq!(display.line(2), 0);
+    }
+  }
+
+  #[test]
+  fn sprites_report_collisions() {
+    let chip8 = step(sprite_machine(Quirks::default()).with_register(0, 4), 0xD014);
+    assert_eq!(chip8.registers()[0xF], 0);
+    assert_eq!(chip8.get_display().line(3), 0xF0);
+
+    // Overlapping by a single pixel is enough, and that pixel goes off.
+    let chip8 = step(chip8.with_register(0, 7).with_register(1, 3), 0xD014);
+    assert_eq!(chip8.registers()[0xF], 1);
+    assert!(!chip8.get_display().pixel(3, 7));
+    assert_eq!(chip8.get_display().line(3), 0x770);
+
+    // Drawing it again erases it, and collides all over.
+    let mut expected = state(&chip8);
+    expected.pc += 2;
+    let chip8 = step(chip8, 0xD014);
+    assert_eq!(state(&chip8), expected);
+    assert_eq!(chip8.get_display().line(3), 0xF0);
+
+    // VF as a coordinate is read before it becomes the flag.
+    let chip8 = step(sprite_machine(Quirks::default()).with_register(0xF, 8), 0xDF04);
+    assert_eq!(chip8.get_display().line(0), 0xF00);
+    assert_eq!(chip8.registers()[0xF], 0);
+  }
+
+  #[test]
+  fn sprites_wrap_around_the_end_of_memory() {
+    let chip8 = step(sprite_machine(Quirks::default()).with_memory(0xFFF, &[0xFF, 0x81]).with_index(0xFFF), 0xD002);
+    assert_eq!((chip8.get_display().line(0), chip8.get_display().line(1)), (0xFF, 0x81));
+  }
+
+  #[test]
+  fn keys_are_the_low_nibble() {
+    let mut keys = [false; 16];
+    keys[0xA] = true;
+    for (instruction, skips) in [(0xE19E, true), (0xE1A1, false)] {
+      let mut chip8 = Chip8::new().with_register(1, 0xFA).with_memory(0x200, &[(instruction >> 8) as u8, instruction as u8]);
+      chip8.fde_loop(&keys);
+      assert_eq!(chip8.pc(), if skips { 0x204 } else { 0x202 });
+    }
+  }
+
+  #[test]
+  fn call_and_return() {
+    for memory_map in [MemoryMap::Separate, MemoryMap::Vip] {
+      let chip8 = Chip8::with_config(Config { memory_map, ..Config::default() }).with_register(5, 0x55);
+      let mut expected = state(&chip8);
+
+      let chip8 = step(chip8, 0x2300);
+      expected.pc = 0x300;
+      expected.sp = 1;
+      assert_eq!(state(&chip8), expected);
+      assert_eq!(chip8.stack(), [0x202]);
+
+      let chip8 = step(chip8, 0x2400);
+      expected.pc = 0x400;
+      expected.sp = 2;
+      assert_eq!(state(&chip8), expected);
+      assert_eq!(chip8.stack(), [0x202, 0x302]);
+
+      let chip8 = step(step(chip8, 0x00EE), 0x00EE);
+      expected.pc = 0x202;
+      expected.sp = 0;
+      assert_eq!(state(&chip8), expected);
+      assert!(chip8.stack().is_empty());
+    }
+  }
+
+  #[test]
+  fn machine_code_sees_the_registers_with_the_vip_memory_map() {
+    for (memory_map, seen) in [(MemoryMap::Separate, 0xAA), (MemoryMap::Vip, 0x55)] {
+      // A subroutine that returns right away (SEP R4).
+      let chip8 = Chip8::with_config(Config { memory_map, machine_code: true, ..Config::default() })
+        .with_memory(VIP_REGISTERS as u16, &[0xAA; 0x10])
+        .with_register(1, 0x55)
+        .with_memory(0x300, &[0xD4]);
+      let chip8 = step(chip8, 0x0300);
+      assert_eq!(chip8.memory()[VIP_REGISTERS + 1], seen, "{:?}", memory_map);
+      assert_eq!(chip8.registers()[1], 0x55);
+    }
+  }
+
+  #[test]
+  fn machine_code_that_never_returns_stops_the_machine() {
+    // A subroutine that branches to itself (BR 00) forever.
+    let chip8 = Chip8::with_config(Config { machine_code: true, ..Config::default() })
+      .with_memory(0x300, &[0x30, 0x00]);
+    let mut chip8 = step(chip8, 0x0300);
+    assert_eq!(chip8.fault(), Some(Fault::MachineCodeTimeout(0x300)));
+    assert_eq!(chip8.pc, 0x200);
+    assert_eq!(chip8.fde_loop(&[false; 16]), 0);
+  }
+
+  #[test]
+  fn calls_nest_as_deep_as_the_stack() {
+    let chip8 = step(Chip8::new().with_stack(&[0x202; MAX_STACK_DEPTH - 1]), 0x2200);
+    assert_eq!(chip8.sp() as usize, MAX_STACK_DEPTH);
+    assert_eq!(chip8.fault(), None);
+
+    // One more doesn't happen, and the machine stops there.
+    let expected = state(&chip8);
+    let mut chip8 = step(chip8, 0x2200);
+    assert_eq!(chip8.fault(), Some(Fault::StackOverflow));
+    assert_eq!(state(&chip8), expected);
+    assert_eq!(chip8.fde_loop(&[false; 16]), 0);
+    assert_eq!(chip8.run_block(&[false; 16]), 0);
+    assert_eq!(state(&chip8), expected);
+
+    chip8.reset();
+    assert_eq!(chip8.fault(), None);
+  }
+
+  #[test]
+  fn resets_start_a_new_vip_frame() {
+    // A sprite, which waits for the interrupt, and then a loop. One machine
+    // is reset somewhere in its third frame, and has to run like a new one.
+    let config = Config { timing: Timing::Vip, ..Config::default() };
+    let program = [0xD0, 0x01, 0x60, 0x00, 0x12, 0x02];
+    let mut fresh = Chip8::with_config(config);
+    fresh.load_bytes(&program);
+    let mut chip8 = Chip8::with_config(config);
+    chip8.load_bytes(&program);
+    while chip8.interrupts < 2 {
+      chip8.fde_loop(&[false; 16]);
+    }
+
+    chip8.reset();
+    assert_eq!(chip8.fde_loop(&[false; 16]), fresh.fde_loop(&[false; 16]));
+    assert_eq!(chip8.take_interrupts(), fresh.take_interrupts());
+  }
+
+  #[test]
+  fn returning_needs_a_call() {
+    for memory_map in [MemoryMap::Separate, MemoryMap::Vip] {
+      let chip8 = Chip8::with_config(Config { memory_map, ..Config::default() });
+      let expected = state(&chip8);
+      let chip8 = step(chip8, 0x00EE);
+      assert_eq!(chip8.fault(), Some(Fault::StackUnderflow));
+      assert_eq!(state(&chip8), expected);
+    }
+  }
+}
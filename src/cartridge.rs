@@ -0,0 +1,192 @@
+use std::io::Cursor;
+
+use serde_json::Value;
+
+use crate::c8::Quirks;
+use crate::palette::{Palette, Rgb};
+use crate::screen::Keymap;
+
+// Octo cartridges are GIF images: a picture of a cartridge with a label on
+// it, that also carries the source code of a program and the options it runs
+// with. The payload hides in the low 2 bits of every pixel's color index, in
+// all the frames one after the other, 4 pixels per byte (most significant
+// bits first). It starts with its length, as a big endian 32-bit number, and
+// then comes a JSON object like {"options": {...}, "program": "..."}.
+
+pub fn is_cartridge(contents: &[u8]) -> bool {
+  contents.starts_with(b"GIF87a") || contents.starts_with(b"GIF89a")
+}
+
+// What a cartridge wants the emulator to be like.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CartridgeOptions {
+  // Instructions per frame.
+  pub tickrate: Option<u32>,
+  pub quirks: Quirks,
+  pub palette: Option<Palette>,
+  pub keymap: Option<Keymap>
+}
+
+pub struct Cartridge {
+  pub options: CartridgeOptions,
+  // Octo source code.
+  pub program: String
+}
+
+fn payload(contents: &[u8]) -> Result<Vec<u8>, String> {
+  let mut options = gif::DecodeOptions::new();
+  options.set_color_output(gif::ColorOutput::Indexed);
+  let mut decoder = options.read_info(Cursor::new(contents)).map_err(|e| e.to_string())?;
+
+  let mut pixels = Vec::new();
+  while let Some(frame) = decoder.read_next_frame().map_err(|e| e.to_string())? {
+    pixels.extend(frame.buffer.iter().map(|index| index & 3));
+  }
+
+  let bytes: Vec<u8> = pixels.chunks_exact(4)
+    .map(|bits| bits[0] << 6 | bits[1] << 4 | bits[2] << 2 | bits[3])
+    .collect();
+  if bytes.len() < 4 {
+    return Err("there's no program in it".to_string());
+  }
+
+  let length = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+  bytes.get(4..4 + length)
+    .map(|payload| payload.to_vec())
+    .ok_or_else(|| "it's not an Octo cartridge (or it's cut short)".to_string())
+}
+
+// Octo names keys like browsers do (KeyX, Digit1...). Only the ones that
+// send a single character to the terminal can be used here.
+fn key_character(code: &str) -> Option<char> {
+  let character = if let Some(letter) = code.strip_prefix("Key") {
+    letter
+  } else if let Some(digit) = code.strip_prefix("Digit") {
+    digit
+  } else if code == "Space" {
+    " "
+  } else {
+    return None;
+  };
+
+  let mut characters = character.chars();
+  match (characters.next(), characters.next()) {
+    (Some(c), None) => Some(c.to_ascii_lowercase()),
+    _ => None
+  }
+}
+
+// The keymap is a list of 16 lists of keys, one per keypad key.
+fn parse_keymap(value: &Value) -> Option<Keymap> {
+  let keys = value.as_array()?;
+  if keys.len() != 16 {
+    return None;
+  }
+
+  let mut keymap: Keymap = Default::default();
+  for (index, codes) in keys.iter().enumerate() {
+    keymap[index] = codes.as_array()?.iter()
+      .filter_map(|code| code.as_str().and_then(key_character))
+      .collect();
+  }
+  Some(keymap)
+}
+
+// Reads the options Octo saves with a program. The ROM database uses them
+// too.
+pub fn parse_options(options: &Value) -> CartridgeOptions {
+  // Octo leaves out the quirks that are off.
+  let flag = |name: &str| options[name].as_bool().unwrap_or(false);
+  let color = |name: &str| options[name].as_str().and_then(|hex| Rgb::parse_hex(hex).ok());
+
+  let palette = match (color("backgroundColor"), color("fillColor")) {
+    (Some(background), Some(fill)) => Some(Palette {
+      colors: [
+        background,
+        fill,
+        color("fillColor2").unwrap_or(fill),
+        color("blendColor").unwrap_or(fill)
+      ]
+    }),
+    _ => None
+  };
+
+  CartridgeOptions {
+    tickrate: options["tickrate"].as_u64().map(|tickrate| tickrate as u32).filter(|tickrate| *tickrate > 0),
+    quirks: Quirks {
+      shift: flag("shiftQuirks"),
+      load_store: flag("loadStoreQuirks"),
+      jump: flag("jumpQuirks"),
+      logic: flag("logicQuirks"),
+      clip: flag("clipQuirks"),
+      vblank: flag("vBlankQuirks")
+    },
+    palette,
+    keymap: parse_keymap(&options["keymap"])
+  }
+}
+
+pub fn decode(contents: &[u8]) -> Result<Cartridge, String> {
+  // Octo writes the JSON one byte per character.
+  let json: String = payload(contents)?.into_iter().map(char::from).collect();
+  let value: Value = serde_json::from_str(&json).map_err(|e| format!("bad cartridge data: {}", e))?;
+
+  let program = value["program"].as_str()
+    .ok_or_else(|| "the cartridge has no program".to_string())?
+    .to_string();
+
+  Ok(Cartridge { options: parse_options(&value["options"]), program })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Builds a single frame cartridge around payload, the way Octo would.
+  fn cartridge(payload: &str) -> Vec<u8> {
+    let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+    bytes.extend(payload.bytes());
+
+    let mut pixels: Vec<u8> = bytes.iter()
+      .flat_map(|byte| [byte >> 6, byte >> 4 & 3, byte >> 2 & 3, byte & 3])
+      .collect();
+    let width = 16;
+    pixels.resize(pixels.len().div_ceil(width) * width, 0);
+    let height = pixels.len() / width;
+
+    let mut gif = Vec::new();
+    {
+      let palette: Vec<u8> = (0..4).flat_map(|i| [i * 64, i * 64, i * 64]).collect();
+      let mut encoder = gif::Encoder::new(&mut gif, width as u16, height as u16, &palette).unwrap();
+      let frame = gif::Frame::from_indexed_pixels(width as u16, height as u16, pixels, None);
+      encoder.write_frame(&frame).unwrap();
+    }
+    gif
+  }
+
+  #[test]
+  fn program_and_options_are_read() {
+    let gif = cartridge(r##"{"options": {"tickrate": 20, "shiftQuirks": true, "clipQuirks": true,
+      "backgroundColor": "#996600", "fillColor": "#FFCC00"}, "program": ": main jump main"}"##);
+    assert!(is_cartridge(&gif));
+
+    let cartridge = decode(&gif).unwrap();
+    assert_eq!(cartridge.program, ": main jump main");
+    assert_eq!(cartridge.options.tickrate, Some(20));
+    assert!(cartridge.options.quirks.shift && cartridge.options.quirks.clip);
+    assert!(!cartridge.options.quirks.load_store);
+    assert_eq!(cartridge.options.palette.unwrap().colors[0], Rgb(0x99, 0x66, 0x00));
+    assert_eq!(cartridge.options.keymap, None);
+  }
+
+  #[test]
+  fn keymaps_keep_the_keys_a_terminal_can_send() {
+    let mut keys = vec![r#"["KeyX"]"#.to_string(); 16];
+    keys[5] = r#"["KeyW", "ArrowUp"]"#.to_string();
+    let gif = cartridge(&format!(r#"{{"options": {{"keymap": [{}]}}, "program": ""}}"#, keys.join(",")));
+
+    let keymap = decode(&gif).unwrap().options.keymap.unwrap();
+    assert_eq!(keymap[0], vec!['x']);
+    assert_eq!(keymap[5], vec!['w']);
+  }
+}
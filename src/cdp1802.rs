@@ -0,0 +1,320 @@
+// The RCA CDP1802, the CPU of the COSMAC VIP. The chip8 interpreter ran on
+// it, and 0nnn instructions called into 1802 machine code that programs
+// brought along, which is what this is for.
+//
+// Only the CPU is here: there are no I/O devices (INP reads 0, OUT goes
+// nowhere), no DMA and no interrupts.
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cdp1802 {
+  pub r: [u16; 16],  // R0-RF, the scratchpad registers
+  pub d: u8,         // Accumulator
+  pub df: bool,      // Carry (and not borrow)
+  pub p: u8,         // Which R is the program counter
+  pub x: u8,         // Which R points at memory operands
+  pub t: u8,         // X and P saved by MARK
+  pub ie: bool,      // Interrupt enable
+  pub q: bool        // The Q output, which drove the VIP's speaker
+}
+
+impl Cdp1802 {
+  pub fn new() -> Cdp1802 {
+    Cdp1802 { ie: true, ..Default::default() }
+  }
+
+  fn read(ram: &[u8], address: u16) -> u8 {
+    ram[address as usize % ram.len()]
+  }
+
+  fn write(ram: &mut [u8], address: u16, value: u8) {
+    let length = ram.len();
+    ram[address as usize % length] = value;
+  }
+
+  // Reads the byte at R(P), and moves past it.
+  fn fetch(&mut self, ram: &[u8]) -> u8 {
+    let p = self.p as usize;
+    let byte = Cdp1802::read(ram, self.r[p]);
+    self.r[p] = self.r[p].wrapping_add(1);
+    byte
+  }
+
+  fn operand(&self, ram: &[u8]) -> u8 {
+    Cdp1802::read(ram, self.r[self.x as usize])
+  }
+
+  fn add(&mut self, a: u8, b: u8, carry: bool) {
+    let sum = a as u16 + b as u16 + carry as u16;
+    self.d = sum as u8;
+    self.df = sum > 0xFF;
+  }
+
+  // a - b, where DF ends up set if there was no borrow.
+  fn subtract(&mut self, a: u8, b: u8, borrow: bool) {
+    let difference = a as i16 - b as i16 - borrow as i16;
+    self.d = difference as u8;
+    self.df = difference >= 0;
+  }
+
+  fn short_branch(&mut self, ram: &[u8], condition: bool) {
+    let p = self.p as usize;
+    if condition {
+      let target = Cdp1802::read(ram, self.r[p]);
+      self.r[p] = self.r[p] & 0xFF00 | target as u16;
+    } else {
+      self.r[p] = self.r[p].wrapping_add(1);
+    }
+  }
+
+  fn long_branch(&mut self, ram: &[u8], condition: bool) {
+    let p = self.p as usize;
+    if condition {
+      let high = Cdp1802::read(ram, self.r[p]);
+      let low = Cdp1802::read(ram, self.r[p].wrapping_add(1));
+      self.r[p] = (high as u16) << 8 | low as u16;
+    } else {
+      self.r[p] = self.r[p].wrapping_add(2);
+    }
+  }
+
+  fn long_skip(&mut self, condition: bool) {
+    if condition {
+      let p = self.p as usize;
+      self.r[p] = self.r[p].wrapping_add(2);
+    }
+  }
+
+  // Executes a single instruction, and returns the machine cycles it took.
+  pub fn step(&mut self, ram: &mut [u8]) -> u32 {
+    let instruction = self.fetch(ram);
+    let n = (instruction & 0x0F) as usize;
+    let x = self.x as usize;
+
+    match instruction >> 4 {
+      0x0 => {
+        // 00 is IDL, which waits for an interrupt or DMA. Neither ever
+        // comes, so it does nothing.
+        if n != 0 {                                       // LDN
+          self.d = Cdp1802::read(ram, self.r[n]);
+        }
+      },
+      0x1 => self.r[n] = self.r[n].wrapping_add(1),      // INC
+      0x2 => self.r[n] = self.r[n].wrapping_sub(1),      // DEC
+      0x3 => {                                            // Short branches
+        let condition = match n & 0x7 {
+          0x0 => true,                                    // BR / SKP
+          0x1 => self.q,                                  // BQ / BNQ
+          0x2 => self.d == 0,                             // BZ / BNZ
+          0x3 => self.df,                                 // BDF / BNF
+          _ => false                                      // B1-B4 / BN1-BN4
+        };
+        // The second half of the row branches on the opposite condition.
+        self.short_branch(ram, condition != (n >= 0x8));
+      },
+      0x4 => {                                            // LDA
+        self.d = Cdp1802::read(ram, self.r[n]);
+        self.r[n] = self.r[n].wrapping_add(1);
+      },
+      0x5 => Cdp1802::write(ram, self.r[n], self.d),      // STR
+      0x6 => match n {
+        0x0 => self.r[x] = self.r[x].wrapping_add(1),     // IRX
+        0x1..=0x7 => self.r[x] = self.r[x].wrapping_add(1), // OUT
+        0x8 => {},
+        _ => {                                            // INP
+          self.d = 0;
+          Cdp1802::write(ram, self.r[x], 0);
+        }
+      },
+      0x7 => match n {
+        0x0 | 0x1 => {                                    // RET / DIS
+          let value = Cdp1802::read(ram, self.r[x]);
+          self.r[x] = self.r[x].wrapping_add(1);
+          self.x = value >> 4;
+          self.p = value & 0x0F;
+          self.ie = n == 0x0;
+        },
+        0x2 => {                                          // LDXA
+          self.d = Cdp1802::read(ram, self.r[x]);
+          self.r[x] = self.r[x].wrapping_add(1);
+        },
+        0x3 => {                                          // STXD
+          Cdp1802::write(ram, self.r[x], self.d);
+          self.r[x] = self.r[x].wrapping_sub(1);
+        },
+        0x4 => self.add(self.operand(ram), self.d, self.df), // ADC
+        0x5 => self.subtract(self.operand(ram), self.d, !self.df), // SDB
+        0x6 => {                                          // SHRC
+          let carry = self.d & 0x01 != 0;
+          self.d = self.d >> 1 | (self.df as u8) << 7;
+          self.df = carry;
+        },
+        0x7 => self.subtract(self.d, self.operand(ram), !self.df), // SMB
+        0x8 => Cdp1802::write(ram, self.r[x], self.t),    // SAV
+        0x9 => {                                          // MARK
+          self.t = self.x << 4 | self.p;
+          Cdp1802::write(ram, self.r[2], self.t);
+          self.x = self.p;
+          self.r[2] = self.r[2].wrapping_sub(1);
+        },
+        0xA => self.q = false,                            // REQ
+        0xB => self.q = true,                             // SEQ
+        0xC => {                                          // ADCI
+          let immediate = self.fetch(ram);
+          self.add(immediate, self.d, self.df);
+        },
+        0xD => {                                          // SDBI
+          let immediate = self.fetch(ram);
+          self.subtract(immediate, self.d, !self.df);
+        },
+        0xE => {                                          // SHLC
+          let carry = self.d & 0x80 != 0;
+          self.d = self.d << 1 | self.df as u8;
+          self.df = carry;
+        },
+        _ => {                                            // SMBI
+          let immediate = self.fetch(ram);
+          self.subtract(self.d, immediate, !self.df);
+        }
+      },
+      0x8 => self.d = self.r[n] as u8,                    // GLO
+      0x9 => self.d = (self.r[n] >> 8) as u8,             // GHI
+      0xA => self.r[n] = self.r[n] & 0xFF00 | self.d as u16, // PLO
+      0xB => self.r[n] = self.r[n] & 0x00FF | (self.d as u16) << 8, // PHI
+      0xC => match n {                                    // Long branches and skips
+        0x0 => self.long_branch(ram, true),               // LBR
+        0x1 => self.long_branch(ram, self.q),             // LBQ
+        0x2 => self.long_branch(ram, self.d == 0),        // LBZ
+        0x3 => self.long_branch(ram, self.df),            // LBDF
+        0x4 => {},                                        // NOP
+        0x5 => self.long_skip(!self.q),                   // LSNQ
+        0x6 => self.long_skip(self.d != 0),               // LSNZ
+        0x7 => self.long_skip(!self.df),                  // LSNF
+        0x8 => self.long_skip(true),                      // LSKP
+        0x9 => self.long_branch(ram, !self.q),            // LBNQ
+        0xA => self.long_branch(ram, self.d != 0),        // LBNZ
+        0xB => self.long_branch(ram, !self.df),           // LBNF
+        0xC => self.long_skip(self.ie),                   // LSIE
+        0xD => self.long_skip(self.q),                    // LSQ
+        0xE => self.long_skip(self.d == 0),               // LSZ
+        _ => self.long_skip(self.df)                      // LSDF
+      },
+      0xD => self.p = n as u8,                            // SEP
+      0xE => self.x = n as u8,                            // SEX
+      _ => {
+        // Fx with N < 8 work on M(R(X)), and with N >= 8 on the byte after
+        // the instruction. SHR (F6) and SHL (FE) take no operand at all.
+        let operand = match n {
+          0x6 | 0xE => 0,
+          0x0..=0x7 => self.operand(ram),
+          _ => self.fetch(ram)
+        };
+        match n & 0x7 {
+          0x0 => self.d = operand,                        // LDX / LDI
+          0x1 => self.d |= operand,                       // OR / ORI
+          0x2 => self.d &= operand,                       // AND / ANI
+          0x3 => self.d ^= operand,                       // XOR / XRI
+          0x4 => self.add(operand, self.d, false),        // ADD / ADI
+          0x5 => self.subtract(operand, self.d, false),   // SD / SDI
+          0x6 if n == 0x6 => {                            // SHR
+            self.df = self.d & 0x01 != 0;
+            self.d >>= 1;
+          },
+          0x6 => {                                        // SHL
+            self.df = self.d & 0x80 != 0;
+            self.d <<= 1;
+          },
+          _ => self.subtract(self.d, operand, false)      // SM / SMI
+        }
+      }
+    }
+
+    // Long branches and skips take 3 cycles, everything else 2.
+    if instruction >> 4 == 0xC { 3 } else { 2 }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Runs code placed at 0x000 with R3 as the program counter, the way the
+  // VIP interpreter calls 0nnn subroutines, until it returns with SEP R4.
+  fn run(code: &[u8], ram: &mut [u8; 0x1000], cpu: &mut Cdp1802) {
+    ram[..code.len()].copy_from_slice(code);
+    cpu.p = 3;
+    cpu.x = 2;
+    cpu.r[3] = 0x000;
+    for _ in 0..10_000 {
+      cpu.step(ram);
+      if cpu.p == 4 {
+        return;
+      }
+    }
+    panic!("the subroutine never returned");
+  }
+
+  #[test]
+  fn clears_the_display_page() {
+    let mut ram = [0xFF; 0x1000];
+    let mut cpu = Cdp1802::new();
+    cpu.r[0xB] = 0x0F00;
+    run(&[
+      0x9B,       // GHI RB
+      0xBF,       // PHI RF
+      0xF8, 0x00, // LDI 00
+      0xAF,       // PLO RF
+      0xF8, 0x00, // LDI 00
+      0x5F,       // STR RF
+      0x1F,       // INC RF
+      0x8F,       // GLO RF
+      0x3A, 0x05, // BNZ 05
+      0xD4        // SEP R4
+    ], &mut ram, &mut cpu);
+
+    assert!(ram[0xF00..].iter().all(|byte| *byte == 0));
+    assert_eq!(ram[0xEFF], 0xFF);
+  }
+
+  #[test]
+  fn arithmetic_sets_df() {
+    let mut ram = [0; 0x1000];
+    let mut cpu = Cdp1802::new();
+    cpu.r[2] = 0x100;
+    ram[0x100] = 0x80;
+    // 0x90 + 0x80, then 0x10 - 0x20.
+    run(&[0xF8, 0x90, 0xF4, 0xA5, 0xF8, 0x10, 0xFF, 0x20, 0xD4], &mut ram, &mut cpu);
+
+    assert_eq!(cpu.r[5] & 0xFF, 0x10);
+    assert_eq!(cpu.d, 0xF0);
+    assert!(!cpu.df);
+  }
+
+  #[test]
+  fn mark_and_ret_save_and_restore_x_and_p() {
+    let mut ram = [0; 0x1000];
+    let mut cpu = Cdp1802::new();
+    cpu.r[2] = 0x100;
+    // MARK saves X and P (2 and 3) at R2, SEX R2 and INC R2 point back at
+    // them, and RET restores them, which carries on after the RET.
+    run(&[0x79, 0xE2, 0x12, 0x70, 0x7B, 0xD4], &mut ram, &mut cpu);
+
+    assert_eq!(ram[0x100], 0x23);
+    assert_eq!(cpu.r[2], 0x101);
+    assert!(cpu.q);
+  }
+
+  #[test]
+  fn long_branches_and_skips() {
+    let mut ram = [0; 0x1000];
+    ram[0x10..0x13].copy_from_slice(&[0xF8, 0x42, 0xD4]); // LDI 42, SEP R4
+    let mut cpu = Cdp1802::new();
+    run(&[
+      0xF8, 0x00,      // LDI 00
+      0xCE,            // LSZ
+      0xF8, 0x01,      //   LDI 01, skipped
+      0xC2, 0x00, 0x10 // LBZ 0010
+    ], &mut ram, &mut cpu);
+
+    assert_eq!(cpu.d, 0x42);
+  }
+}
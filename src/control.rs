@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+use crate::TIMER_HZ;
+
+// Speeds the emulator can run at, as multiples of the normal speed. Normal
+// speed is NORMAL_SPEED.
+const SPEEDS: [(u32, u32); 9] = [
+  (1, 8), (1, 4), (1, 2), (3, 4), (1, 1), (3, 2), (2, 1), (4, 1), (8, 1)
+];
+const NORMAL_SPEED: usize = 4;
+
+// What the user wants the emulator to be doing: running or paused, and how
+// fast.
+pub struct Control {
+  paused: bool,
+  // Run until the end of the current frame, then pause again.
+  advancing_frame: bool,
+  speed: usize,
+  // Run as fast as possible, ignoring speed.
+  uncapped: bool,
+  // Instructions per second at normal speed.
+  rate: u32
+}
+
+impl Default for Control {
+  fn default() -> Control {
+    Control::new()
+  }
+}
+
+impl Control {
+  pub fn new() -> Control {
+    Control {
+      paused: false,
+      advancing_frame: false,
+      speed: NORMAL_SPEED,
+      uncapped: false,
+      rate: TIMER_HZ as u32
+    }
+  }
+
+  pub fn rate(&self) -> u32 {
+    self.rate
+  }
+
+  // Programs that come with their own speed (Octo cartridges have a tickrate)
+  // change the normal speed.
+  pub fn set_rate(&mut self, rate: u32) {
+    self.rate = rate.max(1);
+  }
+
+  // Whether instructions should be executed right now.
+  pub fn is_running(&self) -> bool {
+    !self.paused || self.advancing_frame
+  }
+
+  pub fn toggle_pause(&mut self) {
+    self.paused = !self.paused;
+    self.advancing_frame = false;
+  }
+
+  // Only does something while paused.
+  pub fn advance_frame(&mut self) {
+    if self.paused {
+      self.advancing_frame = true;
+    }
+  }
+
+  // Called at every vblank.
+  pub fn end_frame(&mut self) {
+    self.advancing_frame = false;
+  }
+
+  pub fn faster(&mut self) {
+    self.speed = (self.speed + 1).min(SPEEDS.len() - 1);
+    self.uncapped = false;
+  }
+
+  pub fn slower(&mut self) {
+    self.speed = self.speed.saturating_sub(1);
+    self.uncapped = false;
+  }
+
+  pub fn normal_speed(&mut self) {
+    self.speed = NORMAL_SPEED;
+    self.uncapped = false;
+  }
+
+  pub fn toggle_uncapped(&mut self) {
+    self.uncapped = !self.uncapped;
+  }
+
+  // How fast emulated time goes compared to real time, or None if it doesn't
+  // follow real time at all (paused or uncapped).
+  pub fn speed_factor(&self) -> Option<f64> {
+    if self.paused || self.uncapped {
+      return None;
+    }
+
+    let (numerator, denominator) = SPEEDS[self.speed];
+    Some(numerator as f64 / denominator as f64)
+  }
+
+  // Real time a single instruction should take, or None to run uncapped.
+  pub fn instruction_period(&self) -> Option<Duration> {
+    if self.uncapped {
+      return None;
+    }
+
+    let (numerator, denominator) = SPEEDS[self.speed];
+    Some(Duration::from_secs(1) * denominator / (self.rate * numerator))
+  }
+
+  pub fn label(&self) -> String {
+    let (numerator, denominator) = SPEEDS[self.speed];
+    let speed = if self.uncapped {
+      "uncapped".to_string()
+    } else if denominator == 1 {
+      format!("{}x", numerator)
+    } else {
+      format!("{}/{}x", numerator, denominator)
+    };
+
+    if self.paused {
+      format!("paused ({})", speed)
+    } else {
+      speed
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn frames_advance_one_at_a_time_while_paused() {
+    let mut control = Control::new();
+    // Not while running.
+    control.advance_frame();
+    control.toggle_pause();
+    assert!(!control.is_running());
+    assert_eq!(control.label(), "paused (1x)");
+
+    control.advance_frame();
+    assert!(control.is_running());
+    control.end_frame();
+    assert!(!control.is_running());
+
+    // Pausing again in the middle of a frame doesn't finish it.
+    control.advance_frame();
+    control.toggle_pause();
+    control.toggle_pause();
+    assert!(!control.is_running());
+
+    control.toggle_pause();
+    assert!(control.is_running());
+    assert_eq!(control.label(), "1x");
+  }
+
+  #[test]
+  fn speeds_stop_at_the_ends() {
+    let mut control = Control::new();
+    control.set_rate(500);
+    assert_eq!(control.instruction_period(), Some(Duration::from_millis(2)));
+
+    for _ in 0..SPEEDS.len() {
+      control.slower();
+    }
+    assert_eq!(control.label(), "1/8x");
+    assert_eq!(control.speed_factor(), Some(0.125));
+    assert_eq!(control.instruction_period(), Some(Duration::from_millis(16)));
+
+    for _ in 0..SPEEDS.len() {
+      control.faster();
+    }
+    assert_eq!(control.label(), "8x");
+    assert_eq!(control.speed_factor(), Some(8.0));
+
+    control.normal_speed();
+    assert_eq!(control.speed_factor(), Some(1.0));
+  }
+
+  #[test]
+  fn uncapped_and_paused_machines_dont_follow_real_time() {
+    let mut control = Control::new();
+    control.toggle_uncapped();
+    assert_eq!(control.label(), "uncapped");
+    assert_eq!(control.speed_factor(), None);
+    assert_eq!(control.instruction_period(), None);
+
+    // Picking a speed caps it again.
+    control.faster();
+    assert_eq!(control.label(), "3/2x");
+
+    control.toggle_pause();
+    assert_eq!(control.speed_factor(), None);
+    assert!(control.instruction_period().is_some());
+  }
+}
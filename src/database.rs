@@ -0,0 +1,74 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::cartridge::{self, CartridgeOptions};
+
+// What's known about ROMs that don't say how they want to be run (anything
+// but Octo cartridges), by the SHA-1 of their contents. The file is a JSON
+// object like:
+//
+//   {"0b1e4fa4...": {"title": "IBM Logo", "options": {"tickrate": 15, ...}}}
+//
+// where the options are the ones Octo saves in its cartridges.
+#[derive(Clone, Debug, Default)]
+pub struct RomDatabase {
+  entries: HashMap<String, DatabaseEntry>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DatabaseEntry {
+  pub title: Option<String>,
+  pub options: CartridgeOptions
+}
+
+// The lowercase hex SHA-1 of bytes, which ROMs are looked up by.
+pub fn sha1(bytes: &[u8]) -> String {
+  sha1_smol::Sha1::from(bytes).digest().to_string()
+}
+
+impl RomDatabase {
+  pub fn parse(json: &str) -> Result<RomDatabase, String> {
+    let value: Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+    let roms = value.as_object().ok_or_else(|| "it's not a JSON object".to_string())?;
+
+    let entries = roms.iter()
+      .map(|(hash, rom)| (hash.to_ascii_lowercase(), DatabaseEntry {
+        title: rom["title"].as_str().map(str::to_string),
+        options: cartridge::parse_options(&rom["options"])
+      }))
+      .collect();
+    Ok(RomDatabase { entries })
+  }
+
+  pub fn load(path: &Path) -> Result<RomDatabase, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    RomDatabase::parse(&json).map_err(|e| format!("Couldn't parse {}: {}", path.display(), e))
+  }
+
+  pub fn lookup(&self, rom: &[u8]) -> Option<&DatabaseEntry> {
+    self.entries.get(&sha1(rom))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn roms_are_found_by_their_hash() {
+    let rom = [0x12, 0x00];
+    let database = RomDatabase::parse(&format!(
+      r#"{{"{}": {{"title": "Loop", "options": {{"tickrate": 7, "jumpQuirks": true}}}}}}"#,
+      sha1(&rom).to_uppercase()
+    )).unwrap();
+
+    let entry = database.lookup(&rom).unwrap();
+    assert_eq!(entry.title.as_deref(), Some("Loop"));
+    assert_eq!(entry.options.tickrate, Some(7));
+    assert!(entry.options.quirks.jump && !entry.options.quirks.shift);
+    assert!(database.lookup(&[0x00, 0xE0]).is_none());
+  }
+}
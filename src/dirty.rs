@@ -0,0 +1,90 @@
+use crate::{SCREEN_LINES, SCREEN_COLUMNS};
+
+// Keeps track of which pixels have changed since the last time the screen was
+// drawn. Every display line is stored as a bitmask (bit j is column j), so
+// marking and merging changes is cheap, and the renderer can skip whole lines
+// that haven't been touched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirtyTracker {
+  rows: [u64; SCREEN_LINES]
+}
+
+impl Default for DirtyTracker {
+  fn default() -> DirtyTracker {
+    DirtyTracker::new()
+  }
+}
+
+impl DirtyTracker {
+  pub fn new() -> DirtyTracker {
+    DirtyTracker {
+      rows: [0; SCREEN_LINES]
+    }
+  }
+
+  // A tracker with every pixel marked, used to force a full redraw.
+  pub fn all() -> DirtyTracker {
+    let mut tracker = DirtyTracker::new();
+    tracker.mark_all();
+    tracker
+  }
+
+  pub fn mark(&mut self, line: usize, column: usize) {
+    self.rows[line] |= 1 << column;
+  }
+
+  // Marks the columns of a line set in columns.
+  pub fn mark_line(&mut self, line: usize, columns: u64) {
+    self.rows[line] |= columns;
+  }
+
+  pub fn mark_all(&mut self) {
+    // SCREEN_COLUMNS is 64, so every bit of the u64 is a column.
+    self.rows = [u64::MAX >> (64 - SCREEN_COLUMNS); SCREEN_LINES];
+  }
+
+  pub fn merge(&mut self, other: &DirtyTracker) {
+    for (row, other_row) in self.rows.iter_mut().zip(other.rows.iter()) {
+      *row |= *other_row;
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.rows.iter().all(|row| *row == 0)
+  }
+
+  // Returns the changed columns of a display line, as a bitmask.
+  pub fn line(&self, line: usize) -> u64 {
+    self.rows[line]
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn changes_are_kept_per_line() {
+    let mut tracker = DirtyTracker::new();
+    assert!(tracker.is_empty());
+    tracker.mark(3, 0);
+    tracker.mark(3, 63);
+    tracker.mark_line(4, 0b1010);
+    assert_eq!(tracker.line(3), 1 << 63 | 1);
+    assert_eq!(tracker.line(4), 0b1010);
+    assert_eq!(tracker.line(5), 0);
+
+    let mut other = DirtyTracker::new();
+    other.mark_line(4, 0b0101);
+    other.mark(31, 7);
+    tracker.merge(&other);
+    assert_eq!(tracker.line(4), 0b1111);
+    assert_eq!(tracker.line(31), 1 << 7);
+  }
+
+  #[test]
+  fn all_marks_every_pixel() {
+    let tracker = DirtyTracker::all();
+    assert!((0..SCREEN_LINES).all(|line| tracker.line(line) == u64::MAX));
+  }
+}
@@ -0,0 +1,168 @@
+use crate::{SCREEN_LINES, SCREEN_COLUMNS};
+use crate::dirty::DirtyTracker;
+use crate::framebuffer::Framebuffer;
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterKind {
+  // Present every change as soon as the instruction that made it is executed.
+  None,
+  // Present the display once per frame, at vblank, like the COSMAC VIP did.
+  // Sprites that are erased and redrawn within a frame never flicker.
+  Vblank,
+  // Present once per frame, keeping every pixel lit for a few frames after it
+  // turns off, like the phosphor of an old CRT.
+  Persistence
+}
+
+// Sits between the chip8 display and the renderer, and decides what the
+// renderer gets to see and when. The renderer only gets a display and the
+// cells that changed since the last presented frame, so any renderer works
+// with any filter.
+pub struct DisplayFilter {
+  kind: FilterKind,
+  persistence_frames: u8,
+
+  // What the renderer has been given so far.
+  output: Framebuffer,
+  // Changes to the chip8 display that haven't been presented yet.
+  pending: DirtyTracker,
+  // Whether a pixel has been lit at any point during the current frame, and
+  // for how many more frames it should stay lit.
+  lit_this_frame: Framebuffer,
+  glow: [[u8; SCREEN_COLUMNS]; SCREEN_LINES]
+}
+
+impl DisplayFilter {
+  pub fn new(kind: FilterKind, persistence_frames: u8, display: &Framebuffer) -> DisplayFilter {
+    DisplayFilter {
+      kind,
+      persistence_frames,
+      output: *display,
+      pending: DirtyTracker::new(),
+      lit_this_frame: *display,
+      glow: [[0; SCREEN_COLUMNS]; SCREEN_LINES]
+    }
+  }
+
+  pub fn output(&self) -> &Framebuffer {
+    &self.output
+  }
+
+  // Feeds the filter with the changes the last instruction made to display.
+  // vblank tells whether a frame ends after this instruction. Returns the
+  // cells of output() that changed, if output() should be presented now.
+  pub fn update(&mut self, display: &Framebuffer, dirty: &DirtyTracker, vblank: bool) -> Option<DirtyTracker> {
+    self.pending.merge(dirty);
+
+    match self.kind {
+      FilterKind::None => Some(self.present(display)),
+      FilterKind::Vblank => {
+        if vblank { Some(self.present(display)) } else { None }
+      },
+      FilterKind::Persistence => {
+        self.remember_lit(display, dirty);
+        if vblank { Some(self.present_persistent(display)) } else { None }
+      }
+    }
+  }
+
+  // Copies the pending changes to the output.
+  fn present(&mut self, display: &Framebuffer) -> DirtyTracker {
+    let changes = std::mem::take(&mut self.pending);
+
+    for i in 0..SCREEN_LINES {
+      self.output.copy_from(display, i, changes.line(i));
+    }
+
+    changes
+  }
+
+  fn remember_lit(&mut self, display: &Framebuffer, dirty: &DirtyTracker) {
+    for i in 0..SCREEN_LINES {
+      let lit = self.lit_this_frame.line(i) | display.line(i) & dirty.line(i);
+      self.lit_this_frame.set_line(i, lit);
+    }
+  }
+
+  // A pixel is shown if it has been lit during this frame, or during one of
+  // the previous persistence_frames frames.
+  fn present_persistent(&mut self, display: &Framebuffer) -> DirtyTracker {
+    let mut changes = DirtyTracker::new();
+    self.pending = DirtyTracker::new();
+
+    for i in 0..SCREEN_LINES {
+      for j in 0..SCREEN_COLUMNS {
+        let shown = if self.lit_this_frame.pixel(i, j) {
+          self.glow[i][j] = self.persistence_frames;
+          true
+        } else {
+          let glowing = self.glow[i][j] > 0;
+          self.glow[i][j] = self.glow[i][j].saturating_sub(1);
+          glowing
+        };
+
+        if shown != self.output.pixel(i, j) {
+          self.output.set_pixel(i, j, shown);
+          changes.mark(i, j);
+        }
+      }
+    }
+
+    // The next frame starts with whatever is on the display right now.
+    self.lit_this_frame = *display;
+
+    changes
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Turns a pixel on or off, as an instruction would, and feeds the change to
+  // filter. Returns whether the filter presented the pixel lit, if it
+  // presented anything.
+  fn draw(filter: &mut DisplayFilter, display: &mut Framebuffer, on: bool, vblank: bool) -> Option<bool> {
+    let mut dirty = DirtyTracker::new();
+    if display.pixel(3, 4) != on {
+      display.set_pixel(3, 4, on);
+      dirty.mark(3, 4);
+    }
+    filter.update(display, &dirty, vblank).map(|_| filter.output().pixel(3, 4))
+  }
+
+  #[test]
+  fn changes_wait_for_vblank() {
+    let mut display = Framebuffer::new();
+    let mut none = DisplayFilter::new(FilterKind::None, 0, &display);
+    assert_eq!(draw(&mut none, &mut display, true, false), Some(true));
+
+    let mut display = Framebuffer::new();
+    let mut vblank = DisplayFilter::new(FilterKind::Vblank, 0, &display);
+    assert_eq!(draw(&mut vblank, &mut display, true, false), None);
+    assert!(!vblank.output().pixel(3, 4));
+    assert_eq!(draw(&mut vblank, &mut display, true, true), Some(true));
+  }
+
+  #[test]
+  fn persistence_keeps_pixels_lit_for_a_few_frames() {
+    let mut display = Framebuffer::new();
+    let mut filter = DisplayFilter::new(FilterKind::Persistence, 2, &display);
+    assert_eq!(draw(&mut filter, &mut display, true, true), Some(true));
+
+    // Off during the next frame, which still counts as lit in it, and then
+    // glowing for 2 more frames.
+    assert_eq!(draw(&mut filter, &mut display, false, false), None);
+    let frames: Vec<Option<bool>> = (0..4).map(|_| draw(&mut filter, &mut display, false, true)).collect();
+    assert_eq!(frames, [Some(true), Some(true), Some(true), Some(false)]);
+  }
+
+  #[test]
+  fn persistence_shows_pixels_that_flicker_within_a_frame() {
+    let mut display = Framebuffer::new();
+    let mut filter = DisplayFilter::new(FilterKind::Persistence, 0, &display);
+    assert_eq!(draw(&mut filter, &mut display, true, false), None);
+    assert_eq!(draw(&mut filter, &mut display, false, true), Some(true));
+    assert_eq!(draw(&mut filter, &mut display, false, true), Some(false));
+  }
+}
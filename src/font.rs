@@ -0,0 +1,196 @@
+use std::fs;
+use std::path::Path;
+
+// Bytes taken by the small font (16 digits, 5 bytes each) and the big one
+// (16 digits, 10 bytes each).
+pub const SMALL_FONT_SIZE: usize = 5*16;
+pub const BIG_FONT_SIZE: usize = 10*16;
+pub const FONT_SIZE: usize = SMALL_FONT_SIZE + BIG_FONT_SIZE;
+
+// The hex digits Fx29 points at, and the big ones Fx30 points at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Font {
+  pub small: [u8; SMALL_FONT_SIZE],
+  pub big: [u8; BIG_FONT_SIZE]
+}
+
+// The font most interpreters use, which came with CHIP-48.
+const CHIP48: [u8; SMALL_FONT_SIZE] = [
+  0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+  0x20, 0x60, 0x20, 0x20, 0x70, // 1
+  0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+  0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+  0x90, 0x90, 0xF0, 0x10, 0x10, // 4
+  0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+  0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+  0xF0, 0x10, 0x20, 0x40, 0x40, // 7
+  0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+  0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+  0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+  0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
+  0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+  0xE0, 0x90, 0x90, 0x90, 0xE0, // D
+  0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+  0xF0, 0x80, 0xF0, 0x80, 0x80  // F
+];
+
+// The COSMAC VIP's, from the original interpreter.
+const VIP: [u8; SMALL_FONT_SIZE] = [
+  0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
+  0x60, 0x20, 0x20, 0x20, 0x70, // 1
+  0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
+  0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
+  0xA0, 0xA0, 0xF0, 0x20, 0x20, // 4
+  0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
+  0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
+  0xF0, 0x10, 0x10, 0x10, 0x10, // 7
+  0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
+  0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
+  0xF0, 0x90, 0xF0, 0x90, 0x90, // A
+  0xF0, 0x50, 0x70, 0x50, 0xF0, // B
+  0xF0, 0x80, 0x80, 0x80, 0xF0, // C
+  0xF0, 0x50, 0x50, 0x50, 0xF0, // D
+  0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
+  0xF0, 0x80, 0xF0, 0x80, 0x80  // F
+];
+
+// The DREAM 6800's digits are only 3 pixels wide.
+const DREAM6800: [u8; SMALL_FONT_SIZE] = [
+  0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+  0x40, 0x40, 0x40, 0x40, 0x40, // 1
+  0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+  0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+  0x80, 0xA0, 0xA0, 0xE0, 0x20, // 4
+  0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+  0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+  0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+  0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+  0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+  0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+  0xC0, 0xA0, 0xE0, 0xA0, 0xC0, // B
+  0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+  0xC0, 0xA0, 0xA0, 0xA0, 0xC0, // D
+  0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+  0xE0, 0x80, 0xC0, 0x80, 0x80  // F
+];
+
+// The ETI-660's, 3 pixels wide as well.
+const ETI660: [u8; SMALL_FONT_SIZE] = [
+  0xE0, 0xA0, 0xA0, 0xA0, 0xE0, // 0
+  0x20, 0x20, 0x20, 0x20, 0x20, // 1
+  0xE0, 0x20, 0xE0, 0x80, 0xE0, // 2
+  0xE0, 0x20, 0xE0, 0x20, 0xE0, // 3
+  0xA0, 0xA0, 0xE0, 0x20, 0x20, // 4
+  0xE0, 0x80, 0xE0, 0x20, 0xE0, // 5
+  0xE0, 0x80, 0xE0, 0xA0, 0xE0, // 6
+  0xE0, 0x20, 0x20, 0x20, 0x20, // 7
+  0xE0, 0xA0, 0xE0, 0xA0, 0xE0, // 8
+  0xE0, 0xA0, 0xE0, 0x20, 0xE0, // 9
+  0xE0, 0xA0, 0xE0, 0xA0, 0xA0, // A
+  0x80, 0x80, 0xE0, 0xA0, 0xE0, // B
+  0xE0, 0x80, 0x80, 0x80, 0xE0, // C
+  0x20, 0x20, 0xE0, 0xA0, 0xE0, // D
+  0xE0, 0x80, 0xE0, 0x80, 0xE0, // E
+  0xE0, 0x80, 0xC0, 0x80, 0x80  // F
+];
+
+// SUPER-CHIP's big font. It only has the decimal digits, so A-F are blank.
+const SCHIP_BIG: [u8; BIG_FONT_SIZE] = [
+  0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+  0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+  0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+  0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+  0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+  0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+  0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+  0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+  0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+  0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // A
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // B
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // C
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // D
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // E
+  0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00  // F
+];
+
+const BUILT_IN: [(&str, [u8; SMALL_FONT_SIZE]); 4] = [
+  ("chip48", CHIP48),
+  ("vip", VIP),
+  ("dream6800", DREAM6800),
+  ("eti660", ETI660)
+];
+
+impl Default for Font {
+  fn default() -> Font {
+    Font { small: CHIP48, big: SCHIP_BIG }
+  }
+}
+
+impl Font {
+  // Looks a font up by name, or else reads it from the file with that name.
+  //
+  // Font files hold the 80 bytes of the small font, optionally followed by
+  // the 160 bytes of a big one.
+  pub fn lookup(name: &str) -> Result<Font, String> {
+    if let Some((_, small)) = BUILT_IN.iter().find(|(font_name, _)| *font_name == name) {
+      return Ok(Font { small: *small, ..Font::default() });
+    }
+
+    let path = Path::new(name);
+    if !path.is_file() {
+      let names: Vec<&str> = BUILT_IN.iter().map(|(font_name, _)| *font_name).collect();
+      return Err(format!("Unknown font '{}'. Built-in fonts are: {}", name, names.join(", ")));
+    }
+
+    let contents = fs::read(path).map_err(|e| format!("Couldn't read {}: {}", name, e))?;
+    let mut font = Font::default();
+    match contents.len() {
+      SMALL_FONT_SIZE => font.small.copy_from_slice(&contents),
+      FONT_SIZE => {
+        font.small.copy_from_slice(&contents[..SMALL_FONT_SIZE]);
+        font.big.copy_from_slice(&contents[SMALL_FONT_SIZE..]);
+      },
+      size => return Err(format!("{} is {} bytes long, but fonts are {} or {} bytes long",
+        name, size, SMALL_FONT_SIZE, FONT_SIZE))
+    }
+    Ok(font)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn built_in_fonts_are_looked_up_by_name() {
+    assert_eq!(Font::lookup("chip48").unwrap(), Font::default());
+    let vip = Font::lookup("vip").unwrap();
+    assert_eq!(vip.small, VIP);
+    assert_eq!(vip.big, SCHIP_BIG);
+    assert!(Font::lookup("no-such-font").unwrap_err().contains("chip48, vip, dream6800, eti660"));
+  }
+
+  #[test]
+  fn font_files_hold_one_or_both_fonts() {
+    let path = std::env::temp_dir().join(format!("c8rs-font-{}", std::process::id()));
+    let name = path.to_str().unwrap();
+
+    fs::write(&path, [0xAA; SMALL_FONT_SIZE]).unwrap();
+    let font = Font::lookup(name).unwrap();
+    assert_eq!(font.small, [0xAA; SMALL_FONT_SIZE]);
+    assert_eq!(font.big, SCHIP_BIG);
+
+    let mut both = vec![0xAA; SMALL_FONT_SIZE];
+    both.extend([0xBB; BIG_FONT_SIZE]);
+    fs::write(&path, &both).unwrap();
+    let font = Font::lookup(name).unwrap();
+    assert_eq!(font.small, [0xAA; SMALL_FONT_SIZE]);
+    assert_eq!(font.big, [0xBB; BIG_FONT_SIZE]);
+
+    fs::write(&path, [0xAA; 81]).unwrap();
+    let error = Font::lookup(name).unwrap_err();
+    fs::remove_file(&path).unwrap();
+    assert!(error.contains("is 81 bytes long"));
+  }
+}
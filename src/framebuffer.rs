@@ -0,0 +1,100 @@
+use crate::{SCREEN_LINES, SCREEN_COLUMNS};
+
+// The chip8 display. Like DirtyTracker, every line is a u64 where bit j is
+// column j, so a sprite row is drawn with a shift and an XOR, and copying or
+// comparing the whole display is cheap.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Framebuffer {
+  rows: [u64; SCREEN_LINES]
+}
+
+// SCREEN_COLUMNS is 64, so every bit of the u64 is a column.
+const FULL_LINE: u64 = u64::MAX >> (64 - SCREEN_COLUMNS);
+
+impl Default for Framebuffer {
+  fn default() -> Framebuffer {
+    Framebuffer::new()
+  }
+}
+
+impl Framebuffer {
+  // All pixels off.
+  pub fn new() -> Framebuffer {
+    Framebuffer {
+      rows: [0; SCREEN_LINES]
+    }
+  }
+
+  // All pixels on.
+  pub fn lit() -> Framebuffer {
+    Framebuffer {
+      rows: [FULL_LINE; SCREEN_LINES]
+    }
+  }
+
+  pub fn pixel(&self, line: usize, column: usize) -> bool {
+    self.rows[line] & 1 << column != 0
+  }
+
+  pub fn set_pixel(&mut self, line: usize, column: usize, on: bool) {
+    if on {
+      self.rows[line] |= 1 << column;
+    } else {
+      self.rows[line] &= !(1 << column);
+    }
+  }
+
+  // Returns the pixels of a line, as a bitmask.
+  pub fn line(&self, line: usize) -> u64 {
+    self.rows[line]
+  }
+
+  pub fn set_line(&mut self, line: usize, pixels: u64) {
+    self.rows[line] = pixels & FULL_LINE;
+  }
+
+  // Flips the pixels of a line set in pixels, and returns whether any of
+  // them was on.
+  pub fn xor_line(&mut self, line: usize, pixels: u64) -> bool {
+    let collision = self.rows[line] & pixels != 0;
+    self.rows[line] ^= pixels & FULL_LINE;
+    collision
+  }
+
+  // Turns the pixels set in mask to what they are in other.
+  pub fn copy_from(&mut self, other: &Framebuffer, line: usize, mask: u64) {
+    self.rows[line] = self.rows[line] & !mask | other.rows[line] & mask;
+  }
+
+  // A line of text per display line, with # for pixels that are on and .
+  // for the ones that are off.
+  pub fn to_text(&self) -> String {
+    let mut text = String::with_capacity(SCREEN_LINES * (SCREEN_COLUMNS + 1));
+    for line in 0..SCREEN_LINES {
+      text.extend((0..SCREEN_COLUMNS).map(|column| if self.pixel(line, column) { '#' } else { '.' }));
+      text.push('\n');
+    }
+    text
+  }
+}
+
+// Turns a sprite row (or a byte of the VIP's display memory), whose leftmost
+// pixel is its highest bit, into line pixels starting at column 0.
+pub fn byte_pixels(byte: u8) -> u64 {
+  byte.reverse_bits() as u64
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn xor_line_reports_collisions() {
+    let mut framebuffer = Framebuffer::new();
+    assert!(!framebuffer.xor_line(3, byte_pixels(0xC0) << 10));
+    assert!(framebuffer.pixel(3, 10) && framebuffer.pixel(3, 11) && !framebuffer.pixel(3, 12));
+
+    assert!(framebuffer.xor_line(3, byte_pixels(0x40) << 10));
+    assert!(framebuffer.pixel(3, 10) && !framebuffer.pixel(3, 11));
+  }
+}
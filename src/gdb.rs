@@ -0,0 +1,559 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use crate::c8::Chip8;
+
+// A stub for the GDB remote serial protocol, so that gdb (or anything else
+// that speaks it) can stop, step and inspect the emulator:
+//
+//   c8rs rom.ch8 --gdb 127.0.0.1:1234
+//   (gdb) target remote 127.0.0.1:1234
+//
+// The registers are V0-VF, I, PC, SP, DT and ST, in that order (see
+// target_xml). I and PC are sent little-endian, like most targets gdb knows.
+
+// Register numbers, after V0-VF.
+const I: usize = 16;
+const PC: usize = 17;
+const SP: usize = 18;
+const DT: usize = 19;
+const ST: usize = 20;
+const REGISTERS: usize = 21;
+
+// What a stop reply says stopped the machine.
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// How often listen checks whether to give up, while nobody connects.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+
+// What the emulator should do next, after poll.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+  // Execute an instruction, and call executed.
+  Run,
+  // Nothing, the debugger has the machine stopped.
+  Wait,
+  // The debugger is gone, and the emulator runs on its own.
+  Detach,
+  // The debugger wants the emulator to quit.
+  Kill
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+  Halted,
+  Running,
+  Stepping
+}
+
+pub struct GdbStub {
+  stream: TcpStream,
+  input: Vec<u8>,          // Received, not handled yet
+  last_reply: Vec<u8>,     // Sent again if the debugger asks for it
+  no_ack: bool,            // After QStartNoAckMode
+  breakpoints: BTreeSet<u16>,
+  state: State
+}
+
+fn checksum(data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<Vec<u8>> {
+  if !text.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_number(text: &str) -> Option<usize> {
+  usize::from_str_radix(text, 16).ok()
+}
+
+// addr,length as in m and M packets. Ranges that don't fit in a usize are
+// refused, so that address + length can't overflow.
+fn parse_range(text: &str) -> Option<(usize, usize)> {
+  let (address, length) = text.split_once(',')?;
+  let (address, length) = (parse_number(address)?, parse_number(length)?);
+  address.checked_add(length)?;
+  Some((address, length))
+}
+
+// What gdb learns the registers from.
+pub fn target_xml() -> String {
+  let mut registers: Vec<String> = (0..0x10)
+    .map(|x| format!("    <reg name=\"v{:x}\" bitsize=\"8\" type=\"uint8\"/>", x))
+    .collect();
+  registers.push("    <reg name=\"i\" bitsize=\"16\" type=\"data_ptr\"/>".to_string());
+  registers.push("    <reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>".to_string());
+  for name in ["sp", "dt", "st"] {
+    registers.push(format!("    <reg name=\"{}\" bitsize=\"8\" type=\"uint8\"/>", name));
+  }
+  format!(
+    "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.c8rs.chip8\">\n{}\n  </feature>\n</target>\n",
+    registers.join("\n")
+  )
+}
+
+// The bytes of register n, as sent to the debugger.
+fn read_register(chip8: &Chip8, n: usize) -> Vec<u8> {
+  match n {
+    0..=15 => vec![chip8.registers()[n]],
+    I => chip8.index().to_le_bytes().to_vec(),
+    PC => chip8.pc().to_le_bytes().to_vec(),
+    SP => vec![chip8.sp()],
+    DT => vec![chip8.dt()],
+    ST => vec![chip8.st()],
+    _ => vec![]
+  }
+}
+
+// Sets register n from the bytes the debugger sent, returning false if they
+// don't fit.
+fn write_register(chip8: &mut Chip8, n: usize, bytes: &[u8]) -> bool {
+  match (n, bytes) {
+    (0..=15, [value]) => chip8.set_register(n, *value),
+    (I, [low, high]) => chip8.set_index(u16::from_le_bytes([*low, *high])),
+    (PC, [low, high]) => chip8.set_pc(u16::from_le_bytes([*low, *high]) & 0xfff),
+    (SP, [value]) => chip8.set_sp(*value),
+    (DT, [value]) => chip8.set_timers(*value, chip8.st()),
+    (ST, [value]) => chip8.set_timers(chip8.dt(), *value),
+    _ => return false
+  }
+  true
+}
+
+fn register_size(n: usize) -> usize {
+  if n == I || n == PC { 2 } else { 1 }
+}
+
+impl GdbStub {
+  // Waits for a debugger to connect on address (e.g. 127.0.0.1:1234). Every
+  // now and then, give_up is asked whether to stop waiting (say, because of
+  // Ctrl-C), and then this returns None.
+  pub fn listen(address: &str, mut give_up: impl FnMut() -> bool) -> io::Result<Option<GdbStub>> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    loop {
+      match listener.accept() {
+        Ok((stream, _)) => {
+          stream.set_nonblocking(false)?;
+          return GdbStub::connected(stream).map(Some);
+        },
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+          if give_up() {
+            return Ok(None);
+          }
+          thread::sleep(ACCEPT_INTERVAL);
+        },
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+        Err(e) => return Err(e)
+      }
+    }
+  }
+
+  pub fn accept(listener: &TcpListener) -> io::Result<GdbStub> {
+    let (stream, _) = listener.accept()?;
+    GdbStub::connected(stream)
+  }
+
+  fn connected(stream: TcpStream) -> io::Result<GdbStub> {
+    stream.set_nodelay(true)?;
+    Ok(GdbStub {
+      stream,
+      input: Vec::new(),
+      last_reply: Vec::new(),
+      no_ack: false,
+      breakpoints: BTreeSet::new(),
+      // Debuggers expect the machine stopped when they connect.
+      state: State::Halted
+    })
+  }
+
+  // Handles whatever the debugger sent, without waiting for more, and says
+  // what the emulator should do next.
+  pub fn poll(&mut self, chip8: &mut Chip8) -> io::Result<Action> {
+    let mut buffer = [0; 4096];
+    self.stream.set_nonblocking(true)?;
+    let received = loop {
+      match self.stream.read(&mut buffer) {
+        Ok(0) => break None,
+        Ok(length) => self.input.extend_from_slice(&buffer[..length]),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Some(()),
+        Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+        Err(e) => {
+          self.stream.set_nonblocking(false)?;
+          return Err(e);
+        }
+      }
+    };
+    self.stream.set_nonblocking(false)?;
+    if received.is_none() {
+      // The debugger hung up.
+      return Ok(Action::Detach);
+    }
+
+    while let Some(action) = self.handle_input(chip8)? {
+      if action != Action::Run {
+        return Ok(action);
+      }
+    }
+    Ok(match self.state {
+      State::Halted => Action::Wait,
+      State::Running | State::Stepping => Action::Run
+    })
+  }
+
+  // Call after every instruction executed: stops at breakpoints, after a
+  // single step, and when the program faults.
+  pub fn executed(&mut self, chip8: &Chip8) -> io::Result<()> {
+    if self.state != State::Halted && chip8.fault().is_some() {
+      return self.stop(SIGSEGV);
+    }
+    let stopped = match self.state {
+      State::Stepping => true,
+      State::Running => self.breakpoints.contains(&chip8.pc()),
+      State::Halted => false
+    };
+    if stopped {
+      self.stop(SIGTRAP)?;
+    }
+    Ok(())
+  }
+
+  fn stop(&mut self, signal: u8) -> io::Result<()> {
+    self.state = State::Halted;
+    self.send(&format!("S{:02x}", signal))
+  }
+
+  fn send(&mut self, data: &str) -> io::Result<()> {
+    // $, #, } and * can't go in a packet as they are.
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data.bytes() {
+      if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+        escaped.extend_from_slice(&[b'}', byte ^ 0x20]);
+      } else {
+        escaped.push(byte);
+      }
+    }
+    let mut packet = vec![b'$'];
+    packet.extend_from_slice(&escaped);
+    packet.extend_from_slice(format!("#{:02x}", checksum(&escaped)).as_bytes());
+    self.stream.write_all(&packet)?;
+    self.last_reply = packet;
+    Ok(())
+  }
+
+  // Handles the first thing in the input, if it's all there. Returns None
+  // when there's nothing (complete) to handle.
+  fn handle_input(&mut self, chip8: &mut Chip8) -> io::Result<Option<Action>> {
+    let first = match self.input.first() {
+      Some(first) => *first,
+      None => return Ok(None)
+    };
+    match first {
+      b'+' => {
+        self.input.remove(0);
+      },
+      b'-' => {
+        self.input.remove(0);
+        let reply = self.last_reply.clone();
+        self.stream.write_all(&reply)?;
+      },
+      // Ctrl-C
+      0x03 => {
+        self.input.remove(0);
+        if self.state != State::Halted {
+          self.stop(SIGINT)?;
+        }
+      },
+      b'$' => {
+        let end = match self.input.iter().position(|byte| *byte == b'#') {
+          Some(end) if self.input.len() >= end + 3 => end,
+          _ => return Ok(None)
+        };
+        let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+        let data = &packet[1..end];
+        let expected = std::str::from_utf8(&packet[end + 1..]).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+        if !self.no_ack {
+          let ack = if expected == Some(checksum(data)) { b"+" } else { b"-" };
+          self.stream.write_all(ack)?;
+          if expected != Some(checksum(data)) {
+            return Ok(Some(Action::Run));
+          }
+        }
+        let data = String::from_utf8_lossy(data).into_owned();
+        return self.handle_packet(&data, chip8).map(Some);
+      },
+      // Noise between packets.
+      _ => {
+        self.input.remove(0);
+      }
+    }
+    Ok(Some(Action::Run))
+  }
+
+  // Answers a packet. Returns Run unless the debugger is done with us. c, s
+  // and vCont get their reply when the machine stops.
+  fn handle_packet(&mut self, packet: &str, chip8: &mut Chip8) -> io::Result<Action> {
+    let reply = match packet.as_bytes().first() {
+      Some(b'?') => format!("S{:02x}", SIGTRAP),
+      Some(b'g') => (0..REGISTERS).map(|n| hex(&read_register(chip8, n))).collect(),
+      Some(b'G') => {
+        let bytes = parse_hex(&packet[1..]).unwrap_or_default();
+        let mut offset = 0;
+        let mut ok = true;
+        for n in 0..REGISTERS {
+          let size = register_size(n);
+          match bytes.get(offset..offset + size) {
+            Some(value) => ok &= write_register(chip8, n, value),
+            None => ok = false
+          }
+          offset += size;
+        }
+        if ok { "OK".to_string() } else { "E01".to_string() }
+      },
+      Some(b'p') => match parse_number(&packet[1..]) {
+        Some(n) if n < REGISTERS => hex(&read_register(chip8, n)),
+        _ => "E01".to_string()
+      },
+      Some(b'P') => {
+        let written = packet[1..].split_once('=')
+          .and_then(|(n, value)| Some((parse_number(n)?, parse_hex(value)?)))
+          .is_some_and(|(n, value)| write_register(chip8, n, &value));
+        if written { "OK".to_string() } else { "E01".to_string() }
+      },
+      Some(b'm') => match parse_range(&packet[1..]) {
+        Some((address, length)) if address < 0x1000 => {
+          let end = (address + length).min(0x1000);
+          hex(&chip8.memory()[address..end])
+        },
+        _ => "E01".to_string()
+      },
+      Some(b'M') => {
+        let write = packet[1..].split_once(':')
+          .and_then(|(range, data)| Some((parse_range(range)?, parse_hex(data)?)));
+        match write {
+          Some(((address, length), data)) if address + length <= 0x1000 && data.len() == length => {
+            chip8.write_memory(address as u16, &data);
+            "OK".to_string()
+          },
+          _ => "E01".to_string()
+        }
+      },
+      Some(b'Z') | Some(b'z') => {
+        // Software and hardware breakpoints are the same thing here.
+        let mut fields = packet[1..].split(',');
+        let kind = fields.next();
+        let address = fields.next().and_then(parse_number);
+        match (kind, address) {
+          (Some("0") | Some("1"), Some(address)) if address >= 0x1000 => "E01".to_string(),
+          (Some("0") | Some("1"), Some(address)) => {
+            if packet.starts_with('Z') {
+              self.breakpoints.insert(address as u16);
+            } else {
+              self.breakpoints.remove(&(address as u16));
+            }
+            "OK".to_string()
+          },
+          _ => String::new()
+        }
+      },
+      Some(b'c') | Some(b's') => {
+        if let Some(address) = parse_number(&packet[1..]) {
+          chip8.set_pc(address as u16 & 0xfff);
+        }
+        self.state = if packet.starts_with('c') { State::Running } else { State::Stepping };
+        return Ok(Action::Run);
+      },
+      Some(b'v') if packet.starts_with("vCont;") => {
+        // A single thread, so the first action is the one.
+        let step = packet[6..].starts_with(['s', 'S']);
+        self.state = if step { State::Stepping } else { State::Running };
+        return Ok(Action::Run);
+      },
+      Some(b'D') => {
+        self.send("OK")?;
+        return Ok(Action::Detach);
+      },
+      Some(b'k') => return Ok(Action::Kill),
+      Some(b'H') => "OK".to_string(),
+      _ => self.handle_query(packet)
+    };
+    self.send(&reply)?;
+    Ok(Action::Run)
+  }
+
+  // The q and v packets, and whatever else gdb asks that we don't know about
+  // (which gets an empty reply).
+  fn handle_query(&mut self, packet: &str) -> String {
+    if packet.starts_with("qSupported") {
+      return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+;vContSupported+".to_string();
+    }
+    if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+      let xml = target_xml();
+      return match parse_range(request) {
+        Some((offset, _)) if offset >= xml.len() => "l".to_string(),
+        Some((offset, length)) => {
+          let end = (offset + length).min(xml.len());
+          format!("{}{}", if end == xml.len() { "l" } else { "m" }, &xml[offset..end])
+        },
+        None => "E01".to_string()
+      };
+    }
+    match packet {
+      "QStartNoAckMode" => {
+        self.no_ack = true;
+        "OK".to_string()
+      },
+      "qAttached" => "1".to_string(),
+      "qC" => "QC1".to_string(),
+      "qfThreadInfo" => "m1".to_string(),
+      "qsThreadInfo" => "l".to_string(),
+      "vCont?" => "vCont;c;C;s;S".to_string(),
+      _ => String::new()
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::thread;
+
+  // The debugger's side of the connection.
+  struct Client {
+    stream: TcpStream
+  }
+
+  impl Client {
+    // Sends a packet, and returns the reply, if one is expected.
+    fn send(&mut self, data: &str) {
+      write!(self.stream, "${}#{:02x}", data, checksum(data.as_bytes())).unwrap();
+      let mut ack = [0];
+      self.stream.read_exact(&mut ack).unwrap();
+      assert_eq!(ack[0], b'+');
+    }
+
+    fn reply(&mut self) -> String {
+      let mut packet = Vec::new();
+      let mut byte = [0];
+      loop {
+        self.stream.read_exact(&mut byte).unwrap();
+        packet.push(byte[0]);
+        if packet.len() >= 3 && packet[packet.len() - 3] == b'#' {
+          break;
+        }
+      }
+      self.stream.write_all(b"+").unwrap();
+      let end = packet.len() - 3;
+      assert_eq!(packet[0], b'$');
+      assert_eq!(format!("{:02x}", checksum(&packet[1..end])).as_bytes(), &packet[end + 1..]);
+      String::from_utf8(packet[1..end].to_vec()).unwrap()
+    }
+
+    fn ask(&mut self, data: &str) -> String {
+      self.send(data);
+      self.reply()
+    }
+  }
+
+  // Runs a machine with the stub, until debugger (on another thread) kills
+  // it.
+  fn debug(debugger: impl FnOnce(&mut Client) + Send + 'static) -> Chip8 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let debugger = thread::spawn(move || {
+      let mut gdb = Client { stream: TcpStream::connect(address).unwrap() };
+      debugger(&mut gdb);
+      gdb.send("k");
+    });
+
+    let mut chip8 = Chip8::new();
+    let mut stub = GdbStub::accept(&listener).unwrap();
+    loop {
+      match stub.poll(&mut chip8).unwrap() {
+        Action::Run => {
+          chip8.fde_loop(&[false; 16]);
+          stub.executed(&chip8).unwrap();
+        },
+        Action::Wait => thread::yield_now(),
+        Action::Detach => panic!("the debugger hung up"),
+        Action::Kill => break
+      }
+    }
+    debugger.join().unwrap();
+    chip8
+  }
+
+  #[test]
+  fn debugging_over_loopback() {
+    let chip8 = debug(|gdb| {
+      assert!(gdb.ask("qSupported:swbreak+").contains("qXfer:features:read+"));
+      assert_eq!(gdb.ask("?"), "S05");
+      let xml = gdb.ask("qXfer:features:read:target.xml:0,1000");
+      assert!(xml.starts_with('l') && xml.contains("name=\"pc\" bitsize=\"16\""));
+
+      // Registers as the machine starts: PC is 0x200, little-endian.
+      assert_eq!(gdb.ask("g"), format!("{}{}{}", "00".repeat(16), "0000", "0002000000"));
+      assert_eq!(gdb.ask("P3=2a"), "OK");
+      assert_eq!(gdb.ask("p3"), "2a");
+
+      // A program that counts up in V3, and a breakpoint at its loop.
+      assert_eq!(gdb.ask("M200,6:73016401f433"), "OK");
+      assert_eq!(gdb.ask("m200,6"), "73016401f433");
+      assert_eq!(gdb.ask("Z0,204,2"), "OK");
+      gdb.send("c");
+      assert_eq!(gdb.reply(), "S05");
+      assert_eq!(gdb.ask("p11"), "0402");
+      assert_eq!(gdb.ask("p3"), "2b");
+
+      // One instruction at a time from there.
+      gdb.send("s");
+      assert_eq!(gdb.reply(), "S05");
+      assert_eq!(gdb.ask("p11"), "0602");
+      assert_eq!(gdb.ask("p4"), "01");
+
+      // Reading and writing past memory fails.
+      assert_eq!(gdb.ask("m1000,2"), "E01");
+      assert_eq!(gdb.ask("MFFF,2:0000"), "E01");
+
+      // A return without a call stops the program where it is.
+      assert_eq!(gdb.ask("M206,2:00ee"), "OK");
+      gdb.send("c");
+      assert_eq!(gdb.reply(), "S0b");
+      assert_eq!(gdb.ask("p11"), "0602");
+    });
+    assert_eq!(chip8.registers()[3], 0x2b);
+  }
+
+  #[test]
+  fn malformed_packets_get_errors() {
+    let chip8 = debug(|gdb| {
+      for packet in ["m1,ffffffffffffffff", "mffffffffffffffff,2", "m200", "mxyz,2", "M1,ffffffffffffffff:00", "M200,2:0", "M200,1:zz", "P3=", "p99", "G00", "Z0,10200,2", "z0,10200,2"] {
+        assert_eq!(gdb.ask(packet), "E01", "{}", packet);
+      }
+      assert_eq!(gdb.ask("qXfer:features:read:target.xml:1,ffffffffffffffff"), "E01");
+      // The stub is still there, and nothing was written.
+      assert_eq!(gdb.ask("m200,2"), "0000");
+    });
+    assert_eq!(chip8.registers(), &[0; 16]);
+  }
+
+  #[test]
+  fn waiting_for_a_debugger_can_be_given_up() {
+    let mut asked = 0;
+    let stub = GdbStub::listen("127.0.0.1:0", || { asked += 1; asked == 3 }).unwrap();
+    assert!(stub.is_none());
+    assert_eq!(asked, 3);
+  }
+}
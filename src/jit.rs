@@ -0,0 +1,217 @@
+use std::rc::Rc;
+
+use crate::c8::Chip8;
+use crate::op::Op;
+
+// How programs are run.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+  // One instruction at a time, with Chip8::fde_loop.
+  Interpreter,
+  // A block of instructions at a time, with Chip8::run_block.
+  Jit
+}
+
+// Longest run of instructions put in a single block.
+const MAX_BLOCK_LENGTH: usize = 64;
+
+// An instruction, turned into something that executes it.
+pub(crate) type Compiled = Box<dyn Fn(&mut Chip8, &[bool; 16])>;
+
+// A run of instructions that always execute one after the other: it ends
+// with the first one that can go anywhere but the next instruction (jumps,
+// calls, skips, and the ones that wait by repeating themselves).
+pub struct Block {
+  start: usize,
+  length: usize, // In bytes
+  ops: Vec<Compiled>
+}
+
+impl Block {
+  pub fn len(&self) -> usize {
+    self.ops.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ops.is_empty()
+  }
+
+  // Executes the instructions one by one. keep_going is asked after each of
+  // them, and stops the block when it says no. Returns how many ran.
+  pub fn run(&self, chip8: &mut Chip8, pressed_keys: &[bool; 16], mut keep_going: impl FnMut(&mut Chip8) -> bool) -> u32 {
+    let mut count = 0;
+    for op in self.ops.iter() {
+      op(chip8, pressed_keys);
+      count += 1;
+      if !keep_going(chip8) {
+        break;
+      }
+    }
+    count
+  }
+}
+
+fn ends_block(op: Op) -> bool {
+  matches!(op,
+    Op::Sys(_) | Op::Jp(_) | Op::Call(_) | Op::Ret | Op::JpV0(..) |
+    Op::SeByte(..) | Op::SneByte(..) | Op::SeReg(..) | Op::SneReg(..) |
+    Op::Skp(_) | Op::Sknp(_) |
+    // These can repeat themselves, to wait for a key or the next frame.
+    Op::LdVxK(_) | Op::Drw(..))
+}
+
+// Blocks compiled so far, by the address they start at. They're thrown away
+// when the memory they were compiled from changes.
+#[derive(Default)]
+pub struct Jit {
+  blocks: Vec<Option<Rc<Block>>>,
+  // How many blocks were compiled from each byte of memory.
+  coverage: Vec<u16>,
+  // Whether any block was thrown away since the last take_invalidated().
+  invalidated: bool
+}
+
+impl Jit {
+  pub fn block(&self, address: usize) -> Option<Rc<Block>> {
+    self.blocks.get(address).and_then(|block| block.clone())
+  }
+
+  // Compiles the block starting at address, from the instructions in ram.
+  // compile_op turns each instruction into a closure.
+  pub fn compile(&mut self, ram: &[u8; 0x1000], address: usize, compile_op: impl Fn(Op) -> Compiled) -> Rc<Block> {
+    let mut ops: Vec<Compiled> = Vec::new();
+    let mut length = 0;
+    // Blocks stop at the end of memory, where the program counter would
+    // wrap around.
+    while ops.len() < MAX_BLOCK_LENGTH && address + length + 1 < ram.len() {
+      let op = Op::decode((ram[address + length] as u16) << 8 | ram[address + length + 1] as u16);
+      ops.push(compile_op(op));
+      length += 2;
+      if ends_block(op) {
+        break;
+      }
+    }
+
+    if self.coverage.is_empty() {
+      self.coverage = vec![0; ram.len()];
+      self.blocks = vec![None; ram.len()];
+    }
+    for count in self.coverage[address..address + length].iter_mut() {
+      *count += 1;
+    }
+
+    let block = Rc::new(Block { start: address, length, ops });
+    self.blocks[address] = Some(block.clone());
+    block
+  }
+
+  // Throws away the blocks compiled from the length bytes at start.
+  pub fn invalidate(&mut self, start: usize, length: usize) {
+    let end = (start + length).min(self.coverage.len());
+    if start >= end || self.coverage[start..end].iter().all(|count| *count == 0) {
+      return;
+    }
+
+    // Blocks are at most MAX_BLOCK_LENGTH instructions long, so the ones that
+    // overlap start a little before start at the earliest.
+    let first = start.saturating_sub(2 * MAX_BLOCK_LENGTH);
+    for slot in self.blocks[first..end].iter_mut() {
+      if let Some(block) = slot {
+        if block.start + block.length > start {
+          for count in self.coverage[block.start..block.start + block.length].iter_mut() {
+            *count -= 1;
+          }
+          *slot = None;
+        }
+      }
+    }
+    self.invalidated = true;
+  }
+
+  pub fn take_invalidated(&mut self) -> bool {
+    std::mem::take(&mut self.invalidated)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::panic::{self, AssertUnwindSafe};
+
+  use rand::{Rng, SeedableRng};
+  use rand::rngs::StdRng;
+
+  use crate::c8::Config;
+
+  // Runs program with both backends, block by block, and checks that they
+  // agree on everything after every block. Either both panic or neither.
+  fn differential(program: &[u8], blocks: usize) {
+    let config = Config { seed: Some(1), ..Config::default() };
+    let mut interpreter = Chip8::with_config(config);
+    let mut jit = Chip8::with_config(config);
+    interpreter.load_bytes(program);
+    jit.load_bytes(program);
+    let keys = [false; 16];
+
+    for _ in 0..blocks {
+      let ran = panic::catch_unwind(AssertUnwindSafe(|| jit.run_block(&keys)));
+      let count = match ran {
+        Ok(count) => count,
+        Err(_) => {
+          // The interpreter has to panic on the same instruction, somewhere
+          // in the block.
+          let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+            for _ in 0..MAX_BLOCK_LENGTH {
+              interpreter.fde_loop(&keys);
+            }
+          }));
+          assert!(panicked.is_err(), "only the JIT panicked");
+          return;
+        }
+      };
+      for _ in 0..count {
+        interpreter.fde_loop(&keys);
+      }
+
+      assert_eq!(jit.pc(), interpreter.pc());
+      assert_eq!(jit.registers(), interpreter.registers());
+      assert_eq!(jit.index(), interpreter.index());
+      assert_eq!(jit.sp(), interpreter.sp());
+      assert_eq!((jit.dt(), jit.st()), (interpreter.dt(), interpreter.st()));
+      assert!(jit.memory() == interpreter.memory());
+      assert!(jit.get_display() == interpreter.get_display());
+    }
+  }
+
+  #[test]
+  fn loops_and_sprites() {
+    differential(&[
+      0x60, 0x00, 0x70, 0x01, 0x81, 0x04, 0x82, 0x13, 0xA3, 0x00, 0xF2, 0x33,
+      0xF2, 0x65, 0xD0, 0x15, 0x30, 0x00, 0x12, 0x02, 0x12, 0x00
+    ], 1000);
+  }
+
+  #[test]
+  fn self_modifying_code() {
+    // Rewrites the instruction it jumps back to in the same block, with an
+    // increasing value.
+    differential(&[
+      0x60, 0x63, // 200: V0 = 63
+      0x71, 0x01, // 202: V1 += 1
+      0xA2, 0x0A, // 204: I = 20A
+      0xF1, 0x55, // 206: Store V0 and V1 at 20A
+      0x63, 0x00, // 208: V3 = 0
+      0x63, 0x42, // 20A: V3 = 42, rewritten to V3 = V1
+      0x12, 0x02  // 20C: Jump to 202
+    ], 1000);
+  }
+
+  #[test]
+  fn random_programs() {
+    let mut rng = StdRng::seed_from_u64(8);
+    for _ in 0..200 {
+      let program: Vec<u8> = (0..0x200).map(|_| rng.gen()).collect();
+      differential(&program, 200);
+    }
+  }
+}
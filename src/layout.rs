@@ -0,0 +1,66 @@
+use crate::{SCREEN_LINES, SCREEN_COLUMNS};
+
+// Size of the display in terminal cells. Every cell holds two pixels, one on
+// top of the other.
+pub const DISPLAY_WIDTH: u16 = SCREEN_COLUMNS as u16;
+pub const DISPLAY_HEIGHT: u16 = (SCREEN_LINES / 2) as u16;
+
+pub const PANEL_WIDTH: u16 = 20;
+// Columns between the display and the side panel.
+const PANEL_GAP: u16 = 2;
+
+// A rectangle of terminal cells. x and y are 1-based, like termion's Goto.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+  pub x: u16,
+  pub y: u16,
+  pub width: u16,
+  pub height: u16
+}
+
+// Where every part of the user interface goes, for a given terminal size.
+//
+// The display and the side panel are centered in the terminal, and the status
+// bar takes the last line. Parts that don't fit are left out, except for the
+// display: if that doesn't fit, there's no layout at all.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+  pub display: Rect,
+  pub status: Option<Rect>,
+  pub side_panel: Option<Rect>
+}
+
+impl Layout {
+  pub fn compute(lines: u16, columns: u16, want_status: bool, want_side_panel: bool) -> Option<Layout> {
+    if lines < DISPLAY_HEIGHT || columns < DISPLAY_WIDTH {
+      return None;
+    }
+
+    let has_status = want_status && lines > DISPLAY_HEIGHT;
+    let has_side_panel = want_side_panel && columns >= DISPLAY_WIDTH + PANEL_GAP + PANEL_WIDTH;
+
+    let content_width = if has_side_panel { DISPLAY_WIDTH + PANEL_GAP + PANEL_WIDTH } else { DISPLAY_WIDTH };
+    let content_lines = if has_status { lines - 1 } else { lines };
+
+    let display = Rect {
+      x: (columns - content_width) / 2 + 1,
+      y: (content_lines - DISPLAY_HEIGHT) / 2 + 1,
+      width: DISPLAY_WIDTH,
+      height: DISPLAY_HEIGHT
+    };
+
+    Some(Layout {
+      display,
+      status: if has_status {
+        Some(Rect { x: 1, y: lines, width: columns, height: 1 })
+      } else {
+        None
+      },
+      side_panel: if has_side_panel {
+        Some(Rect { x: display.x + DISPLAY_WIDTH + PANEL_GAP, y: display.y, width: PANEL_WIDTH, height: DISPLAY_HEIGHT })
+      } else {
+        None
+      }
+    })
+  }
+}
@@ -0,0 +1,31 @@
+// Modules
+pub mod audio;
+pub mod batch;
+pub mod c8;
+pub mod cartridge;
+pub mod cdp1802;
+pub mod control;
+pub mod database;
+pub mod dirty;
+pub mod filter;
+pub mod font;
+pub mod framebuffer;
+pub mod gdb;
+pub mod jit;
+pub mod layout;
+pub mod loader;
+pub mod octo;
+pub mod op;
+pub mod palette;
+pub mod platform;
+pub mod screen;
+pub mod stats;
+pub mod terminal;
+pub mod timing;
+pub mod watch;
+
+// CONSTANTS
+pub const SCREEN_LINES: usize = 32;
+pub const SCREEN_COLUMNS: usize = 64;
+pub const TIMER_HZ: u64 = 500;
+pub const FRAME_HZ: u64 = 60;
@@ -0,0 +1,291 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+use flate2::read::GzDecoder;
+
+use crate::cartridge::{self, CartridgeOptions};
+use crate::octo;
+
+// Programs are usually loaded at 0x200, and memory ends at 0xFFF.
+pub const PROGRAM_START: usize = 0x200;
+const MEMORY_SIZE: usize = 0x1000;
+
+// Extensions that mark the files worth loading inside an archive.
+const ROM_EXTENSIONS: [&str; 6] = ["ch8", "c8", "sc8", "xo8", "hex", "bin"];
+// Files that can hold Intel HEX or an Octo hex listing. Anything else is
+// binary, even when its bytes happen to read like text.
+const TEXT_EXTENSIONS: [&str; 3] = ["hex", "ihx", "txt"];
+
+#[derive(Debug)]
+pub enum LoadError {
+  Io(io::Error),
+  Archive(String),
+  // The archive has more than one ROM in it, and we weren't told which one.
+  AmbiguousArchive(Vec<String>),
+  Format(String),
+  TooBig { size: usize, load_address: usize }
+}
+
+impl fmt::Display for LoadError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      LoadError::Io(e) => write!(f, "{}", e),
+      LoadError::Archive(e) => write!(f, "Couldn't read archive: {}", e),
+      LoadError::AmbiguousArchive(names) => {
+        write!(f, "The archive has several ROMs, pick one with --archive-entry: {}", names.join(", "))
+      },
+      LoadError::Format(e) => write!(f, "{}", e),
+      LoadError::TooBig { size, load_address } => {
+        write!(f, "The ROM is {} bytes long, but only {} bytes fit in memory after 0x{:03X}",
+          size, MEMORY_SIZE - load_address, load_address)
+      }
+    }
+  }
+}
+
+impl From<io::Error> for LoadError {
+  fn from(e: io::Error) -> LoadError {
+    LoadError::Io(e)
+  }
+}
+
+impl From<zip::result::ZipError> for LoadError {
+  fn from(e: zip::result::ZipError) -> LoadError {
+    LoadError::Archive(e.to_string())
+  }
+}
+
+// A program, ready to be copied into memory at its load address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Rom {
+  pub name: String,
+  pub bytes: Vec<u8>,
+  // How the program wants to be run, if it came in an Octo cartridge.
+  pub options: Option<CartridgeOptions>
+}
+
+// Loads a ROM from a file, or from stdin if source is "-". The file can be a
+// raw binary, an Intel HEX file or Octo hex listing (named as such, see
+// TEXT_EXTENSIONS), an Octo cartridge, or any of those inside a .zip or .gz
+// archive. entry picks the file to load from a zip archive, and the ROM has
+// to fit in memory from load_address on.
+pub fn load(source: &str, entry: Option<&str>, load_address: usize) -> Result<Rom, LoadError> {
+  let (name, contents) = if source == "-" {
+    let mut contents = Vec::new();
+    io::stdin().read_to_end(&mut contents)?;
+    ("stdin".to_string(), contents)
+  } else {
+    let path = Path::new(source);
+    let name = path.file_name().map_or(source.to_string(), |name| name.to_string_lossy().into_owned());
+    (name, fs::read(path)?)
+  };
+
+  let rom = decode(&name, contents, entry, load_address)?;
+  if load_address + rom.bytes.len() > MEMORY_SIZE {
+    return Err(LoadError::TooBig { size: rom.bytes.len(), load_address });
+  }
+
+  Ok(Rom { name, ..rom })
+}
+
+// Works out what contents is from its first bytes, and unpacks it.
+fn decode(name: &str, contents: Vec<u8>, entry: Option<&str>, load_address: usize) -> Result<Rom, LoadError> {
+  let raw = |bytes: Vec<u8>| Ok(Rom { name: name.to_string(), bytes, options: None });
+
+  if contents.starts_with(&[0x1f, 0x8b]) {
+    let mut unpacked = Vec::new();
+    GzDecoder::new(&contents[..]).read_to_end(&mut unpacked)?;
+    return decode(name.trim_end_matches(".gz"), unpacked, entry, load_address);
+  }
+  if contents.starts_with(b"PK\x03\x04") {
+    let (entry_name, unpacked) = unzip(contents, entry)?;
+    return decode(&entry_name, unpacked, None, load_address);
+  }
+  if cartridge::is_cartridge(&contents) {
+    let cartridge = cartridge::decode(&contents)
+      .map_err(|e| LoadError::Format(format!("Couldn't read Octo cartridge: {}", e)))?;
+    let bytes = octo::assemble(&cartridge.program)
+      .map_err(|e| LoadError::Format(format!("Couldn't assemble the cartridge's program: {}", e)))?;
+    return Ok(Rom { name: name.to_string(), bytes, options: Some(cartridge.options) });
+  }
+
+  let text = if has_extension(name, &TEXT_EXTENSIONS) { std::str::from_utf8(&contents).ok() } else { None };
+  if let Some(text) = text {
+    if text.trim_start().starts_with(':') {
+      return raw(parse_intel_hex(text, load_address)?);
+    }
+    if let Some(bytes) = parse_hex_text(text) {
+      return raw(bytes);
+    }
+  }
+
+  raw(contents)
+}
+
+fn has_extension(name: &str, extensions: &[&str]) -> bool {
+  Path::new(name).extension()
+    .is_some_and(|extension| extensions.iter().any(|known| extension.eq_ignore_ascii_case(known)))
+}
+
+fn unzip(contents: Vec<u8>, entry: Option<&str>) -> Result<(String, Vec<u8>), LoadError> {
+  let mut archive = zip::ZipArchive::new(Cursor::new(contents))?;
+
+  let files: Vec<String> = archive.file_names()
+    .filter(|name| !name.ends_with('/'))
+    .map(String::from)
+    .collect();
+  let chosen = match entry {
+    Some(entry) => {
+      files.iter()
+        .find(|name| *name == entry || Path::new(name).file_name().is_some_and(|file| file == entry))
+        .cloned()
+        .ok_or_else(|| LoadError::Archive(format!("there's no {} in it", entry)))?
+    },
+    None => {
+      // With a single file there's nothing to choose. Otherwise, README files
+      // and such don't count.
+      let candidates: Vec<&String> = if files.len() == 1 {
+        files.iter().collect()
+      } else {
+        files.iter().filter(|name| has_extension(name, &ROM_EXTENSIONS)).collect()
+      };
+      match candidates.len() {
+        0 => return Err(LoadError::Archive("it's got no ROMs in it".to_string())),
+        1 => candidates[0].clone(),
+        _ => return Err(LoadError::AmbiguousArchive(candidates.into_iter().cloned().collect()))
+      }
+    }
+  };
+
+  let mut unpacked = Vec::new();
+  archive.by_name(&chosen)?.read_to_end(&mut unpacked)?;
+  Ok((chosen, unpacked))
+}
+
+fn hex_byte(text: &str) -> Option<u8> {
+  if text.len() != 2 {
+    return None;
+  }
+  u8::from_str_radix(text, 16).ok()
+}
+
+// Parses an Intel HEX file. Addresses are usually those of chip8 memory, so
+// data is placed relative to load_address. Files that start below it are
+// taken to be relative to the start of the program instead.
+fn parse_intel_hex(text: &str, load_address: usize) -> Result<Vec<u8>, LoadError> {
+  let mut records: Vec<(usize, Vec<u8>)> = Vec::new();
+  let mut base_address = 0;
+
+  for (line_number, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() {
+      continue;
+    }
+    let error = |message: &str| LoadError::Format(format!("Intel HEX line {}: {}", line_number + 1, message));
+
+    let digits = line.strip_prefix(':').ok_or_else(|| error("doesn't start with ':'"))?;
+    if digits.len() % 2 != 0 {
+      return Err(error("odd number of digits"));
+    }
+    let bytes = (0..digits.len()).step_by(2)
+      .map(|i| digits.get(i..i+2).and_then(hex_byte))
+      .collect::<Option<Vec<u8>>>()
+      .ok_or_else(|| error("not a hex number"))?;
+
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+      return Err(error("wrong record length"));
+    }
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+      return Err(error("bad checksum"));
+    }
+
+    let address = (bytes[1] as usize) << 8 | bytes[2] as usize;
+    let data = &bytes[4..bytes.len()-1];
+    match bytes[3] {
+      0x00 => records.push((base_address + address, data.to_vec())),
+      0x01 => break,
+      // Extended segment address (in 16-byte paragraphs)
+      0x02 if data.len() == 2 => base_address = ((data[0] as usize) << 8 | data[1] as usize) << 4,
+      // Extended linear address (the upper 16 bits)
+      0x04 if data.len() == 2 => base_address = ((data[0] as usize) << 8 | data[1] as usize) << 16,
+      // Start addresses mean nothing to a chip8.
+      0x03 | 0x05 => {},
+      _ => return Err(error("unknown record type"))
+    }
+  }
+
+  let lowest = records.iter().map(|(address, _)| *address).min().unwrap_or(load_address);
+  let origin = if lowest >= load_address { load_address } else { 0 };
+
+  let mut rom = Vec::new();
+  for (address, data) in records {
+    let offset = address - origin;
+    if load_address + offset + data.len() > MEMORY_SIZE {
+      return Err(LoadError::TooBig { size: offset + data.len(), load_address });
+    }
+    if rom.len() < offset + data.len() {
+      rom.resize(offset + data.len(), 0);
+    }
+    rom[offset..offset + data.len()].copy_from_slice(&data);
+  }
+
+  Ok(rom)
+}
+
+// Parses a listing of bytes as written by Octo, like "0x00 0xE0 0xA2 0x2A",
+// where bytes can also go without the 0x prefix, be separated by commas and
+// have # comments after them. Returns None if text doesn't look like one.
+fn parse_hex_text(text: &str) -> Option<Vec<u8>> {
+  let mut bytes = Vec::new();
+
+  for line in text.lines() {
+    let line = line.split('#').next().unwrap();
+    for token in line.split(|c: char| c.is_whitespace() || c == ',').filter(|token| !token.is_empty()) {
+      let digits = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")).unwrap_or(token);
+      bytes.push(hex_byte(digits)?);
+    }
+  }
+
+  if bytes.is_empty() { None } else { Some(bytes) }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const PROGRAM: [u8; 4] = [0x00, 0xE0, 0x12, 0x00];
+
+  #[test]
+  fn raw_binaries_are_loaded_as_they_are() {
+    assert_eq!(decode("a.ch8", PROGRAM.to_vec(), None, PROGRAM_START).unwrap().bytes, PROGRAM);
+  }
+
+  #[test]
+  fn binaries_that_read_like_text_are_raw() {
+    // 3A12 (skip if VA is 0x12), then the same as a hex listing.
+    for contents in [&b":\x12\x00\xE0"[..], b"00 E0"] {
+      assert_eq!(decode("a.ch8", contents.to_vec(), None, PROGRAM_START).unwrap().bytes, contents);
+      assert_eq!(decode("stdin", contents.to_vec(), None, PROGRAM_START).unwrap().bytes, contents);
+    }
+  }
+
+  #[test]
+  fn octo_hex_listings_are_parsed() {
+    let text = b"0x00 0xE0, 12 00 # loop\n";
+    assert_eq!(decode("a.txt", text.to_vec(), None, PROGRAM_START).unwrap().bytes, PROGRAM);
+  }
+
+  #[test]
+  fn intel_hex_addresses_are_relative_to_the_program_start() {
+    let text = b":0402000000E0120008\n:00000001FF\n";
+    assert_eq!(decode("a.hex", text.to_vec(), None, PROGRAM_START).unwrap().bytes, PROGRAM);
+  }
+
+  #[test]
+  fn intel_hex_checksums_are_checked() {
+    let text = b":0402000000E0120009\n";
+    assert!(matches!(decode("a.hex", text.to_vec(), None, PROGRAM_START), Err(LoadError::Format(_))));
+  }
+}
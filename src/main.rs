@@ -1,80 +1,538 @@
-#![feature(bigint_helper_methods)]
-
-use crate::c8::Chip8;
-use screen::{Screen, ColorScheme};
+use c8rs::{audio, font, loader, platform, terminal, timing, FRAME_HZ, TIMER_HZ};
+use c8rs::c8::{Chip8, Config, MemoryMap, Quirks};
+use c8rs::screen::{Screen, Hotkey};
+use c8rs::stats::Stats;
+use c8rs::control::Control;
+use c8rs::watch::RomWatcher;
+use c8rs::platform::Platform;
+use c8rs::timing::{FixedClock, Timing};
+use c8rs::jit::Backend;
+use c8rs::loader::Rom;
+use c8rs::terminal::{TerminalGuard, Signals, Signal};
+use c8rs::palette::{Palette, Rgb};
+use c8rs::font::Font;
+use c8rs::filter::{DisplayFilter, FilterKind};
+use c8rs::batch::{self, BatchOptions, ExitReason};
+use c8rs::database::RomDatabase;
+use c8rs::gdb::{Action, GdbStub};
+use c8rs::audio::{Audio, AudioBackend, AudioSink, BellSink, NullSink, PipeSink, WavSink};
 use std::time::Duration;
 use clap::Parser;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 use std::thread;
 
-// Modules
-pub mod c8;
-pub mod screen;
-
-// CONSTANTS
-pub const SCREEN_LINES: usize = 32;
-pub const SCREEN_COLUMNS: usize = 64;
-pub const TIMER_HZ: u64 = 500;
-
 #[derive(Parser)]
 #[clap(author, version, about, long_about = None)]
+#[clap(after_help = "Run `c8rs batch --help` to run many ROMs headless and get a report.")]
 struct Cli {
-    /// The file you want the emulator to execute.
+    /// The file you want the emulator to execute, or - to read it from
+    /// stdin. It can be a raw binary, an Intel HEX file or Octo hex listing
+    /// (named .hex, .ihx or .txt) or an Octo cartridge, on its own or inside
+    /// a .zip or .gz archive.
     binary: String,
-    /// The color scheme you want to use.
-    #[clap(arg_enum, default_value_t=ColorScheme::BlackWhite)]
-    color_scheme: ColorScheme
+    /// The color scheme you want to use: black-white (the default),
+    /// orange-yellow, black-green or any palette defined in the palette file.
+    /// Octo cartridges bring their own, unless you pick one.
+    color_scheme: Option<String>,
+    /// File with user-defined palettes (default: ~/.config/c8rs/palettes).
+    #[clap(long)]
+    palette_file: Option<String>,
+    /// Override the foreground color of the scheme (e.g. #ffb000).
+    #[clap(long, parse(try_from_str = Rgb::parse_hex))]
+    fg: Option<Rgb>,
+    /// Override the background color of the scheme (e.g. #1a0f00).
+    #[clap(long, parse(try_from_str = Rgb::parse_hex))]
+    bg: Option<Rgb>,
+    /// Anti-flicker filter applied to the display before drawing it.
+    #[clap(long, arg_enum, default_value_t=FilterKind::None)]
+    filter: FilterKind,
+    /// Number of frames a pixel stays lit after turning off, with the
+    /// persistence filter.
+    #[clap(long, default_value_t=2)]
+    persistence: u8,
+    /// Where the sound timer's tone goes.
+    #[clap(long, arg_enum, default_value_t=AudioBackend::Bell)]
+    audio: AudioBackend,
+    /// Frequency of the tone, in Hz.
+    #[clap(long, default_value_t=440.0)]
+    tone: f32,
+    /// Volume of the tone, from 0.0 to 1.0.
+    #[clap(long, default_value_t=0.25)]
+    volume: f32,
+    /// Sample rate of the generated audio.
+    #[clap(long, default_value_t=audio::DEFAULT_SAMPLE_RATE, possible_values=["44100", "48000"])]
+    sample_rate: u32,
+    /// File the wav audio backend writes to.
+    #[clap(long, default_value = "c8rs.wav")]
+    wav_file: String,
+    /// Player the pipe audio backend sends raw samples to (default: aplay).
+    #[clap(long)]
+    audio_command: Option<String>,
+    /// Show the status line from the start (toggle it with 'h').
+    #[clap(long)]
+    hud: bool,
+    /// Show the register panel from the start (toggle it with 'i').
+    #[clap(long)]
+    registers: bool,
+    /// The file to load from a .zip archive with more than one ROM in it.
+    #[clap(long)]
+    archive_entry: Option<String>,
+    /// The machine the ROM was written for, which decides where it's loaded.
+    /// vip-hybrid runs 0nnn machine code subroutines on an emulated 1802.
+    #[clap(long, arg_enum, default_value_t=Platform::Chip8)]
+    platform: Platform,
+    /// Load the ROM at this address instead of the platform's (e.g. 0x600).
+    #[clap(long, parse(try_from_str = platform::parse_address))]
+    load_address: Option<u16>,
+    /// Start executing at this address instead of the load address.
+    #[clap(long, parse(try_from_str = platform::parse_address))]
+    entry: Option<u16>,
+    /// The hex digit font: chip48, vip, dream6800, eti660 or a font file.
+    #[clap(long, default_value = "chip48")]
+    font: String,
+    /// Where the font goes in memory (the big SUPER-CHIP font follows it).
+    #[clap(long, default_value = "0x000", parse(try_from_str = platform::parse_address))]
+    font_address: u16,
+    /// Where the interpreter keeps the stack, V0-VF and the display. With
+    /// vip, they're in memory where the COSMAC VIP had them.
+    #[clap(long, arg_enum, default_value_t=MemoryMap::Separate)]
+    memory_map: MemoryMap,
+    /// Nested subroutine calls allowed (default: 12 with the VIP memory map,
+    /// 16 otherwise).
+    #[clap(long, possible_values=["12", "16"])]
+    stack_depth: Option<usize>,
+    /// How long instructions take. With vip, as long as on the COSMAC VIP,
+    /// display waits and all (the speed of cartridges is then ignored).
+    #[clap(long, arg_enum, default_value_t=Timing::Fixed)]
+    timing: Timing,
+    /// How instructions are run: one by one, or compiled a block at a time.
+    #[clap(long, arg_enum, default_value_t=Backend::Interpreter)]
+    backend: Backend,
+    /// Reset and reload the ROM whenever the file changes.
+    #[clap(long)]
+    watch: bool,
+    /// With --watch, keep the machine state across reloads when the new ROM
+    /// is the same size as the old one: only the program is reloaded.
+    #[clap(long, requires = "watch")]
+    keep_state: bool,
+    /// Wait for gdb to connect on ADDRESS (e.g. 127.0.0.1:1234), and let it
+    /// stop, step and inspect the machine. Instructions are interpreted
+    /// while it's attached.
+    #[clap(long, value_name = "ADDRESS")]
+    gdb: Option<String>
+}
+
+// c8rs batch: runs ROMs without a terminal, and reports how they ended up.
+#[derive(Parser)]
+#[clap(name = "c8rs batch", version, about = "Run ROMs headless, in parallel, and write a report")]
+struct BatchCli {
+    /// The ROMs to run, in any format the emulator can load.
+    #[clap(required = true)]
+    roms: Vec<String>,
+    /// Instructions each ROM runs, at most.
+    #[clap(long, default_value_t=1_000_000)]
+    cycles: u64,
+    /// Directory the report goes to.
+    #[clap(long, default_value = "results")]
+    out: PathBuf,
+    /// ROMs run at the same time (default: one per core).
+    #[clap(long)]
+    jobs: Option<usize>,
+    /// A ROM database, with the quirks of known ROMs (e.g.
+    /// ~/.config/c8rs/roms.json). Without one, ROMs that aren't Octo
+    /// cartridges run with the default quirks, so that reports are the same
+    /// on every machine.
+    #[clap(long)]
+    database: Option<PathBuf>,
+    /// How instructions are run.
+    #[clap(long, arg_enum, default_value_t=Backend::Interpreter)]
+    backend: Backend
+}
+
+fn batch_main() {
+  let cli = BatchCli::parse_from(std::env::args().skip(1));
+
+  let database = match cli.database {
+    Some(path) => RomDatabase::load(&path),
+    None => Ok(RomDatabase::default())
+  };
+  let database = match database {
+    Ok(database) => database,
+    Err(e) => {
+      println!("Error: {}", e);
+      return;
+    }
+  };
+  let options = BatchOptions {
+    cycles: cli.cycles,
+    jobs: cli.jobs.unwrap_or_else(|| thread::available_parallelism().map_or(1, |jobs| jobs.get())),
+    backend: cli.backend,
+    database
+  };
+
+  let results = batch::run_batch(&cli.roms, &options);
+  for result in results.iter() {
+    match &result.exit {
+      ExitReason::Error(e) => println!("{}: error after {} instructions: {}", result.path, result.instructions, e),
+      exit => println!("{}: {} after {} instructions", result.path, exit.label(), result.instructions)
+    }
+  }
+  if let Err(e) = batch::write_report(&results, &cli.out) {
+    println!("Error: Couldn't write the report to {}: {}", cli.out.display(), e);
+    return;
+  }
+  let errors = results.iter().filter(|result| matches!(result.exit, ExitReason::Error(_))).count();
+  println!("Ran {} ROMs ({} errors), report in {}", results.len(), errors, cli.out.join("report.html").display());
+}
+
+// The lines of the side panel: the state of every register.
+fn register_panel(chip8: &Chip8) -> Vec<String> {
+  let v = chip8.registers();
+  let mut lines: Vec<String> = (0..8)
+    .map(|i| format!("V{:X} {:02X}     V{:X} {:02X}", i, v[i], i + 8, v[i + 8]))
+    .collect();
+
+  lines.push(String::new());
+  lines.push(format!("PC {:03X}   I  {:03X}", chip8.pc(), chip8.index()));
+  lines.push(format!("SP {:02X}    OP {:04X}", chip8.sp(), chip8.next_instruction()));
+  lines.push(format!("DT {:02X}    ST {:02X}", chip8.dt(), chip8.st()));
+  lines
+}
+
+// A fresh machine with the program in rom loaded, set up the way the program
+// wants it.
+fn boot(rom: &Rom, config: Config) -> Chip8 {
+  let mut chip8 = Chip8::with_config(config);
+  chip8.set_quirks(rom.options.as_ref().map_or(Quirks::default(), |options| options.quirks));
+  chip8.load_bytes(&rom.bytes);
+  chip8
+}
+
+// Instructions per second the program in rom is meant to run at, or machine
+// cycles per second with VIP timing.
+fn instruction_rate(rom: &Rom, timing: Timing) -> u32 {
+  if timing == Timing::Vip {
+    return timing::VIP_CYCLE_HZ;
+  }
+  match rom.options.as_ref().and_then(|options| options.tickrate) {
+    Some(tickrate) => tickrate * FRAME_HZ as u32,
+    None => TIMER_HZ as u32
+  }
 }
 
 fn main() {
+  if std::env::args().nth(1).as_deref() == Some("batch") {
+    batch_main();
+    return;
+  }
   let cli = Cli::parse();
-  let binary_path = Path::new(&cli.binary);
 
-  if !binary_path.exists() || !binary_path.is_file() {
-     println!("Error: Invalid path.");
-     return;
+  if cli.watch && cli.binary == "-" {
+    println!("Error: Can't watch stdin for changes.");
+    return;
   }
+  let font = match Font::lookup(&cli.font) {
+    Ok(font) => font,
+    Err(e) => {
+      println!("Error: {}", e);
+      return;
+    }
+  };
+  if cli.font_address as usize + font::FONT_SIZE > 0x1000 {
+    println!("Error: The font doesn't fit in memory at 0x{:03X}.", cli.font_address);
+    return;
+  }
+
+  let load_address = cli.load_address.unwrap_or_else(|| cli.platform.load_address());
+  // Machine code expects the interpreter's state where the VIP kept it.
+  let memory_map = if cli.platform.machine_code() { MemoryMap::Vip } else { cli.memory_map };
+  let config = Config {
+    load_address,
+    entry: cli.entry.unwrap_or(load_address),
+    font,
+    font_address: cli.font_address,
+    memory_map,
+    stack_depth: cli.stack_depth.unwrap_or(match memory_map {
+      MemoryMap::Separate => 16,
+      MemoryMap::Vip => 12
+    }),
+    machine_code: cli.platform.machine_code(),
+    timing: cli.timing,
+    ..Config::default()
+  };
+  let rom = match loader::load(&cli.binary, cli.archive_entry.as_deref(), load_address as usize) {
+    Ok(rom) => rom,
+    Err(e) => {
+      println!("Error: Couldn't load {}: {}", cli.binary, e);
+      return;
+    }
+  };
 
-  let mut screen = Screen::new(cli.color_scheme);
-  let mut chip8 = Chip8::new();
+  let palette_file = cli.palette_file.map(PathBuf::from).or_else(Palette::default_file);
+  let cartridge_palette = rom.options.as_ref().and_then(|options| options.palette.clone());
+  let palette = match (cli.color_scheme.as_deref(), cartridge_palette) {
+    (None, Some(palette)) => Ok(palette),
+    (name, _) => Palette::lookup(name.unwrap_or("black-white"), palette_file.as_deref())
+  };
+  let palette = match palette {
+    Ok(palette) => palette.with_overrides(cli.fg, cli.bg),
+    Err(e) => {
+      println!("Error: {}", e);
+      return;
+    }
+  };
+
+  let sink: Box<dyn AudioSink> = match cli.audio {
+    AudioBackend::Bell => Box::new(BellSink::default()),
+    AudioBackend::Pipe => {
+      let sample_rate = cli.sample_rate;
+      let command = cli.audio_command.unwrap_or_else(|| audio::default_audio_command(sample_rate));
+      match PipeSink::spawn(&command) {
+        Ok(sink) => Box::new(sink),
+        Err(e) => {
+          println!("Error: Couldn't start '{}': {}", command, e);
+          return;
+        }
+      }
+    },
+    AudioBackend::Wav => match WavSink::create(Path::new(&cli.wav_file), cli.sample_rate) {
+      Ok(sink) => Box::new(sink),
+      Err(e) => {
+        println!("Error: Couldn't create {}: {}", cli.wav_file, e);
+        return;
+      }
+    },
+    AudioBackend::None => Box::new(NullSink)
+  };
+  let mut audio = Audio::new(cli.sample_rate, cli.tone, cli.volume, sink);
+  // Why the sound stopped, if it did.
+  let mut audio_error = None;
+
+  let mut rom_name = rom.name.clone();
+
+  let signals = match Signals::register() {
+    Ok(signals) => signals,
+    Err(e) => {
+      println!("Error: Couldn't install signal handlers: {}", e);
+      return;
+    }
+  };
+
+  let mut gdb = match &cli.gdb {
+    Some(address) => {
+      println!("Waiting for gdb on {}...", address);
+      // Ctrl-C stops waiting.
+      match GdbStub::listen(address, || signals.take() == Some(Signal::Quit)) {
+        Ok(Some(stub)) => Some(stub),
+        Ok(None) => return,
+        Err(e) => {
+          println!("Error: Couldn't listen on {}: {}", address, e);
+          return;
+        }
+      }
+    },
+    None => None
+  };
 
-  chip8.load_file(&binary_path);
+  // Whatever happens from here on, the terminal has to be restored.
+  let terminal_guard = TerminalGuard::install();
+  let mut screen = Screen::new(&palette);
+  if let Some(keymap) = rom.options.as_ref().and_then(|options| options.keymap.as_ref()) {
+    screen.set_keymap(keymap);
+  }
+  let mut chip8 = boot(&rom, config);
   screen.setup();
 
+  let mut filter = DisplayFilter::new(cli.filter, cli.persistence, chip8.get_display());
+
+  let mut stats = Stats::new();
+  let mut show_hud = cli.hud;
+  let mut show_registers = cli.registers;
+  screen.set_status_visible(show_hud);
+  screen.set_side_panel_visible(show_registers);
+  let mut control = Control::new();
+  control.set_rate(instruction_rate(&rom, cli.timing));
+  let mut watcher = if cli.watch { Some(RomWatcher::new(Path::new(&cli.binary), cli.archive_entry.as_deref(), load_address as usize, &rom)) } else { None };
+  let mut rom = rom;
+  screen.write_status(&stats.status_line(&rom_name, chip8.quirks().label(), &control.label()));
+
   let mut counter = 0;
+  // With VIP timing, the rate is in machine cycles instead of instructions,
+  // and frames end with the interrupt rather than with the clock.
+  let mut clock = FixedClock::default();
+  // Whether gdb has the machine stopped.
+  let mut gdb_halted = false;
+  // Why the program stopped for good, if it did.
+  let mut fault = None;
   loop {
-    eprint!("\x1b[{};{}H[main] {}", 25, 0, counter);
     let timer = Instant::now();
 
-    // Loop until the terminal screen is 32x64.
-    screen.require_screen_size(SCREEN_LINES, SCREEN_COLUMNS);
+    // Loop until the display fits in the terminal.
+    screen.require_screen_size();
+
+    match signals.take() {
+      Some(Signal::Quit) => break,
+      Some(Signal::Suspend) => {
+        screen.suspend();
+        terminal::stop();
+        screen.resume();
+      },
+      Some(Signal::Resume) => screen.resume(),
+      None => {}
+    }
 
-    // Only poll keypresses every 30 frames
-    if counter % 30 == 0 {
-      if screen.update_keys() {
+    // Only poll keypresses every 30 frames, unless we're paused and have
+    // nothing better to do.
+    if counter % 30 == 0 || !control.is_running() || gdb_halted {
+      let hotkeys = screen.update_keys();
+      if hotkeys.contains(&Hotkey::Quit) {
         break;
       }
+
+      for hotkey in hotkeys.iter() {
+        match hotkey {
+          Hotkey::ToggleHud => {
+            show_hud = !show_hud;
+            screen.set_status_visible(show_hud);
+          },
+          Hotkey::ToggleSidePanel => {
+            show_registers = !show_registers;
+            screen.set_side_panel_visible(show_registers);
+          },
+          Hotkey::TogglePause => control.toggle_pause(),
+          Hotkey::AdvanceFrame => control.advance_frame(),
+          Hotkey::SoftReset => {
+            chip8.reset();
+            chip8.load_bytes(&rom.bytes);
+          },
+          Hotkey::HardReset => chip8 = boot(&rom, config),
+          Hotkey::Faster => control.faster(),
+          Hotkey::Slower => control.slower(),
+          Hotkey::NormalSpeed => control.normal_speed(),
+          Hotkey::ToggleUncapped => control.toggle_uncapped(),
+          Hotkey::Quit => {}
+        }
+      }
+
+      if !hotkeys.is_empty() {
+        screen.write_status(&stats.status_line(&rom_name, chip8.quirks().label(), &control.label()));
+      }
     }
-    //eprint!("\x1b[{};{}H[main] {:?}", 23, 0, screen.pressed_keys);
 
-    let previous_display = chip8.get_display().clone();
-    chip8.fde_loop(&screen.pressed_keys);
-    
-    screen.write(&previous_display, chip8.get_display());
-   
-    let elapsed: Duration = timer.elapsed();
-    // Beep if we can't keep up!
-    if elapsed >= Duration::from_millis((1000/TIMER_HZ)*2) {
-      screen.beep();
+    if let Some(change) = watcher.as_mut().and_then(RomWatcher::poll) {
+      if cli.keep_state && change.compatible {
+        // Only the program changes, and registers, timers and the display
+        // carry on.
+        chip8.load_bytes(&change.rom.bytes);
+      } else {
+        chip8 = boot(&change.rom, config);
+      }
+      control.set_rate(instruction_rate(&change.rom, cli.timing));
+      rom = change.rom;
+      rom_name = rom.name.clone();
     }
+    stats.advance_clock(control.speed_factor());
+
+    gdb_halted = false;
+    if let Some(stub) = gdb.as_mut() {
+      match stub.poll(&mut chip8) {
+        Ok(Action::Run) => {},
+        Ok(Action::Wait) => gdb_halted = true,
+        Ok(Action::Kill) => break,
+        Ok(Action::Detach) | Err(_) => gdb = None
+      }
+    }
+
+    if !control.is_running() || gdb_halted {
+      // Show what a reset did, even while paused. Only then, though: every
+      // update ends a frame, and persistence would fade away while paused.
+      let dirty = chip8.take_dirty();
+      if !dirty.is_empty() {
+        if let Some(changes) = filter.update(chip8.get_display(), &dirty, true) {
+          screen.write(filter.output(), &changes);
+        }
+      }
+
+      // Answer gdb quickly, it waits for every reply.
+      thread::sleep(Duration::from_millis(if gdb_halted { 1 } else { 1000 / FRAME_HZ }));
+      continue;
+    }
+
+    terminal::note_instruction(chip8.pc(), chip8.next_instruction());
+    // gdb stops between any two instructions, so they're run one by one.
+    let cycles = match cli.backend {
+      Backend::Jit if gdb.is_none() => chip8.run_block(&screen.pressed_keys),
+      _ => chip8.fde_loop(&screen.pressed_keys)
+    };
+    // cycles counts the instructions run, but for VIP timing, where it's
+    // machine cycles and always one instruction.
+    stats.count_instructions(if cli.timing == Timing::Vip { 1 } else { cycles as u64 });
+    if let Some(stub) = gdb.as_mut() {
+      if stub.executed(&chip8).is_err() {
+        gdb = None;
+      }
+    }
+    // gdb gets to look at a program that faulted, before it's gone.
+    if let Some(e) = chip8.fault().filter(|_| gdb.is_none()) {
+      fault = Some(format!("{} at 0x{:03X}", e, chip8.pc()));
+      break;
+    }
+
+    let (frames, timer_ticks) = clock.run(cycles as u64, control.rate() as u64);
+    let vblank = match cli.timing {
+      Timing::Fixed => frames > 0,
+      Timing::Vip => chip8.take_interrupts() > 0
+    };
+    if vblank {
+      chip8.vblank();
+      stats.count_frame();
+      control.end_frame();
+      if show_registers {
+        screen.write_side_panel(&register_panel(&chip8));
+      }
+    }
+
+    let dirty = chip8.take_dirty();
+    if let Some(changes) = filter.update(chip8.get_display(), &dirty, vblank) {
+      screen.write(filter.output(), &changes);
+    }
+   
     // If we're too fast, sleep for the remaining time.
-    if elapsed < Duration::from_millis(1000 / TIMER_HZ) {
-      thread::sleep(Duration::from_millis(1000 / TIMER_HZ) - elapsed);
+    if let Some(period) = control.instruction_period() {
+      let period = period * cycles;
+      let elapsed: Duration = timer.elapsed();
+      if elapsed < period {
+        thread::sleep(period - elapsed);
+      }
     }
-    if chip8.update_timers() {
-      screen.beep();
+    for _ in 0..timer_ticks {
+      if let Err(e) = audio.tick(chip8.update_timers(), chip8.audio_pattern()) {
+        // The game goes on without sound.
+        screen.write_status(&format!("Audio stopped: {}", e));
+        audio_error = Some(e);
+      }
+      stats.count_timer_tick();
+    }
+
+    if stats.update() {
+      screen.write_status(&stats.status_line(&rom_name, chip8.quirks().label(), &control.label()));
     }
     counter += 1;
   }
+
+  if let Err(e) = audio.finish() {
+    audio_error.get_or_insert(e);
+  }
+
+  // Leave raw mode before printing the summary.
+  drop(screen);
+  drop(terminal_guard);
+  println!("{}", stats.summary(&rom_name));
+  if let Some(fault) = fault {
+    println!("Error: {}", fault);
+  }
+  if let Some(e) = audio_error {
+    println!("Error: Audio stopped: {}", e);
+  }
 }
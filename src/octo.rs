@@ -0,0 +1,919 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+
+// An assembler for Octo, the language chip8 programs in Octo cartridges are
+// written in. It covers the language in Octo's manual: labels, constants,
+// aliases, :unpack, :org, :next, macros, :calc expressions, the structured
+// if/else/loop statements and the SCHIP and XO-CHIP instructions. :stringmode
+// isn't supported.
+
+const PROGRAM_START: usize = 0x200;
+// Macros that expand into more macros could go on forever.
+const MAX_EXPANSIONS: usize = 100_000;
+
+#[derive(Clone)]
+struct Token {
+  text: String,
+  line: usize
+}
+
+fn tokenize(source: &str) -> Vec<Token> {
+  let mut tokens = Vec::new();
+
+  for (line_number, line) in source.lines().enumerate() {
+    let mut characters = line.chars().peekable();
+    while let Some(&c) = characters.peek() {
+      if c.is_whitespace() {
+        characters.next();
+      } else if c == '#' {
+        break;
+      } else if c == '"' {
+        // Strings keep their quotes, so they can be told apart from names.
+        let mut text = String::new();
+        text.push(characters.next().unwrap());
+        for c in characters.by_ref() {
+          text.push(c);
+          if c == '"' {
+            break;
+          }
+        }
+        tokens.push(Token { text, line: line_number + 1 });
+      } else {
+        let mut text = String::new();
+        while let Some(&c) = characters.peek() {
+          if c.is_whitespace() {
+            break;
+          }
+          text.push(c);
+          characters.next();
+        }
+        tokens.push(Token { text, line: line_number + 1 });
+      }
+    }
+  }
+
+  tokens
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+  let (negative, digits) = match text.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, text)
+  };
+
+  let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+    i64::from_str_radix(hex, 16).ok()? as f64
+  } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+    i64::from_str_radix(binary, 2).ok()? as f64
+  } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+    digits.parse::<f64>().ok()?
+  } else {
+    return None;
+  };
+
+  Some(if negative { -value } else { value })
+}
+
+#[derive(Clone, Copy)]
+enum Comparison {
+  Equal,
+  NotEqual,
+  Less,
+  Greater,
+  LessOrEqual,
+  GreaterOrEqual,
+  Key,
+  NotKey
+}
+
+impl Comparison {
+  fn negated(self) -> Comparison {
+    match self {
+      Comparison::Equal => Comparison::NotEqual,
+      Comparison::NotEqual => Comparison::Equal,
+      Comparison::Less => Comparison::GreaterOrEqual,
+      Comparison::Greater => Comparison::LessOrEqual,
+      Comparison::LessOrEqual => Comparison::Greater,
+      Comparison::GreaterOrEqual => Comparison::Less,
+      Comparison::Key => Comparison::NotKey,
+      Comparison::NotKey => Comparison::Key
+    }
+  }
+}
+
+#[derive(Clone, Copy)]
+enum Operand {
+  Register(u8),
+  Byte(u8),
+  None
+}
+
+struct Condition {
+  register: u8,
+  comparison: Comparison,
+  operand: Operand
+}
+
+// Blocks that are still open, with the addresses of the jumps that have to be
+// pointed at their end.
+enum Block {
+  If(usize),
+  Else(usize),
+  Loop { start: usize, whiles: Vec<usize> }
+}
+
+#[derive(Clone, Copy)]
+enum Fixup {
+  // The low 12 bits of an instruction.
+  Address,
+  // The 16-bit address after an i := long.
+  Long,
+  // The two instructions of an :unpack, with the given high nibble.
+  Unpack(u8),
+  UnpackLong
+}
+
+struct Macro {
+  arguments: Vec<String>,
+  body: Vec<Token>
+}
+
+struct Assembler {
+  tokens: VecDeque<Token>,
+  line: usize,
+  expansions: usize,
+
+  rom: Vec<u8>,
+  here: usize,
+
+  labels: HashMap<String, usize>,
+  constants: HashMap<String, f64>,
+  aliases: HashMap<String, u8>,
+  macros: HashMap<String, Macro>,
+
+  fixups: Vec<(usize, String, Fixup, usize)>,
+  blocks: Vec<Block>,
+  // Label waiting for the next instruction, from :next.
+  next_label: Option<String>
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+impl Assembler {
+  fn error<T>(&self, message: String) -> Result<T> {
+    Err(format!("line {}: {}", self.line, message))
+  }
+
+  fn next(&mut self) -> Result<String> {
+    match self.tokens.pop_front() {
+      Some(token) => {
+        self.line = token.line;
+        Ok(token.text)
+      },
+      None => self.error("unexpected end of program".to_string())
+    }
+  }
+
+  fn peek(&self) -> Option<&str> {
+    self.tokens.front().map(|token| token.text.as_str())
+  }
+
+  fn expect(&mut self, expected: &str) -> Result<()> {
+    let token = self.next()?;
+    if token != expected {
+      return self.error(format!("expected '{}', found '{}'", expected, token));
+    }
+    Ok(())
+  }
+
+  // Writes a byte at the current address, and moves on.
+  fn emit(&mut self, byte: u8) -> Result<()> {
+    if self.here < PROGRAM_START || self.here > 0xFFFF {
+      return self.error(format!("address 0x{:X} is out of the program's memory", self.here));
+    }
+    let offset = self.here - PROGRAM_START;
+    if self.rom.len() <= offset {
+      self.rom.resize(offset + 1, 0);
+    }
+    self.rom[offset] = byte;
+    self.here += 1;
+    Ok(())
+  }
+
+  fn instruction(&mut self, high: u8, low: u8) -> Result<()> {
+    if let Some(name) = self.next_label.take() {
+      self.define_label(name, self.here + 1)?;
+    }
+    self.emit(high)?;
+    self.emit(low)
+  }
+
+  fn write_at(&mut self, address: usize, byte: u8) {
+    self.rom[address - PROGRAM_START] = byte;
+  }
+
+  fn patch_jump(&mut self, address: usize, target: usize) {
+    self.write_at(address, 0x10 | (target >> 8) as u8 & 0x0F);
+    self.write_at(address + 1, target as u8);
+  }
+
+  fn define_label(&mut self, name: String, address: usize) -> Result<()> {
+    if self.labels.contains_key(&name) {
+      return self.error(format!("the label '{}' is defined twice", name));
+    }
+    self.labels.insert(name, address);
+    Ok(())
+  }
+
+  fn register_of(&self, token: &str) -> Option<u8> {
+    if let Some(register) = self.aliases.get(token) {
+      return Some(*register);
+    }
+    let mut characters = token.chars();
+    match (characters.next(), characters.next(), characters.next()) {
+      (Some('v') | Some('V'), Some(digit), None) => digit.to_digit(16).map(|digit| digit as u8),
+      _ => None
+    }
+  }
+
+  fn register(&mut self) -> Result<u8> {
+    let token = self.next()?;
+    match self.register_of(&token) {
+      Some(register) => Ok(register),
+      None => self.error(format!("expected a register, found '{}'", token))
+    }
+  }
+
+  fn is_register(&self) -> bool {
+    self.peek().is_some_and(|token| self.register_of(token).is_some())
+  }
+
+  // The value of a name or number that has to be known right now.
+  fn lookup(&self, token: &str) -> Option<f64> {
+    parse_number(token)
+      .or_else(|| self.constants.get(token).copied())
+      .or_else(|| self.labels.get(token).map(|address| *address as f64))
+  }
+
+  // A number, a name or a { calc expression }. None if it's a name that
+  // isn't defined yet (a label further down, presumably).
+  fn value(&mut self) -> Result<(String, Option<f64>)> {
+    let token = self.next()?;
+    if token == "{" {
+      let value = self.calc_block()?;
+      return Ok((token, Some(value)));
+    }
+    let value = self.lookup(&token);
+    Ok((token, value))
+  }
+
+  fn known_value(&mut self) -> Result<f64> {
+    match self.value()? {
+      (_, Some(value)) => Ok(value),
+      (token, None) => self.error(format!("'{}' isn't defined", token))
+    }
+  }
+
+  fn byte(&mut self) -> Result<u8> {
+    let value = self.known_value()?.floor();
+    if !(-128.0..=255.0).contains(&value) {
+      return self.error(format!("{} doesn't fit in a byte", value));
+    }
+    Ok(value as i64 as u8)
+  }
+
+  fn nibble(&mut self) -> Result<u8> {
+    let value = self.known_value()?.floor();
+    if !(0.0..=15.0).contains(&value) {
+      return self.error(format!("{} doesn't fit in a nibble", value));
+    }
+    Ok(value as u8)
+  }
+
+  // Emits an instruction with an address in it, possibly to be filled in
+  // later.
+  fn address_instruction(&mut self, high: u8, fixup: Fixup) -> Result<()> {
+    let (token, value) = self.value()?;
+    let address = self.here + if matches!(fixup, Fixup::Long) { 2 } else { 0 };
+    match fixup {
+      Fixup::Long => self.instruction(0xF0, 0x00)?,
+      _ => self.instruction(high, 0x00)?
+    }
+    if matches!(fixup, Fixup::Long) {
+      self.emit(0)?;
+      self.emit(0)?;
+    }
+
+    match value {
+      Some(value) => self.resolve(address, value as usize, fixup),
+      None => {
+        self.fixups.push((address, token, fixup, self.line));
+        Ok(())
+      }
+    }
+  }
+
+  fn resolve(&mut self, address: usize, value: usize, fixup: Fixup) -> Result<()> {
+    match fixup {
+      Fixup::Address => {
+        if value > 0xFFF {
+          return self.error(format!("address 0x{:X} doesn't fit in 12 bits", value));
+        }
+        let high = self.rom[address - PROGRAM_START];
+        self.write_at(address, high | (value >> 8) as u8);
+        self.write_at(address + 1, value as u8);
+      },
+      Fixup::Long => {
+        self.write_at(address, (value >> 8) as u8);
+        self.write_at(address + 1, value as u8);
+      },
+      Fixup::Unpack(nibble) => {
+        self.write_at(address + 1, nibble << 4 | (value >> 8) as u8 & 0x0F);
+        self.write_at(address + 3, value as u8);
+      },
+      Fixup::UnpackLong => {
+        self.write_at(address + 1, (value >> 8) as u8);
+        self.write_at(address + 3, value as u8);
+      }
+    }
+    Ok(())
+  }
+
+  fn condition(&mut self) -> Result<Condition> {
+    let register = self.register()?;
+    let token = self.next()?;
+    let comparison = match token.as_str() {
+      "==" => Comparison::Equal,
+      "!=" => Comparison::NotEqual,
+      "<" => Comparison::Less,
+      ">" => Comparison::Greater,
+      "<=" => Comparison::LessOrEqual,
+      ">=" => Comparison::GreaterOrEqual,
+      "key" => return Ok(Condition { register, comparison: Comparison::Key, operand: Operand::None }),
+      "-key" => return Ok(Condition { register, comparison: Comparison::NotKey, operand: Operand::None }),
+      _ => return self.error(format!("'{}' isn't a comparison", token))
+    };
+    let operand = if self.is_register() { Operand::Register(self.register()?) } else { Operand::Byte(self.byte()?) };
+    Ok(Condition { register, comparison, operand })
+  }
+
+  // Emits the instructions that skip the next one unless condition holds.
+  fn skip_unless(&mut self, condition: &Condition) -> Result<()> {
+    let x = condition.register;
+    // <, >, <= and >= compare through VF.
+    let compare = |assembler: &mut Assembler, subtraction: u8, skip: u8| -> Result<()> {
+      match condition.operand {
+        Operand::Register(y) => assembler.instruction(0x8F, y << 4)?,
+        Operand::Byte(byte) => assembler.instruction(0x6F, byte)?,
+        Operand::None => {}
+      }
+      assembler.instruction(0x8F, x << 4 | subtraction)?;
+      assembler.instruction(skip, 1)
+    };
+
+    match (condition.comparison, condition.operand) {
+      (Comparison::Equal, Operand::Register(y)) => self.instruction(0x90 | x, y << 4),
+      (Comparison::Equal, Operand::Byte(byte)) => self.instruction(0x40 | x, byte),
+      (Comparison::NotEqual, Operand::Register(y)) => self.instruction(0x50 | x, y << 4),
+      (Comparison::NotEqual, Operand::Byte(byte)) => self.instruction(0x30 | x, byte),
+      (Comparison::Key, _) => self.instruction(0xE0 | x, 0xA1),
+      (Comparison::NotKey, _) => self.instruction(0xE0 | x, 0x9E),
+      (Comparison::Greater, _) => compare(self, 0x5, 0x3F),
+      (Comparison::Less, _) => compare(self, 0x7, 0x3F),
+      (Comparison::GreaterOrEqual, _) => compare(self, 0x7, 0x4F),
+      (Comparison::LessOrEqual, _) => compare(self, 0x5, 0x4F),
+      (_, Operand::None) => unreachable!()
+    }
+  }
+
+  // Emits a jump to be pointed somewhere later, and returns its address.
+  fn placeholder_jump(&mut self) -> Result<usize> {
+    let address = self.here;
+    self.instruction(0x10, 0x00)?;
+    Ok(address)
+  }
+
+  fn block_tokens(&mut self) -> Result<Vec<Token>> {
+    self.expect("{")?;
+    let mut depth = 1;
+    let mut body = Vec::new();
+    loop {
+      let token = match self.tokens.pop_front() {
+        Some(token) => token,
+        None => return self.error("missing '}'".to_string())
+      };
+      match token.text.as_str() {
+        "{" => depth += 1,
+        "}" => depth -= 1,
+        _ => {}
+      }
+      if depth == 0 {
+        return Ok(body);
+      }
+      body.push(token);
+    }
+  }
+
+  // Evaluates a { calc expression }, whose "{" has already been read.
+  fn calc_block(&mut self) -> Result<f64> {
+    self.tokens.push_front(Token { text: "{".to_string(), line: self.line });
+    let tokens = self.block_tokens()?;
+    let mut expression = Calc { tokens: tokens.iter().map(|token| token.text.as_str()).collect(), position: 0, assembler: self };
+    let value = expression.expression();
+    let finished = expression.position == expression.tokens.len();
+    match value {
+      Ok(_) if !finished => self.error("unexpected tokens after the expression".to_string()),
+      Ok(value) => Ok(value),
+      Err(e) => self.error(e)
+    }
+  }
+
+  fn statement(&mut self) -> Result<()> {
+    let token = self.next()?;
+
+    if let Some(register) = self.register_of(&token) {
+      return self.assignment(register);
+    }
+
+    match token.as_str() {
+      ":" => {
+        let name = self.next()?;
+        self.define_label(name, self.here)?;
+      },
+      ":next" => self.next_label = Some(self.next()?),
+      ":const" => {
+        let name = self.next()?;
+        let value = self.known_value()?;
+        self.constants.insert(name, value);
+      },
+      ":alias" => {
+        let name = self.next()?;
+        let register = self.register()?;
+        self.aliases.insert(name, register);
+      },
+      ":unpack" => {
+        let fixup = if self.peek() == Some("long") {
+          self.next()?;
+          Fixup::UnpackLong
+        } else {
+          Fixup::Unpack(self.nibble()?)
+        };
+        let (name, value) = self.value()?;
+        let address = self.here;
+        self.instruction(0x60, 0)?;
+        self.instruction(0x61, 0)?;
+        match value {
+          Some(value) => self.resolve(address, value as usize, fixup)?,
+          None => self.fixups.push((address, name, fixup, self.line))
+        }
+      },
+      ":org" => self.here = self.known_value()? as usize,
+      ":byte" => {
+        let byte = self.byte()?;
+        self.emit(byte)?;
+      },
+      ":call" => self.address_instruction(0x20, Fixup::Address)?,
+      ":macro" => {
+        let name = self.next()?;
+        let mut arguments = Vec::new();
+        while self.peek() != Some("{") {
+          arguments.push(self.next()?);
+        }
+        let body = self.block_tokens()?;
+        self.macros.insert(name, Macro { arguments, body });
+      },
+      ":calc" => {
+        let name = self.next()?;
+        self.expect("{")?;
+        let value = self.calc_block()?;
+        self.constants.insert(name, value);
+      },
+      ":assert" => {
+        let message = if self.peek().is_some_and(|token| token.starts_with('"')) {
+          self.next()?.trim_matches('"').to_string()
+        } else {
+          "assertion failed".to_string()
+        };
+        self.expect("{")?;
+        if self.calc_block()? == 0.0 {
+          return self.error(message);
+        }
+      },
+      // Debugger directives mean nothing here.
+      ":breakpoint" | ":proto" => {
+        self.next()?;
+      },
+      ":monitor" => {
+        self.next()?;
+        self.next()?;
+      },
+      ":stringmode" => return self.error(":stringmode isn't supported".to_string()),
+
+      ";" | "return" => self.instruction(0x00, 0xEE)?,
+      "clear" => self.instruction(0x00, 0xE0)?,
+      "hires" => self.instruction(0x00, 0xFF)?,
+      "lores" => self.instruction(0x00, 0xFE)?,
+      "exit" => self.instruction(0x00, 0xFD)?,
+      "scroll-left" => self.instruction(0x00, 0xFC)?,
+      "scroll-right" => self.instruction(0x00, 0xFB)?,
+      "scroll-down" => {
+        let rows = self.nibble()?;
+        self.instruction(0x00, 0xC0 | rows)?;
+      },
+      "scroll-up" => {
+        let rows = self.nibble()?;
+        self.instruction(0x00, 0xD0 | rows)?;
+      },
+      "audio" => self.instruction(0xF0, 0x02)?,
+      "plane" => {
+        let planes = self.nibble()?;
+        self.instruction(0xF0 | planes, 0x01)?;
+      },
+      "bcd" => {
+        let x = self.register()?;
+        self.instruction(0xF0 | x, 0x33)?;
+      },
+      "save" | "load" => {
+        let x = self.register()?;
+        if self.peek() == Some("-") {
+          self.next()?;
+          let y = self.register()?;
+          self.instruction(0x50 | x, y << 4 | if token == "save" { 0x2 } else { 0x3 })?;
+        } else {
+          self.instruction(0xF0 | x, if token == "save" { 0x55 } else { 0x65 })?;
+        }
+      },
+      "saveflags" | "loadflags" => {
+        let x = self.register()?;
+        self.instruction(0xF0 | x, if token == "saveflags" { 0x75 } else { 0x85 })?;
+      },
+      "sprite" => {
+        let x = self.register()?;
+        let y = self.register()?;
+        let rows = self.nibble()?;
+        self.instruction(0xD0 | x, y << 4 | rows)?;
+      },
+      "jump" => self.address_instruction(0x10, Fixup::Address)?,
+      "jump0" => self.address_instruction(0xB0, Fixup::Address)?,
+      "native" => self.address_instruction(0x00, Fixup::Address)?,
+      "delay" | "buzzer" | "pitch" => {
+        self.expect(":=")?;
+        let x = self.register()?;
+        let low = match token.as_str() { "delay" => 0x15, "buzzer" => 0x18, _ => 0x3A };
+        self.instruction(0xF0 | x, low)?;
+      },
+      "i" => {
+        let operator = self.next()?;
+        match operator.as_str() {
+          ":=" => match self.peek() {
+            Some("long") => {
+              self.next()?;
+              self.address_instruction(0xF0, Fixup::Long)?;
+            },
+            Some("hex") | Some("bighex") => {
+              let big = self.next()? == "bighex";
+              let x = self.register()?;
+              self.instruction(0xF0 | x, if big { 0x30 } else { 0x29 })?;
+            },
+            _ => self.address_instruction(0xA0, Fixup::Address)?
+          },
+          "+=" => {
+            let x = self.register()?;
+            self.instruction(0xF0 | x, 0x1E)?;
+          },
+          _ => return self.error(format!("'{}' can't be used with i", operator))
+        }
+      },
+
+      "if" => {
+        let condition = self.condition()?;
+        match self.next()?.as_str() {
+          "then" => self.skip_unless(&condition)?,
+          "begin" => {
+            let negated = Condition { comparison: condition.comparison.negated(), ..condition };
+            self.skip_unless(&negated)?;
+            let jump = self.placeholder_jump()?;
+            self.blocks.push(Block::If(jump));
+          },
+          other => return self.error(format!("expected 'then' or 'begin', found '{}'", other))
+        }
+      },
+      "else" => match self.blocks.pop() {
+        Some(Block::If(jump)) => {
+          let end_jump = self.placeholder_jump()?;
+          self.patch_jump(jump, self.here);
+          self.blocks.push(Block::Else(end_jump));
+        },
+        _ => return self.error("'else' without 'if ... begin'".to_string())
+      },
+      "end" => match self.blocks.pop() {
+        Some(Block::If(jump)) | Some(Block::Else(jump)) => self.patch_jump(jump, self.here),
+        _ => return self.error("'end' without 'if ... begin'".to_string())
+      },
+      "loop" => self.blocks.push(Block::Loop { start: self.here, whiles: Vec::new() }),
+      "while" => {
+        let condition = self.condition()?;
+        let negated = Condition { comparison: condition.comparison.negated(), ..condition };
+        self.skip_unless(&negated)?;
+        let jump = self.placeholder_jump()?;
+        match self.blocks.iter_mut().rev().find(|block| matches!(block, Block::Loop { .. })) {
+          Some(Block::Loop { whiles, .. }) => whiles.push(jump),
+          _ => return self.error("'while' outside of a loop".to_string())
+        }
+      },
+      "again" => match self.blocks.pop() {
+        Some(Block::Loop { start, whiles }) => {
+          self.instruction(0x10 | (start >> 8) as u8 & 0x0F, start as u8)?;
+          for jump in whiles {
+            self.patch_jump(jump, self.here);
+          }
+        },
+        _ => return self.error("'again' without 'loop'".to_string())
+      },
+
+      "{" => {
+        let value = self.calc_block()?;
+        self.emit(value as i64 as u8)?;
+      },
+      _ => {
+        if let Some(number) = parse_number(&token) {
+          if !(-128.0..=255.0).contains(&number) {
+            return self.error(format!("{} doesn't fit in a byte", number));
+          }
+          self.emit(number as i64 as u8)?;
+        } else if self.macros.contains_key(&token) {
+          self.expand(&token)?;
+        } else if let Some(value) = self.constants.get(&token) {
+          let byte = *value as i64 as u8;
+          self.emit(byte)?;
+        } else if token.starts_with(':') || token.starts_with('"') {
+          return self.error(format!("'{}' isn't something Octo knows", token));
+        } else {
+          // Anything else is a subroutine call.
+          self.tokens.push_front(Token { text: token, line: self.line });
+          self.address_instruction(0x20, Fixup::Address)?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn assignment(&mut self, x: u8) -> Result<()> {
+    let operator = self.next()?;
+    let register_operation = match operator.as_str() {
+      ":=" => 0x0,
+      "|=" => 0x1,
+      "&=" => 0x2,
+      "^=" => 0x3,
+      "+=" => 0x4,
+      "-=" => 0x5,
+      ">>=" => 0x6,
+      "=-" => 0x7,
+      "<<=" => 0xE,
+      _ => return self.error(format!("'{}' isn't an operator", operator))
+    };
+
+    if self.is_register() {
+      let y = self.register()?;
+      return self.instruction(0x80 | x, y << 4 | register_operation);
+    }
+
+    match (operator.as_str(), self.peek()) {
+      (":=", Some("random")) => {
+        self.next()?;
+        let mask = self.byte()?;
+        self.instruction(0xC0 | x, mask)
+      },
+      (":=", Some("key")) => {
+        self.next()?;
+        self.instruction(0xF0 | x, 0x0A)
+      },
+      (":=", Some("delay")) => {
+        self.next()?;
+        self.instruction(0xF0 | x, 0x07)
+      },
+      (":=", _) => {
+        let byte = self.byte()?;
+        self.instruction(0x60 | x, byte)
+      },
+      ("+=", _) => {
+        let byte = self.byte()?;
+        self.instruction(0x70 | x, byte)
+      },
+      ("-=", _) => {
+        let byte = self.byte()?;
+        self.instruction(0x70 | x, byte.wrapping_neg())
+      },
+      _ => self.error(format!("'{}' needs a register", operator))
+    }
+  }
+
+  fn expand(&mut self, name: &str) -> Result<()> {
+    self.expansions += 1;
+    if self.expansions > MAX_EXPANSIONS {
+      return self.error(format!("too many macro expansions (is '{}' recursive?)", name));
+    }
+
+    let argument_count = self.macros[name].arguments.len();
+    let mut values = HashMap::new();
+    for i in 0..argument_count {
+      let value = self.next()?;
+      values.insert(self.macros[name].arguments[i].clone(), value);
+    }
+
+    let line = self.line;
+    for token in self.macros[name].body.iter().rev() {
+      let text = values.get(&token.text).cloned().unwrap_or_else(|| token.text.clone());
+      self.tokens.push_front(Token { text, line });
+    }
+    Ok(())
+  }
+}
+
+// A :calc expression. Like in Octo, operators have no precedence and are
+// evaluated right to left: 2 * 3 + 1 is 8.
+struct Calc<'a> {
+  tokens: Vec<&'a str>,
+  position: usize,
+  assembler: &'a Assembler
+}
+
+impl<'a> Calc<'a> {
+  fn next(&mut self) -> std::result::Result<&'a str, String> {
+    let token = self.tokens.get(self.position).copied().ok_or_else(|| "unfinished expression".to_string())?;
+    self.position += 1;
+    Ok(token)
+  }
+
+  fn expression(&mut self) -> std::result::Result<f64, String> {
+    let left = self.term()?;
+    let operator = match self.tokens.get(self.position) {
+      Some(operator) if *operator != ")" => *operator,
+      _ => return Ok(left)
+    };
+    self.position += 1;
+    let right = self.expression()?;
+
+    let truth = |condition: bool| if condition { 1.0 } else { 0.0 };
+    let (a, b) = (left as i64, right as i64);
+    // Shifting by a negative amount, or by 64 or more, is an error.
+    let shift = |shifted: fn(i64, u32) -> Option<i64>| {
+      u32::try_from(b).ok().and_then(|b| shifted(a, b)).map(|x| x as f64).ok_or_else(|| format!("can't shift by {}", b))
+    };
+    Ok(match operator {
+      "+" => left + right,
+      "-" => left - right,
+      "*" => left * right,
+      "/" => left / right,
+      "%" => left % right,
+      "pow" => left.powf(right),
+      "min" => left.min(right),
+      "max" => left.max(right),
+      "&" => (a & b) as f64,
+      "|" => (a | b) as f64,
+      "^" => (a ^ b) as f64,
+      "<<" => shift(i64::checked_shl)?,
+      ">>" => shift(i64::checked_shr)?,
+      "<" => truth(left < right),
+      ">" => truth(left > right),
+      "<=" => truth(left <= right),
+      ">=" => truth(left >= right),
+      "==" => truth(left == right),
+      "!=" => truth(left != right),
+      _ => return Err(format!("'{}' isn't an operator", operator))
+    })
+  }
+
+  fn term(&mut self) -> std::result::Result<f64, String> {
+    let token = self.next()?;
+    let unary = |f: fn(f64) -> f64, calc: &mut Calc| calc.term().map(f);
+
+    match token {
+      "(" => {
+        let value = self.expression()?;
+        match self.next()? {
+          ")" => Ok(value),
+          other => Err(format!("expected ')', found '{}'", other))
+        }
+      },
+      "-" => unary(|x| -x, self),
+      "~" => unary(|x| !(x as i64) as f64, self),
+      "!" => unary(|x| if x == 0.0 { 1.0 } else { 0.0 }, self),
+      "sin" => unary(f64::sin, self),
+      "cos" => unary(f64::cos, self),
+      "tan" => unary(f64::tan, self),
+      "exp" => unary(f64::exp, self),
+      "log" => unary(f64::ln, self),
+      "abs" => unary(f64::abs, self),
+      "sqrt" => unary(f64::sqrt, self),
+      "sign" => unary(f64::signum, self),
+      "ceil" => unary(f64::ceil, self),
+      "floor" => unary(f64::floor, self),
+      "@" => {
+        let address = self.term()? as usize;
+        Ok(address.checked_sub(PROGRAM_START)
+          .and_then(|offset| self.assembler.rom.get(offset))
+          .copied()
+          .unwrap_or(0) as f64)
+      },
+      "PI" => Ok(std::f64::consts::PI),
+      "E" => Ok(std::f64::consts::E),
+      "HERE" => Ok(self.assembler.here as f64),
+      _ => self.assembler.lookup(token).ok_or_else(|| format!("'{}' isn't defined", token))
+    }
+  }
+}
+
+// Assembles a program, which has to have a main label, into the bytes that
+// go at 0x200.
+pub fn assemble(source: &str) -> Result<Vec<u8>> {
+  let mut assembler = Assembler {
+    tokens: tokenize(source).into(),
+    line: 1,
+    expansions: 0,
+    rom: Vec::new(),
+    here: PROGRAM_START,
+    labels: HashMap::new(),
+    constants: HashMap::new(),
+    aliases: HashMap::new(),
+    macros: HashMap::new(),
+    fixups: Vec::new(),
+    blocks: Vec::new(),
+    next_label: None
+  };
+
+  // Execution starts at 0x200 with a jump to main.
+  assembler.instruction(0x10, 0x00)?;
+  while !assembler.tokens.is_empty() {
+    assembler.statement()?;
+  }
+
+  if !assembler.blocks.is_empty() {
+    return assembler.error("a block isn't closed at the end of the program".to_string());
+  }
+  let main = match assembler.labels.get("main") {
+    Some(main) => *main,
+    None => return Err("the program has no main label".to_string())
+  };
+  assembler.patch_jump(PROGRAM_START, main);
+
+  for (address, name, fixup, line) in std::mem::take(&mut assembler.fixups) {
+    assembler.line = line;
+    match assembler.labels.get(&name) {
+      Some(value) => assembler.resolve(address, *value, fixup)?,
+      None => return assembler.error(format!("'{}' isn't defined", name))
+    }
+  }
+
+  Ok(assembler.rom)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn instructions_are_assembled_after_a_jump_to_main() {
+    let rom = assemble(": main\n  clear\n  v0 := 5  v1 += v0  i := sprite  sprite v0 v1 1\n  return\n: sprite 0x80").unwrap();
+    assert_eq!(rom, [0x12, 0x02, 0x00, 0xE0, 0x60, 0x05, 0x81, 0x04, 0xA2, 0x0E, 0xD0, 0x11, 0x00, 0xEE, 0x80]);
+  }
+
+  #[test]
+  fn blocks_jump_around_their_bodies() {
+    let rom = assemble(": main loop if v0 == 1 begin v1 := 1 else v1 := 2 end while v2 != 3 again").unwrap();
+    assert_eq!(rom, [
+      0x12, 0x02,
+      0x30, 0x01, 0x12, 0x0A, // if v0 == 1 begin
+      0x61, 0x01, 0x12, 0x0C, // v1 := 1 else
+      0x61, 0x02,             // v1 := 2 end
+      0x42, 0x03, 0x12, 0x12, // while v2 != 3
+      0x12, 0x02              // again
+    ]);
+  }
+
+  #[test]
+  fn comparisons_go_through_vf() {
+    let rom = assemble(": main if v3 > 7 then clear").unwrap();
+    assert_eq!(rom, [0x12, 0x02, 0x6F, 0x07, 0x8F, 0x35, 0x3F, 0x01, 0x00, 0xE0]);
+  }
+
+  #[test]
+  fn macros_constants_and_calc_are_expanded() {
+    let source = ":const SPEED 3\n:calc DOUBLE { SPEED * 2 }\n:macro step reg { reg += DOUBLE }\n: main step v4";
+    assert_eq!(assemble(source).unwrap(), [0x12, 0x02, 0x74, 0x06]);
+  }
+
+  #[test]
+  fn calc_shifts_by_too_much_are_reported() {
+    assert_eq!(assemble(":calc X { 1 << 4 }\n: main v0 := X").unwrap(), [0x12, 0x02, 0x60, 0x10]);
+    for source in [":calc X { 1 << 64 }", ":calc X { 1 >> -1 }", ":calc X { 1 << 1000000000000 }"] {
+      assert!(assemble(source).unwrap_err().contains("can't shift"), "{}", source);
+    }
+  }
+
+  #[test]
+  fn undefined_labels_are_reported() {
+    assert!(assemble(": main jump nowhere").unwrap_err().contains("nowhere"));
+    assert!(assemble("clear").unwrap_err().contains("main"));
+  }
+}
@@ -0,0 +1,128 @@
+// A decoded instruction: what it does, with its operands already pulled out
+// of it. x and y are register numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Op {
+  Cls,                  // 00E0
+  Ret,                  // 00EE
+  Sys(u16),             // 0nnn
+  Jp(u16),              // 1nnn
+  Call(u16),            // 2nnn
+  SeByte(u8, u8),       // 3xkk
+  SneByte(u8, u8),      // 4xkk
+  SeReg(u8, u8),        // 5xy0
+  LdByte(u8, u8),       // 6xkk
+  AddByte(u8, u8),      // 7xkk
+  LdReg(u8, u8),        // 8xy0
+  Or(u8, u8),           // 8xy1
+  And(u8, u8),          // 8xy2
+  Xor(u8, u8),          // 8xy3
+  AddReg(u8, u8),       // 8xy4
+  Sub(u8, u8),          // 8xy5
+  Shr(u8, u8),          // 8xy6
+  Subn(u8, u8),         // 8xy7
+  Shl(u8, u8),          // 8xyE
+  SneReg(u8, u8),       // 9xy0
+  LdI(u16),             // Annn
+  JpV0(u16, u8),        // Bnnn, and x for the jump quirk
+  Rnd(u8, u8),          // Cxkk
+  Drw(u8, u8, u8),      // Dxyn
+  Skp(u8),              // Ex9E
+  Sknp(u8),             // ExA1
+  Audio,                // F002
+  LdVxDt(u8),           // Fx07
+  LdVxK(u8),            // Fx0A
+  LdDtVx(u8),           // Fx15
+  LdStVx(u8),           // Fx18
+  AddI(u8),             // Fx1E
+  LdF(u8),              // Fx29
+  LdHf(u8),             // Fx30
+  LdB(u8),              // Fx33
+  Pitch(u8),            // Fx3A
+  LdIVx(u8),            // Fx55
+  LdVxI(u8),            // Fx65
+  Unknown(u16)          // Does nothing
+}
+
+impl Op {
+  pub fn decode(instruction: u16) -> Op {
+    let x = ((instruction & 0x0f00) >> 8) as u8;
+    let y = ((instruction & 0x00f0) >> 4) as u8;
+    let n = (instruction & 0x000f) as u8;
+    let kk = (instruction & 0x00ff) as u8;
+    let nnn = instruction & 0x0fff;
+
+    match instruction & 0xf000 {
+      0x0000 => match instruction {
+        0x00E0 => Op::Cls,
+        0x00EE => Op::Ret,
+        _ => Op::Sys(nnn)
+      },
+      0x1000 => Op::Jp(nnn),
+      0x2000 => Op::Call(nnn),
+      0x3000 => Op::SeByte(x, kk),
+      0x4000 => Op::SneByte(x, kk),
+      0x5000 if n == 0 => Op::SeReg(x, y),
+      0x6000 => Op::LdByte(x, kk),
+      0x7000 => Op::AddByte(x, kk),
+      0x8000 => match n {
+        0x0 => Op::LdReg(x, y),
+        0x1 => Op::Or(x, y),
+        0x2 => Op::And(x, y),
+        0x3 => Op::Xor(x, y),
+        0x4 => Op::AddReg(x, y),
+        0x5 => Op::Sub(x, y),
+        0x6 => Op::Shr(x, y),
+        0x7 => Op::Subn(x, y),
+        0xE => Op::Shl(x, y),
+        _ => Op::Unknown(instruction)
+      },
+      0x9000 if n == 0 => Op::SneReg(x, y),
+      0xA000 => Op::LdI(nnn),
+      0xB000 => Op::JpV0(nnn, x),
+      0xC000 => Op::Rnd(x, kk),
+      0xD000 => Op::Drw(x, y, n),
+      0xE000 => match kk {
+        0x9E => Op::Skp(x),
+        0xA1 => Op::Sknp(x),
+        _ => Op::Unknown(instruction)
+      },
+      0xF000 => match kk {
+        0x02 if x == 0 => Op::Audio,
+        0x07 => Op::LdVxDt(x),
+        0x0A => Op::LdVxK(x),
+        0x15 => Op::LdDtVx(x),
+        0x18 => Op::LdStVx(x),
+        0x1E => Op::AddI(x),
+        0x29 => Op::LdF(x),
+        0x30 => Op::LdHf(x),
+        0x33 => Op::LdB(x),
+        0x3A => Op::Pitch(x),
+        0x55 => Op::LdIVx(x),
+        0x65 => Op::LdVxI(x),
+        _ => Op::Unknown(instruction)
+      },
+      _ => Op::Unknown(instruction)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn operands_are_pulled_out() {
+    assert_eq!(Op::decode(0xD12F), Op::Drw(0x1, 0x2, 0xF));
+    assert_eq!(Op::decode(0xB345), Op::JpV0(0x345, 0x3));
+    assert_eq!(Op::decode(0x8AB6), Op::Shr(0xA, 0xB));
+    assert_eq!(Op::decode(0x0123), Op::Sys(0x123));
+  }
+
+  #[test]
+  fn malformed_instructions_are_unknown() {
+    assert_eq!(Op::decode(0x5121), Op::Unknown(0x5121));
+    assert_eq!(Op::decode(0x812F), Op::Unknown(0x812F));
+    assert_eq!(Op::decode(0xF102), Op::Unknown(0xF102));
+    assert_eq!(Op::decode(0xE1FF), Op::Unknown(0xE1FF));
+  }
+}
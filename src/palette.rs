@@ -0,0 +1,285 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// A palette has four entries, one per combination of XO-CHIP bitplanes:
+// 0 is the background (no plane set), 1 is plane 1, 2 is plane 2 and 3 is
+// both planes at once. Plain chip8 only ever uses the first two.
+pub const PALETTE_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rgb(pub u8, pub u8, pub u8);
+
+impl Rgb {
+  // Parses "#rrggbb", "rrggbb" or the "#rgb" shorthand.
+  pub fn parse_hex(text: &str) -> Result<Rgb, String> {
+    let hex = text.trim().trim_start_matches('#');
+    // Checked up front, so that the slices below fall on character boundaries.
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+      return Err(format!("'{}' is not a hex color (expected #rrggbb)", text));
+    }
+    let expanded: String = match hex.len() {
+      3 => hex.chars().flat_map(|c| [c, c]).collect(),
+      6 => hex.to_string(),
+      _ => return Err(format!("'{}' is not a hex color (expected #rrggbb)", text))
+    };
+
+    let channel = |i: usize| u8::from_str_radix(&expanded[i..i+2], 16)
+      .map_err(|_| format!("'{}' is not a hex color (expected #rrggbb)", text));
+
+    Ok(Rgb(channel(0)?, channel(2)?, channel(4)?))
+  }
+
+  fn distance(&self, other: &Rgb) -> u32 {
+    let dr = self.0 as i32 - other.0 as i32;
+    let dg = self.1 as i32 - other.1 as i32;
+    let db = self.2 as i32 - other.2 as i32;
+    (dr*dr + dg*dg + db*db) as u32
+  }
+}
+
+// How many colors the terminal can show.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorDepth {
+  TrueColor,
+  Ansi256,
+  Ansi16
+}
+
+impl ColorDepth {
+  // Terminals that support 24-bit color advertise it through COLORTERM. If
+  // they don't, TERM tells us whether we can at least use the 256 color cube.
+  pub fn detect() -> ColorDepth {
+    let colorterm = env::var("COLORTERM").unwrap_or_default();
+    if colorterm == "truecolor" || colorterm == "24bit" {
+      return ColorDepth::TrueColor;
+    }
+
+    let term = env::var("TERM").unwrap_or_default();
+    if term.contains("256color") {
+      ColorDepth::Ansi256
+    } else {
+      ColorDepth::Ansi16
+    }
+  }
+}
+
+// Approximate RGB values of the 16 standard terminal colors (xterm's defaults).
+const ANSI16: [Rgb; 16] = [
+  Rgb(0, 0, 0), Rgb(205, 0, 0), Rgb(0, 205, 0), Rgb(205, 205, 0),
+  Rgb(0, 0, 238), Rgb(205, 0, 205), Rgb(0, 205, 205), Rgb(229, 229, 229),
+  Rgb(127, 127, 127), Rgb(255, 0, 0), Rgb(0, 255, 0), Rgb(255, 255, 0),
+  Rgb(92, 92, 255), Rgb(255, 0, 255), Rgb(0, 255, 255), Rgb(255, 255, 255)
+];
+
+// The intensity levels of every channel in the 6x6x6 color cube.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn nearest_cube_level(value: u8) -> usize {
+  (0..CUBE_LEVELS.len())
+    .min_by_key(|&i| (CUBE_LEVELS[i] as i32 - value as i32).abs())
+    .unwrap()
+}
+
+// Maps a color to the closest entry of the xterm 256 color palette, picking
+// between the best candidate in the color cube and the best gray.
+fn quantize_256(color: &Rgb) -> u8 {
+  let (r, g, b) = (nearest_cube_level(color.0), nearest_cube_level(color.1), nearest_cube_level(color.2));
+  let cube_index = 16 + 36*r + 6*g + b;
+  let cube_color = Rgb(CUBE_LEVELS[r], CUBE_LEVELS[g], CUBE_LEVELS[b]);
+
+  // Grays 232-255 go from 8 to 238 in steps of 10.
+  let average = (color.0 as u32 + color.1 as u32 + color.2 as u32) / 3;
+  let gray_step = (average.saturating_sub(3) / 10).min(23);
+  let gray_value = (8 + gray_step*10) as u8;
+  let gray_color = Rgb(gray_value, gray_value, gray_value);
+
+  if gray_color.distance(color) < cube_color.distance(color) {
+    232 + gray_step as u8
+  } else {
+    cube_index as u8
+  }
+}
+
+fn quantize_16(color: &Rgb) -> u8 {
+  (0..ANSI16.len())
+    .min_by_key(|&i| ANSI16[i].distance(color))
+    .unwrap() as u8
+}
+
+// Returns the escape sequence that sets the foreground (or background) to
+// color, using the best approximation the terminal is able to show.
+fn color_escape(color: &Rgb, depth: ColorDepth, background: bool) -> String {
+  match depth {
+    ColorDepth::TrueColor => {
+      format!("\x1b[{};2;{};{};{}m", if background { 48 } else { 38 }, color.0, color.1, color.2)
+    },
+    ColorDepth::Ansi256 => {
+      format!("\x1b[{};5;{}m", if background { 48 } else { 38 }, quantize_256(color))
+    },
+    ColorDepth::Ansi16 => {
+      // Normal colors are 30-37 (40-47), bright ones are 90-97 (100-107).
+      let index = quantize_16(color);
+      let base = match (background, index >= 8) {
+        (false, false) => 30,
+        (false, true) => 90,
+        (true, false) => 40,
+        (true, true) => 100
+      };
+      format!("\x1b[{}m", base + (index % 8))
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+  pub colors: [Rgb; PALETTE_SIZE]
+}
+
+// The color schemes c8rs has always shipped with.
+const PRESETS: [(&str, Palette); 3] = [
+  ("black-white", Palette { colors: [Rgb(0, 0, 0), Rgb(255, 255, 255), Rgb(170, 170, 170), Rgb(85, 85, 85)] }),
+  ("orange-yellow", Palette { colors: [Rgb(174, 94, 22), Rgb(253, 195, 45), Rgb(255, 102, 0), Rgb(102, 34, 0)] }),
+  ("black-green", Palette { colors: [Rgb(0, 0, 0), Rgb(0, 205, 0), Rgb(0, 102, 0), Rgb(170, 255, 170)] })
+];
+
+impl Palette {
+  pub fn background(&self) -> Rgb {
+    self.colors[0]
+  }
+
+  pub fn foreground(&self) -> Rgb {
+    self.colors[1]
+  }
+
+  // Builds the escape sequence that selects the background and foreground
+  // colors, so that it only needs to be computed once.
+  pub fn escape(&self, depth: ColorDepth) -> String {
+    format!("{}{}",
+      color_escape(&self.background(), depth, true),
+      color_escape(&self.foreground(), depth, false))
+  }
+
+  pub fn with_overrides(mut self, fg: Option<Rgb>, bg: Option<Rgb>) -> Palette {
+    if let Some(fg) = fg {
+      self.colors[1] = fg;
+    }
+    if let Some(bg) = bg {
+      self.colors[0] = bg;
+    }
+    self
+  }
+
+  // Parses a "#bg #fg [#plane2 #both]" list of colors. When only the two chip8
+  // colors are given, the XO-CHIP ones are taken from the foreground.
+  fn parse_colors(text: &str) -> Result<Palette, String> {
+    let colors = text.split_whitespace()
+      .map(Rgb::parse_hex)
+      .collect::<Result<Vec<Rgb>, String>>()?;
+
+    match colors.len() {
+      2 => Ok(Palette { colors: [colors[0], colors[1], colors[1], colors[1]] }),
+      PALETTE_SIZE => Ok(Palette { colors: [colors[0], colors[1], colors[2], colors[3]] }),
+      n => Err(format!("expected 2 or {} colors, found {}", PALETTE_SIZE, n))
+    }
+  }
+
+  // Looks a palette up by name: first in the user's palette file, then in the
+  // built-in presets.
+  //
+  // The palette file has one palette per line:
+  //   # background foreground plane-2 both-planes
+  //   amber = #1a0f00 #ffb000 #804000 #ffe0a0
+  pub fn lookup(name: &str, palette_file: Option<&Path>) -> Result<Palette, String> {
+    if let Some(path) = palette_file {
+      if let Some(palette) = Palette::from_file(path, name)? {
+        return Ok(palette);
+      }
+    }
+
+    PRESETS.iter()
+      .find(|(preset_name, _)| *preset_name == name)
+      .map(|(_, palette)| palette.clone())
+      .ok_or_else(|| {
+        let names: Vec<&str> = PRESETS.iter().map(|(preset_name, _)| *preset_name).collect();
+        format!("Unknown color scheme '{}'. Built-in schemes are: {}", name, names.join(", "))
+      })
+  }
+
+  fn from_file(path: &Path, name: &str) -> Result<Option<Palette>, String> {
+    let contents = fs::read_to_string(path)
+      .map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+
+    for (line_number, line) in contents.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+
+      let (entry_name, colors) = line.split_once('=')
+        .ok_or_else(|| format!("{}:{}: expected 'name = colors'", path.display(), line_number + 1))?;
+      if entry_name.trim() == name {
+        return Palette::parse_colors(colors)
+          .map(Some)
+          .map_err(|e| format!("{}:{}: {}", path.display(), line_number + 1, e));
+      }
+    }
+
+    Ok(None)
+  }
+
+  // $XDG_CONFIG_HOME/c8rs/palettes, or ~/.config/c8rs/palettes.
+  pub fn default_file() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+      .map(PathBuf::from)
+      .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    let path = config_dir.join("c8rs").join("palettes");
+
+    if path.is_file() { Some(path) } else { None }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn hex_colors_are_parsed() {
+    assert_eq!(Rgb::parse_hex("#ff8000"), Ok(Rgb(255, 128, 0)));
+    assert_eq!(Rgb::parse_hex(" F80 "), Ok(Rgb(255, 136, 0)));
+    for text in ["#12345", "#gg0000", "", "#1é234", "#é1", "#é12345"] {
+      assert!(Rgb::parse_hex(text).is_err(), "{}", text);
+    }
+  }
+
+  #[test]
+  fn colors_are_quantized_to_the_closest_terminal_color() {
+    // Exact cube colors, and grays that are closer to the gray ramp.
+    assert_eq!(quantize_256(&Rgb(0, 0, 0)), 16);
+    assert_eq!(quantize_256(&Rgb(255, 0, 0)), 196);
+    assert_eq!(quantize_256(&Rgb(255, 255, 255)), 231);
+    assert_eq!(quantize_256(&Rgb(128, 128, 128)), 244);
+    assert_eq!(quantize_256(&Rgb(100, 130, 170)), 16 + 36 + 6 * 2 + 3);
+
+    assert_eq!(quantize_16(&Rgb(0, 0, 0)), 0);
+    assert_eq!(quantize_16(&Rgb(200, 10, 10)), 1);
+    assert_eq!(quantize_16(&Rgb(250, 10, 10)), 9);
+    assert_eq!(quantize_16(&Rgb(140, 140, 140)), 8);
+  }
+
+  #[test]
+  fn escapes_match_the_color_depth() {
+    let palette = Palette { colors: [Rgb(1, 2, 3), Rgb(255, 0, 0), Rgb(0, 0, 0), Rgb(0, 0, 0)] };
+    assert_eq!(palette.escape(ColorDepth::TrueColor), "\x1b[48;2;1;2;3m\x1b[38;2;255;0;0m");
+    assert_eq!(palette.escape(ColorDepth::Ansi256), "\x1b[48;5;16m\x1b[38;5;196m");
+    assert_eq!(palette.escape(ColorDepth::Ansi16), "\x1b[40m\x1b[91m");
+  }
+
+  #[test]
+  fn two_colors_fill_the_xo_chip_planes() {
+    let palette = Palette::parse_colors("#000 #fff").unwrap();
+    assert_eq!(palette.colors, [Rgb(0, 0, 0), Rgb(255, 255, 255), Rgb(255, 255, 255), Rgb(255, 255, 255)]);
+    assert!(Palette::parse_colors("#000 #fff #888").is_err());
+    assert!(Palette::lookup("no-such-scheme", None).unwrap_err().contains("black-white"));
+  }
+}
@@ -0,0 +1,42 @@
+use crate::loader::PROGRAM_START;
+
+// Machines chip8 interpreters ran on. They differ in where the interpreter
+// ends and programs begin.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Platform {
+  // The COSMAC VIP, and nearly everything after it: programs start at 0x200.
+  Chip8,
+  // A COSMAC VIP running a hybrid program, one that calls 1802 machine code
+  // of its own with 0nnn.
+  VipHybrid,
+  // The ETI-660 kept its interpreter below 0x600, so that's where its
+  // programs start.
+  Eti660
+}
+
+impl Platform {
+  pub fn load_address(&self) -> u16 {
+    match self {
+      Platform::Chip8 | Platform::VipHybrid => PROGRAM_START as u16,
+      Platform::Eti660 => 0x600
+    }
+  }
+
+  // Whether 0nnn calls machine code, rather than being ignored.
+  pub fn machine_code(&self) -> bool {
+    *self == Platform::VipHybrid
+  }
+}
+
+// Parses a memory address, in hex (0x600) or decimal (1536).
+pub fn parse_address(text: &str) -> Result<u16, String> {
+  let address = match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+    Some(hex) => u16::from_str_radix(hex, 16),
+    None => text.parse()
+  }.map_err(|_| format!("'{}' is not an address", text))?;
+
+  if address > 0xFFF {
+    return Err(format!("0x{:X} is past the end of memory (0xFFF)", address));
+  }
+  Ok(address)
+}
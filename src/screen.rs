@@ -1,89 +1,156 @@
 extern crate termion;
 use crate::{SCREEN_LINES, SCREEN_COLUMNS};
+use crate::dirty::DirtyTracker;
+use crate::framebuffer::Framebuffer;
+use crate::palette::{Palette, ColorDepth};
+use crate::layout::{Layout, Rect, DISPLAY_HEIGHT, DISPLAY_WIDTH};
+use crate::terminal;
 use std::io::{Write, stdout, Stdout, Read, Bytes};
 use termion::{async_stdin, AsyncReader};
 use termion::input::MouseTerminal;
 use termion::raw::{IntoRawMode, RawTerminal};
+use termion::screen::{AlternateScreen, ToAlternateScreen};
 
-#[derive (Debug)]
-enum MyColor {
-  Black,
-  White,
-  Orange,
-  Yellow,
-  Green
+// The sequence MouseTerminal uses to turn mouse reporting on.
+const MOUSE_REPORTING: &str = "\x1b[?1000h\x1b[?1002h\x1b[?1015h\x1b[?1006h";
+
+const KEYMAP: [char; 16] = [
+  'x', '1', '2', '3', 
+  'q', 'w', 'e', 'a', 
+  's', 'd', 'z', 'c', 
+  '4', 'r', 'f', 'v'
+];
+
+// The keys that press each keypad key, for programs that want a different
+// layout than KEYMAP.
+pub type Keymap = [Vec<char>; 16];
+
+// To make pixels look square, I separate every terminal line into two different
+// virtual sub-lines. The first sub-line is the top half of the pixel, and the
+// second sub-line is the bottom half of the pixel.
+//
+// There's no need to check if the bottom half of the pixel should be rendered,
+// a.k.a. we're not outside of the screen, a.k.a. the first sub-line is not the
+// last line, because the chip8 screen is always 32x64! (some implementations
+// actually have more resolution, but I think the number of rows is always a
+// multiple of 2, so it's not a problem)
+fn cell_character(display: &Framebuffer, line: usize, column: usize) -> char {
+  match (display.pixel(line, column), display.pixel(line + 1, column)) {
+    (true, true) => '█',
+    (true, false) => '▀',
+    (false, true) => '▄',
+    (false, false) => ' '
+  }
 }
 
-impl termion::color::Color for MyColor {
-  fn write_fg(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-    match self {
-      MyColor::Black => termion::color::Black.write_fg(f),
-      MyColor::White => termion::color::White.write_fg(f),
-      MyColor::Orange => termion::color::Rgb(174, 94, 22).write_fg(f),
-      MyColor::Yellow => termion::color::Rgb(253, 195, 45).write_fg(f),
-      MyColor::Green => termion::color::Green.write_fg(f),
-    }
+// The runs of consecutive columns set in mask, as (first column, length).
+fn runs(mut mask: u64) -> Vec<(usize, usize)> {
+  let mut runs = Vec::new();
+  while mask != 0 {
+    let start = mask.trailing_zeros() as usize;
+    let length = (mask >> start).trailing_ones() as usize;
+    runs.push((start, length));
+
+    // Clear the bits of the run. It may reach the last column, so the shift
+    // can't be allowed to overflow.
+    let run_mask = u64::MAX.checked_shl(length as u32).map_or(u64::MAX, |m| !m);
+    mask &= !(run_mask << start);
   }
+  runs
+}
+
+// What redraws the cells with a pixel marked in dirty, with the display at
+// area: one string for every terminal line that has any, holding every run of
+// consecutive dirty cells after a single cursor movement.
+fn changed_lines(display: &Framebuffer, dirty: &DirtyTracker, area: Rect, colors: &str) -> Vec<String> {
+  let mut lines = Vec::new();
+  for i in (0..SCREEN_LINES).step_by(2) {
+    // A terminal cell has to be redrawn if either of its halves changed.
+    let mask = dirty.line(i) | dirty.line(i + 1);
+    if mask == 0 {
+      continue;
+    }
 
-  fn write_bg(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-    match self {
-      MyColor::Black => termion::color::Black.write_bg(f),
-      MyColor::White => termion::color::White.write_bg(f),
-      MyColor::Orange => termion::color::Rgb(174, 94, 22).write_bg(f),
-      MyColor::Yellow => termion::color::Rgb(253, 195, 45).write_bg(f),
-      MyColor::Green => termion::color::Green.write_bg(f),
+    let mut line = colors.to_string();
+    for (start, length) in runs(mask) {
+      line += &termion::cursor::Goto(area.x + start as u16, area.y + (i/2) as u16).to_string();
+      for j in start..(start + length) {
+        line.push(cell_character(display, i, j));
+      }
     }
+    lines.push(line);
   }
+  lines
 }
 
-#[derive(clap::ArgEnum, Clone)]
-pub enum ColorScheme {
-  BlackWhite,
-  OrangeYellow,
-  BlackGreen
-}
-impl ColorScheme {
-  fn get_bg(&self) -> termion::color::Bg<MyColor> {
-    termion::color::Bg(match self  {
-      ColorScheme::BlackWhite => MyColor::Black,
-      ColorScheme::OrangeYellow => MyColor::Orange,
-      ColorScheme::BlackGreen => MyColor::Black
-    })
-  }
-  fn get_fg(&self) -> termion::color::Fg<MyColor> {
-    termion::color::Fg(match self {
-      ColorScheme::BlackWhite => MyColor::White,
-      ColorScheme::OrangeYellow => MyColor::Yellow,
-      ColorScheme::BlackGreen => MyColor::Green
-    })
-  }
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hotkey {
+  Quit,
+  ToggleHud,
+  ToggleSidePanel,
+  TogglePause,
+  AdvanceFrame,
+  SoftReset,
+  HardReset,
+  Faster,
+  Slower,
+  NormalSpeed,
+  ToggleUncapped
 }
 
-const KEYMAP: [char; 16] = [
-  'x', '1', '2', '3', 
-  'q', 'w', 'e', 'a', 
-  's', 'd', 'z', 'c', 
-  '4', 'r', 'f', 'v'
+// Keys outside of the chip8 keypad that control the emulator itself. Any other
+// key quits.
+const HOTKEYS: [(char, Hotkey); 11] = [
+  ('h', Hotkey::ToggleHud),
+  ('i', Hotkey::ToggleSidePanel),
+  ('p', Hotkey::TogglePause),
+  ('n', Hotkey::AdvanceFrame),
+  ('o', Hotkey::SoftReset),
+  ('O', Hotkey::HardReset),
+  ('+', Hotkey::Faster),
+  ('=', Hotkey::Faster),
+  ('-', Hotkey::Slower),
+  ('0', Hotkey::NormalSpeed),
+  ('u', Hotkey::ToggleUncapped)
 ];
 
 pub struct Screen {
-  stdout: MouseTerminal<RawTerminal<Stdout>>,
+  stdout: AlternateScreen<MouseTerminal<RawTerminal<Stdout>>>,
   stdin: Bytes<AsyncReader>,
   pub pressed_keys: [bool; 16],
+  keymap: Keymap,
   
   previous_screen_size: (usize, usize),
-  color_scheme: ColorScheme
+  // Escape sequence that selects the palette's background and foreground.
+  colors: String,
+
+  layout: Option<Layout>,
+  show_status: bool,
+  show_side_panel: bool,
+  // What the status bar and the side panel show, kept to redraw them.
+  status_text: String,
+  side_panel_lines: Vec<String>
 }
 
 impl Screen {
-  pub fn new(color_scheme: ColorScheme) -> Screen {
+  // AsyncReader only hands out whatever is already buffered, so reading it byte
+  // by byte is fine.
+  #[allow(clippy::unbuffered_bytes)]
+  pub fn new(palette: &Palette) -> Screen {
     Screen {
-      stdout: MouseTerminal::from(stdout().into_raw_mode().unwrap()),
+      stdout: AlternateScreen::from(MouseTerminal::from(stdout().into_raw_mode().unwrap())),
       stdin: async_stdin().bytes(),
       pressed_keys: [false; 16],
+      keymap: KEYMAP.map(|key| vec![key]),
       
       previous_screen_size: (0,0),
-      color_scheme,
+      colors: palette.escape(ColorDepth::detect()),
+
+      layout: None,
+      show_status: false,
+      show_side_panel: false,
+      status_text: String::new(),
+      side_panel_lines: Vec::new()
     }
   }
 
@@ -91,36 +158,63 @@ impl Screen {
     write!(self.stdout, "{}{}{}", termion::clear::All, termion::cursor::Hide, termion::cursor::Goto(1,1)).unwrap();
   }
 
-  // Updates pressed_keys and returns true if the pressed key means exit.
+  // Hands the terminal back to the shell, before the process is stopped.
+  pub fn suspend(&mut self) {
+    terminal::restore_terminal();
+  }
+
+  // Takes the terminal back after we've been stopped, and makes sure the next
+  // write redraws the whole display.
+  pub fn resume(&mut self) {
+    self.stdout.activate_raw_mode().unwrap();
+    write!(self.stdout, "{}{}", ToAlternateScreen, MOUSE_REPORTING).unwrap();
+    self.setup();
+    self.previous_screen_size = (0, 0);
+  }
+
+  // Keypad keys the keymap leaves without a key keep the one in KEYMAP.
+  pub fn set_keymap(&mut self, keymap: &Keymap) {
+    for (index, keys) in keymap.iter().enumerate() {
+      self.keymap[index] = if keys.is_empty() { vec![KEYMAP[index]] } else { keys.clone() };
+    }
+  }
+
+  // Updates pressed_keys and returns the hotkeys that were pressed.
   // NOTE: Pressing a key currently presses the virtual key during 1 frame (I
   // think this is a terminal limitation), and pressing any key outside of the 
-  // mapped keyboard exits the program.
-  pub fn update_keys(&mut self) -> bool {
+  // mapped keyboard and the hotkeys exits the program.
+  pub fn update_keys(&mut self) -> Vec<Hotkey> {
     self.pressed_keys = [false; 16];
+    let mut hotkeys = Vec::new();
     loop {
       let bopt = self.stdin.next();
       match bopt {
-        Some(Ok(b)) => match KEYMAP.iter().position(|&x| x == (b as char)) {
+        Some(Ok(b)) => match self.keymap.iter().position(|keys| keys.contains(&(b as char))) {
           Some(index) => self.pressed_keys[index] = true,
-          None => return true
+          None => match HOTKEYS.iter().find(|(key, _)| *key == (b as char)) {
+            Some((_, hotkey)) => hotkeys.push(*hotkey),
+            None => {
+              hotkeys.push(Hotkey::Quit);
+              break;
+            }
+          }
         },
         Some(Err(_)) => {},
         None => break
       }
     }
 
-    // Debug
-    //eprint!("\x1b[{};{}H[screen] {:?}", 22, 0, self.pressed_keys);
-    false
+    hotkeys
   }
 
-  pub fn require_screen_size(&mut self, expected_lines: usize, expected_columns: usize) {
-    while !self.is_correct_screen_size(expected_lines/2, expected_columns) {
+  // Loops until the terminal is big enough for the display.
+  pub fn require_screen_size(&mut self) {
+    while self.compute_layout().is_none() {
       let (lines, columns) = self.get_screen_size();
   
-      write!(self.stdout, "{}{}Expected at least {}x{} screen, current screen is {}x{}\n",
+      writeln!(self.stdout, "{}{}Expected at least {}x{} screen, current screen is {}x{}",
         termion::clear::All, termion::cursor::Goto(1,1),
-        expected_lines/2, expected_columns, lines, columns).unwrap();
+        DISPLAY_HEIGHT, DISPLAY_WIDTH, lines, columns).unwrap();
     }
   }
 
@@ -130,107 +224,141 @@ impl Screen {
     (lines as usize, columns as usize)
   }
 
-  pub fn write(&mut self, prev: &[[bool; SCREEN_COLUMNS]; SCREEN_LINES], display: &[[bool; SCREEN_COLUMNS]; SCREEN_LINES]) {
+  fn compute_layout(&mut self) -> Option<Layout> {
+    let (lines, columns) = self.get_screen_size();
+    Layout::compute(lines as u16, columns as u16, self.show_status, self.show_side_panel)
+  }
+
+  // Showing or hiding parts of the interface moves everything else around, so
+  // the next write redraws it all.
+  pub fn set_status_visible(&mut self, visible: bool) {
+    self.show_status = visible;
+    self.previous_screen_size = (0, 0);
+  }
+
+  pub fn set_side_panel_visible(&mut self, visible: bool) {
+    self.show_side_panel = visible;
+    self.previous_screen_size = (0, 0);
+  }
+
+  pub fn write(&mut self, display: &Framebuffer, dirty: &DirtyTracker) {
     let curr_screen_size = self.get_screen_size();
     if self.previous_screen_size != curr_screen_size {
-      self.write_array(display)      
+      self.layout = self.compute_layout();
+      self.write_array(display);
     } else {
-      self.write_changes(prev, display);
+      self.write_changes(display, dirty);
     }
 
     self.previous_screen_size = curr_screen_size;
   }
 
-  pub fn write_array(&mut self, display: &[[bool; SCREEN_COLUMNS]; SCREEN_LINES]) {
-    write!(self.stdout, "{}{}", termion::clear::All, termion::cursor::Goto(1,1)).unwrap();
+  // Redraws everything: the display, the status bar and the side panel.
+  pub fn write_array(&mut self, display: &Framebuffer) {
+    write!(self.stdout, "{}{}", termion::style::Reset, termion::clear::All).unwrap();
 
+    let area = match self.layout {
+      Some(layout) => layout.display,
+      None => return
+    };
     for i in (0..SCREEN_LINES).step_by(2) {
+      let mut row = format!("{}{}", termion::cursor::Goto(area.x, area.y + (i/2) as u16), self.colors);
       for j in 0..SCREEN_COLUMNS {
-        // To make pixels look square, I separate every line into two different
-        // virtual sub-lines. The first sub-line is the top half of the pixel,
-        // and the second sub-line is the bottom half of the pixel.
-
-        // There's no need to check if the bottom half of the pixel should be 
-        // rendered, a.k.a. we're not outside of the screen, a.k.a. the first 
-        // sub-line is not the last line, because the chip8 screen is always
-        // 32x64! (some implementations actually have more resolution, but I
-        // think the number of rows is always a multiple of 2,
-        // so it's not a problem)
-
-        let first_pixel = display[i][j];
-        let second_pixel = display[i + 1][j];
-        let character = match (first_pixel, second_pixel) {
-          (true, true) => "█",
-          (true, false) => "▀",
-          (false, true) => "▄",
-          (false, false) => " "
-        };
-
-        write!(self.stdout, "{}{}{}{}",
-          termion::cursor::Goto((j+1) as u16, (i/2+1) as u16),
-          self.color_scheme.get_bg(),
-          self.color_scheme.get_fg(),
-          character).unwrap();
+        row.push(cell_character(display, i, j));
       }
+
+      write!(self.stdout, "{}", row).unwrap();
     }
-    //write!(self.stdout, "{}{}",
-    //  termion::cursor::Goto((SCREEN_COLUMNS+1) as u16, (SCREEN_LINES+1) as u16),
-    //  termion::color::Bg(termion::color::LightBlack)).unwrap();
+
+    let status_text = std::mem::take(&mut self.status_text);
+    self.write_status(&status_text);
+    let side_panel_lines = std::mem::take(&mut self.side_panel_lines);
+    self.write_side_panel(&side_panel_lines);
+
     self.stdout.flush().unwrap();
   }
 
-  pub fn write_changes(&mut self, prev: &[[bool; SCREEN_COLUMNS]; SCREEN_LINES], display: &[[bool; SCREEN_COLUMNS]; SCREEN_LINES]) {
-    let mut has_printed = false;
+  // Only redraws the cells that contain a pixel marked in dirty (see
+  // changed_lines), so that redrawing a sprite takes a handful of bytes
+  // instead of a full screen.
+  pub fn write_changes(&mut self, display: &Framebuffer, dirty: &DirtyTracker) {
+    let area = match self.layout {
+      Some(layout) => layout.display,
+      None => return
+    };
 
-    for i in (0..SCREEN_LINES).step_by(2) {
-      for j in 0..SCREEN_COLUMNS {
-        // To make pixels look square, I separate every line into two different
-        // virtual sub-lines. The first sub-line is the top half of the pixel,
-        // and the second sub-line is the bottom half of the pixel.
-
-        // There's no need to check if the bottom half of the pixel should be 
-        // rendered, a.k.a. we're not outside of the screen, a.k.a. the first 
-        // sub-line is not the last line, because the chip8 screen is always
-        // 32x64! (some implementations actually have more resolution, but I
-        // think the number of rows is always a multiple of 2,
-        // so it's not a problem)
-
-        if prev[i][j] != display[i][j] || prev[i+1][j] == display[i+1][j]
-        {
-          let first_pixel = display[i][j];
-          let second_pixel = display[i + 1][j];
-          let character = match (first_pixel, second_pixel) {
-            (true, true) => "█",
-            (true, false) => "▀",
-            (false, true) => "▄",
-            (false, false) => " "
-          };
-
-          write!(self.stdout, "{}{}{}{}",
-            termion::cursor::Goto((j+1) as u16, (i/2+1) as u16),
-            self.color_scheme.get_bg(),
-            self.color_scheme.get_fg(),
-            character).unwrap();
-
-          has_printed = true;
-        }
-      }
+    let lines = changed_lines(display, dirty, area, &self.colors);
+    for line in lines.iter() {
+      write!(self.stdout, "{}", line).unwrap();
     }
+    if !lines.is_empty() {
+      self.stdout.flush().unwrap();
+    }
+  }
+
+  // Writes text on the status bar, if it's shown and fits.
+  pub fn write_status(&mut self, text: &str) {
+    self.status_text = text.to_string();
 
-    if has_printed {
+    if let Some(area) = self.layout.and_then(|layout| layout.status) {
+      let text: String = text.chars().take(area.width as usize).collect();
+      write!(self.stdout, "{}{}{}{}",
+        termion::cursor::Goto(area.x, area.y), termion::style::Reset,
+        termion::clear::CurrentLine, text).unwrap();
       self.stdout.flush().unwrap();
     }
   }
 
+  // Writes lines of text on the side panel, if it's shown and fits.
+  pub fn write_side_panel(&mut self, lines: &[String]) {
+    self.side_panel_lines = lines.to_vec();
 
-  pub fn is_correct_screen_size(&mut self, expected_lines: usize, expected_columns: usize) -> bool {
-    let (lines, columns) = self.get_screen_size();
+    if let Some(area) = self.layout.and_then(|layout| layout.side_panel) {
+      let mut text = termion::style::Reset.to_string();
+      for row in 0..area.height {
+        let line = lines.get(row as usize).map(String::as_str).unwrap_or("");
+        text += &format!("{}{:<width$.width$}",
+          termion::cursor::Goto(area.x, area.y + row), line, width = area.width as usize);
+      }
 
-    lines>=expected_lines && columns>=expected_columns
+      write!(self.stdout, "{}", text).unwrap();
+      self.stdout.flush().unwrap();
+    }
   }
+}
 
-  pub fn beep(&mut self) {
-    write!(self.stdout, "\x07").unwrap();
-    self.stdout.flush().unwrap();
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn dirty_columns_are_split_into_runs() {
+    assert_eq!(runs(0), []);
+    assert_eq!(runs(0b1110_0111), [(0, 3), (5, 3)]);
+    assert_eq!(runs(1 << 63 | 1 << 62 | 1 << 10), [(10, 1), (62, 2)]);
+    assert_eq!(runs(u64::MAX), [(0, 64)]);
+  }
+
+  #[test]
+  fn only_lines_with_changes_are_written() {
+    let mut display = Framebuffer::new();
+    display.set_pixel(0, 2, true);
+    display.set_pixel(1, 3, true);
+    display.set_pixel(7, 40, true);
+
+    // Both halves of the first line of cells, and a pixel further down.
+    let mut dirty = DirtyTracker::new();
+    dirty.mark_line(0, 0b0100);
+    dirty.mark_line(1, 0b1000);
+    dirty.mark(1, 10);
+    dirty.mark(7, 40);
+    dirty.mark(7, 41);
+
+    let area = Rect { x: 3, y: 2, width: DISPLAY_WIDTH, height: DISPLAY_HEIGHT };
+    let goto = |x: u16, y: u16| termion::cursor::Goto(x, y).to_string();
+    assert_eq!(changed_lines(&display, &dirty, area, "C"), [
+      format!("C{}▀▄{} ", goto(5, 2), goto(13, 2)),
+      format!("C{}▄ ", goto(43, 5))
+    ]);
   }
 }
@@ -0,0 +1,121 @@
+use std::time::{Duration, Instant};
+
+use crate::FRAME_HZ;
+
+// How often the rates shown in the status line are recomputed.
+const WINDOW: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Copy, Default)]
+struct Counters {
+  instructions: u64,
+  frames: u64,
+  timer_ticks: u64
+}
+
+// Counts what the emulator has been doing, both since it started and over the
+// last WINDOW, to show effective speeds instead of the configured ones.
+pub struct Stats {
+  started: Instant,
+  total: Counters,
+  // Frames that should have been shown by now, given how long we've been
+  // running and at what speed.
+  expected_frames: f64,
+  last_clock: Instant,
+
+  window_started: Instant,
+  window_start: Counters,
+  instructions_per_second: f64,
+  frames_per_second: f64,
+  timer_hz: f64
+}
+
+impl Default for Stats {
+  fn default() -> Stats {
+    Stats::new()
+  }
+}
+
+fn per_second(count: u64, elapsed: Duration) -> f64 {
+  count as f64 / elapsed.as_secs_f64()
+}
+
+impl Stats {
+  pub fn new() -> Stats {
+    let now = Instant::now();
+    Stats {
+      started: now,
+      total: Counters::default(),
+      expected_frames: 0.0,
+      last_clock: now,
+
+      window_started: now,
+      window_start: Counters::default(),
+      instructions_per_second: 0.0,
+      frames_per_second: 0.0,
+      timer_hz: 0.0
+    }
+  }
+
+  pub fn count_instructions(&mut self, count: u64) {
+    self.total.instructions += count;
+  }
+
+  pub fn count_frame(&mut self) {
+    self.total.frames += 1;
+  }
+
+  pub fn count_timer_tick(&mut self) {
+    self.total.timer_ticks += 1;
+  }
+
+  // Accounts for the real time since the last call. speed is how fast the
+  // emulator is meant to be going (Control::speed_factor); time spent paused
+  // or uncapped doesn't count towards the frames we expected to see.
+  pub fn advance_clock(&mut self, speed: Option<f64>) {
+    let now = Instant::now();
+    match speed {
+      Some(speed) => self.expected_frames += (now - self.last_clock).as_secs_f64() * FRAME_HZ as f64 * speed,
+      // Running uncapped gets us ahead of the expectation, which would then
+      // hide the frames we drop afterwards.
+      None => self.expected_frames = self.expected_frames.max(self.total.frames as f64)
+    }
+    self.last_clock = now;
+  }
+
+  // Recomputes the rates once every WINDOW. Returns true when they changed.
+  pub fn update(&mut self) -> bool {
+    let elapsed = self.window_started.elapsed();
+    if elapsed < WINDOW {
+      return false;
+    }
+
+    self.instructions_per_second = per_second(self.total.instructions - self.window_start.instructions, elapsed);
+    self.frames_per_second = per_second(self.total.frames - self.window_start.frames, elapsed);
+    self.timer_hz = per_second(self.total.timer_ticks - self.window_start.timer_ticks, elapsed);
+
+    self.window_started = Instant::now();
+    self.window_start = self.total;
+    true
+  }
+
+  // Frames that should have been shown by now but weren't, because the
+  // emulator couldn't keep up.
+  pub fn dropped_frames(&self) -> u64 {
+    (self.expected_frames as u64).saturating_sub(self.total.frames)
+  }
+
+  pub fn status_line(&self, rom_name: &str, quirk_profile: &str, mode: &str) -> String {
+    format!("{} | {} | {:.0} ips | {:.1} fps | {} dropped | timers {:.0}Hz | quirks: {}",
+      rom_name, mode, self.instructions_per_second, self.frames_per_second,
+      self.dropped_frames(), self.timer_hz, quirk_profile)
+  }
+
+  pub fn summary(&self, rom_name: &str) -> String {
+    let elapsed = self.started.elapsed();
+    format!("{}: ran for {:.1}s\n  {} instructions ({:.0}/s)\n  {} frames ({:.1}/s), {} dropped\n  {} timer ticks ({:.0}Hz)",
+      rom_name, elapsed.as_secs_f64(),
+      self.total.instructions, per_second(self.total.instructions, elapsed),
+      self.total.frames, per_second(self.total.frames, elapsed), self.dropped_frames(),
+      self.total.timer_ticks, per_second(self.total.timer_ticks, elapsed))
+  }
+}
@@ -0,0 +1,123 @@
+use std::io::{self, Write, stdout};
+use std::panic;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use signal_hook::consts::{SIGCONT, SIGINT, SIGTERM, SIGTSTP};
+
+// Undoes everything Screen does to the terminal: mouse reporting, the
+// alternate screen, the hidden cursor and the colors.
+const RESTORE_SEQUENCE: &str = "\x1b[?1006l\x1b[?1015l\x1b[?1002l\x1b[?1000l\x1b[?1049l\x1b[?25h\x1b[0m";
+
+// Terminal settings from before c8rs put it into raw mode.
+static ORIGINAL_TERMIOS: OnceLock<libc::termios> = OnceLock::new();
+
+// PC and opcode of the instruction being executed, packed as (pc << 16) |
+// opcode, so that a panic can tell where the chip8 program was.
+static CURRENT_INSTRUCTION: AtomicU32 = AtomicU32::new(0);
+
+pub fn note_instruction(pc: u16, opcode: u16) {
+  CURRENT_INSTRUCTION.store((pc as u32) << 16 | opcode as u32, Ordering::Relaxed);
+}
+
+// Puts the terminal back in cooked mode and undoes our escape sequences. It's
+// safe to call any number of times, from anywhere.
+pub fn restore_terminal() {
+  let mut stdout = stdout();
+  let _ = write!(stdout, "{}", RESTORE_SEQUENCE);
+  let _ = stdout.flush();
+
+  if let Some(termios) = ORIGINAL_TERMIOS.get() {
+    unsafe {
+      libc::tcsetattr(libc::STDOUT_FILENO, libc::TCSANOW, termios);
+    }
+  }
+}
+
+// Restores the terminal when dropped, which covers returning from main and
+// unwinding after a panic. Creating it also saves the terminal settings and
+// installs a panic hook that restores the terminal before the panic message
+// is printed, so that the message is readable.
+pub struct TerminalGuard;
+
+impl TerminalGuard {
+  pub fn install() -> TerminalGuard {
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(libc::STDOUT_FILENO, &mut termios) } == 0 {
+      let _ = ORIGINAL_TERMIOS.set(termios);
+    }
+
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+      restore_terminal();
+
+      let instruction = CURRENT_INSTRUCTION.load(Ordering::Relaxed);
+      eprintln!("\nc8rs crashed while executing {:04X} at PC {:03X}.",
+        instruction & 0xffff, instruction >> 16);
+      default_hook(info);
+    }));
+
+    TerminalGuard
+  }
+}
+
+impl Drop for TerminalGuard {
+  fn drop(&mut self) {
+    restore_terminal();
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Signal {
+  // SIGINT or SIGTERM.
+  Quit,
+  // SIGTSTP (^Z from another terminal, or kill -TSTP).
+  Suspend,
+  // SIGCONT, after we've been stopped.
+  Resume
+}
+
+// Catches the signals that would otherwise leave the terminal in raw mode, so
+// that the main loop can deal with them.
+pub struct Signals {
+  quit: Arc<AtomicBool>,
+  suspend: Arc<AtomicBool>,
+  resume: Arc<AtomicBool>
+}
+
+impl Signals {
+  pub fn register() -> io::Result<Signals> {
+    let signals = Signals {
+      quit: Arc::new(AtomicBool::new(false)),
+      suspend: Arc::new(AtomicBool::new(false)),
+      resume: Arc::new(AtomicBool::new(false))
+    };
+
+    signal_hook::flag::register(SIGINT, Arc::clone(&signals.quit))?;
+    signal_hook::flag::register(SIGTERM, Arc::clone(&signals.quit))?;
+    signal_hook::flag::register(SIGTSTP, Arc::clone(&signals.suspend))?;
+    signal_hook::flag::register(SIGCONT, Arc::clone(&signals.resume))?;
+
+    Ok(signals)
+  }
+
+  // Returns the most important signal received since the last call.
+  pub fn take(&self) -> Option<Signal> {
+    if self.quit.swap(false, Ordering::Relaxed) {
+      Some(Signal::Quit)
+    } else if self.suspend.swap(false, Ordering::Relaxed) {
+      Some(Signal::Suspend)
+    } else if self.resume.swap(false, Ordering::Relaxed) {
+      Some(Signal::Resume)
+    } else {
+      None
+    }
+  }
+}
+
+// Stops the process the way SIGTSTP would have, if we hadn't caught it. This
+// only returns once we get a SIGCONT.
+pub fn stop() {
+  let _ = signal_hook::low_level::emulate_default_handler(SIGTSTP);
+}
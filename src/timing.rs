@@ -0,0 +1,177 @@
+use crate::{FRAME_HZ, TIMER_HZ};
+
+// How long instructions take.
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Timing {
+  // Every instruction takes the same time, and the speed is a number of
+  // instructions per second.
+  Fixed,
+  // Instructions take as long as they did on the COSMAC VIP, in 1802 machine
+  // cycles, and the 60 Hz interrupt takes its share of every frame.
+  Vip
+}
+
+// The VIP's 1802 ran at 1.76064 MHz, and a machine cycle is 8 clock cycles.
+pub const VIP_CYCLE_HZ: u32 = 1_760_640 / 8;
+// 3668 machine cycles per frame, at 60 Hz.
+const FRAME_CYCLES: u32 = VIP_CYCLE_HZ / 60;
+// Cycles of every frame the interpreter doesn't get: the CDP1861 takes 8 DMA
+// cycles for each of the 128 lines it shows, and the interrupt routine that
+// sets it up (and counts the timers down) takes the rest.
+const DISPLAY_DMA_CYCLES: u32 = 8 * 128;
+const INTERRUPT_CYCLES: u32 = 46;
+pub const STOLEN_CYCLES: u32 = DISPLAY_DMA_CYCLES + INTERRUPT_CYCLES;
+// What's left for the interpreter.
+const RUN_CYCLES: u32 = FRAME_CYCLES - STOLEN_CYCLES;
+
+// Fetching and decoding an instruction, before it does anything.
+const FETCH_CYCLES: u32 = 40;
+
+// Where the VIP is in the current frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VipClock {
+  cycles: u32 // Run since the last interrupt
+}
+
+impl VipClock {
+  // Lets the interpreter run for cycles, and returns how many times the
+  // interrupt came in the meantime.
+  pub fn run(&mut self, cycles: u32) -> u32 {
+    self.cycles += cycles;
+    let interrupts = self.cycles / RUN_CYCLES;
+    self.cycles %= RUN_CYCLES;
+    interrupts
+  }
+
+  // Cycles the interpreter has left until the next interrupt.
+  pub fn until_interrupt(&self) -> u32 {
+    RUN_CYCLES - self.cycles
+  }
+}
+
+// Frames and timer ticks with fixed timing, at a rate of instructions per
+// second. A frame ends every rate/FRAME_HZ instructions, and the timers tick
+// every rate/TIMER_HZ instructions. The clocks accumulate FRAME_HZ and
+// TIMER_HZ per instruction, so that non-integer ratios don't drift.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FixedClock {
+  frame: u64,
+  timer: u64
+}
+
+impl FixedClock {
+  // Lets count instructions run, and returns how many frames ended and how
+  // many times the timers ticked in the meantime.
+  pub fn run(&mut self, count: u64, rate: u64) -> (u64, u64) {
+    self.frame += FRAME_HZ * count;
+    self.timer += TIMER_HZ * count;
+    let frames = self.frame / rate;
+    let ticks = self.timer / rate;
+    self.frame %= rate;
+    self.timer %= rate;
+    (frames, ticks)
+  }
+}
+
+// Machine cycles instruction takes on the VIP, given the registers it ran
+// with and whether it skipped the next instruction. Dxyn doesn't count the
+// wait for the interrupt before it draws, and 0nnn doesn't count the machine
+// code it calls.
+pub fn vip_cycles(instruction: u16, v: &[u8; 0x10], skipped: bool) -> u32 {
+  let x = ((instruction & 0x0f00) >> 8) as usize;
+  let n = (instruction & 0x000f) as u32;
+  let skip = if skipped { 4 } else { 0 };
+
+  let execute = match instruction & 0xf000 {
+    0x0000 => match instruction {
+      0x00E0 => 3078,             // Clears the 256 bytes of the display
+      0x00EE => 10,
+      _ => 8
+    },
+    0x1000 => 12,
+    0x2000 => 26,
+    0x3000 | 0x4000 => 10 + skip,
+    0x5000 | 0x9000 => 14 + skip,
+    0x6000 => 6,
+    0x7000 => 10,
+    0x8000 => if n == 0 { 12 } else { 44 },
+    0xA000 => 12,
+    0xB000 => {
+      // Crossing into another page takes a little longer.
+      let base = instruction & 0x0fff;
+      let target = base.wrapping_add(v[0] as u16);
+      if target >> 8 != base >> 8 { 24 } else { 22 }
+    },
+    0xC000 => 36,
+    0xD000 => {
+      // Each row is shifted into place one bit at a time.
+      let shift = v[x] as u32 % 8;
+      26 + n * (46 + 20 * shift)
+    },
+    0xE000 => 14 + skip,
+    _ => match instruction & 0x00ff {
+      0x0A => 8,                  // Per check for a key
+      0x1E => 16,
+      0x29 => 16,
+      0x33 => {
+        // The digits are worked out by repeated subtraction.
+        let value = v[x] as u32;
+        80 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+      },
+      0x55 | 0x65 => 14 + 14 * (x as u32 + 1),
+      _ => 10
+    }
+  };
+  FETCH_CYCLES + execute
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn the_interrupt_comes_once_per_frame() {
+    let mut clock = VipClock::default();
+    assert_eq!(clock.run(RUN_CYCLES - 10), 0);
+    assert_eq!(clock.until_interrupt(), 10);
+    assert_eq!(clock.run(10 + 2 * RUN_CYCLES + 5), 3);
+    assert_eq!(clock.until_interrupt(), RUN_CYCLES - 5);
+  }
+
+  #[test]
+  fn fixed_clocks_keep_the_remainder() {
+    // 700 instructions per second: a frame every 11.67 instructions, and a
+    // timer tick every 1.4.
+    let mut clock = FixedClock::default();
+    assert_eq!(clock.run(11, 700), (0, 7));
+    assert_eq!(clock.run(1, 700), (1, 1));
+
+    // A second's worth, one at a time, comes out exact.
+    let mut clock = FixedClock::default();
+    let (mut frames, mut ticks) = (0, 0);
+    for _ in 0..700 {
+      let (ended, ticked) = clock.run(1, 700);
+      frames += ended;
+      ticks += ticked;
+    }
+    assert_eq!((frames, ticks), (FRAME_HZ, TIMER_HZ));
+    assert_eq!(clock, FixedClock::default());
+    assert_eq!(clock.run(3 * 700, 700), (3 * FRAME_HZ, 3 * TIMER_HZ));
+  }
+
+  #[test]
+  fn costs_depend_on_what_instructions_do() {
+    let mut v = [0; 0x10];
+    assert_eq!(vip_cycles(0x3012, &v, true), vip_cycles(0x3012, &v, false) + 4);
+
+    // Sprites cost more the taller they are, and the further off a byte
+    // boundary they're drawn.
+    assert!(vip_cycles(0xD01F, &v, false) > vip_cycles(0xD011, &v, false));
+    let aligned = vip_cycles(0xD015, &v, false);
+    v[0] = 3;
+    assert!(vip_cycles(0xD015, &v, false) > aligned);
+
+    v[1] = 199;
+    assert_eq!(vip_cycles(0xF133, &v, false), FETCH_CYCLES + 80 + 16 * 19);
+  }
+}
@@ -0,0 +1,184 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::cartridge::CartridgeOptions;
+use crate::loader::{self, Rom};
+
+// How often the ROM file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// What identifies a version of the file: its modification time and size.
+type Stamp = (SystemTime, u64);
+
+fn stamp(path: &Path) -> Option<Stamp> {
+  let metadata = fs::metadata(path).ok()?;
+  Some((metadata.modified().ok()?, metadata.len()))
+}
+
+// A new version of the ROM.
+pub struct RomChange {
+  pub rom: Rom,
+  // Whether the new ROM is the same size as the old one, and (for Octo
+  // cartridges) wants to run the same way. It goes at the same address, so
+  // the program can be swapped in under a running machine, keeping its
+  // registers, timers and display.
+  pub compatible: bool
+}
+
+// Polls a ROM file for changes, like the ones an assembler makes on every
+// build.
+pub struct RomWatcher {
+  path: PathBuf,
+  entry: Option<String>,
+  load_address: usize,
+  last_poll: Instant,
+  // Version of the file that is loaded right now.
+  loaded: Option<Stamp>,
+  // Size and options of the ROM that is loaded right now.
+  loaded_len: usize,
+  loaded_options: Option<CartridgeOptions>,
+  // A new version seen in the last poll. Assemblers don't write files in one
+  // go, so it's only loaded once it stays the same for a whole poll.
+  pending: Option<Stamp>
+}
+
+impl RomWatcher {
+  // rom is the ROM that was loaded from path (and entry, for archives), to
+  // go at load_address.
+  pub fn new(path: &Path, entry: Option<&str>, load_address: usize, rom: &Rom) -> RomWatcher {
+    RomWatcher {
+      path: path.to_path_buf(),
+      entry: entry.map(String::from),
+      load_address,
+      last_poll: Instant::now(),
+      loaded: stamp(path),
+      loaded_len: rom.bytes.len(),
+      loaded_options: rom.options.clone(),
+      pending: None
+    }
+  }
+
+  // Returns the new ROM if the file changed (and settled) since the last time.
+  // It's cheap to call this all the time: the file is only looked at every
+  // POLL_INTERVAL.
+  pub fn poll(&mut self) -> Option<RomChange> {
+    if self.last_poll.elapsed() < POLL_INTERVAL {
+      return None;
+    }
+    self.last_poll = Instant::now();
+
+    // While the file is missing (e.g. while it's being replaced) there's
+    // nothing to load.
+    let current = stamp(&self.path)?;
+    if Some(current) == self.loaded {
+      self.pending = None;
+      return None;
+    }
+    if self.pending != Some(current) {
+      self.pending = Some(current);
+      return None;
+    }
+
+    // A ROM that doesn't load (say, it's too big) is skipped until the next
+    // change.
+    self.loaded = Some(current);
+    self.pending = None;
+    let rom = loader::load(&self.path.to_string_lossy(), self.entry.as_deref(), self.load_address).ok()?;
+
+    let compatible = rom.bytes.len() == self.loaded_len && rom.options == self.loaded_options;
+    self.loaded_len = rom.bytes.len();
+    self.loaded_options = rom.options.clone();
+    Some(RomChange { rom, compatible })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs::File;
+
+  struct RomFile {
+    path: PathBuf
+  }
+
+  impl RomFile {
+    fn new(name: &str, bytes: &[u8]) -> RomFile {
+      let path = std::env::temp_dir().join(format!("c8rs-watch-{}-{}.ch8", std::process::id(), name));
+      let file = RomFile { path };
+      file.write(bytes, SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000));
+      file
+    }
+
+    // Writes bytes, and gives the file a modification time of its own, which
+    // writing alone doesn't guarantee on every file system.
+    fn write(&self, bytes: &[u8], modified: SystemTime) {
+      fs::write(&self.path, bytes).unwrap();
+      File::options().write(true).open(&self.path).unwrap().set_modified(modified).unwrap();
+    }
+
+    fn watch(&self) -> RomWatcher {
+      let rom = loader::load(self.path.to_str().unwrap(), None, 0x200).unwrap();
+      RomWatcher::new(&self.path, None, 0x200, &rom)
+    }
+  }
+
+  impl Drop for RomFile {
+    fn drop(&mut self) {
+      let _ = fs::remove_file(&self.path);
+    }
+  }
+
+  // Polls as if POLL_INTERVAL had gone by since the last time.
+  fn poll(watcher: &mut RomWatcher) -> Option<RomChange> {
+    watcher.last_poll = Instant::now().checked_sub(POLL_INTERVAL).unwrap();
+    watcher.poll()
+  }
+
+  fn later(seconds: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000 + seconds)
+  }
+
+  #[test]
+  fn changes_are_loaded_once_they_settle() {
+    let file = RomFile::new("settle", &[0x12, 0x00]);
+    let mut watcher = file.watch();
+    assert!(poll(&mut watcher).is_none());
+
+    // The first poll to see it only takes note, in case it's still being
+    // written.
+    file.write(&[0x13, 0x00], later(1));
+    assert!(poll(&mut watcher).is_none());
+    let change = poll(&mut watcher).unwrap();
+    assert_eq!(change.rom.bytes, [0x13, 0x00]);
+    assert!(poll(&mut watcher).is_none());
+  }
+
+  #[test]
+  fn files_with_the_same_stamp_are_left_alone() {
+    let file = RomFile::new("stamp", &[0x12, 0x00]);
+    let mut watcher = file.watch();
+    file.write(&[0x13, 0x00], later(0));
+    assert!(poll(&mut watcher).is_none());
+    assert!(poll(&mut watcher).is_none());
+  }
+
+  #[test]
+  fn roms_of_the_same_size_are_compatible() {
+    let file = RomFile::new("compatible", &[0x12, 0x00]);
+    let mut watcher = file.watch();
+
+    file.write(&[0x13, 0x00], later(1));
+    poll(&mut watcher);
+    assert!(poll(&mut watcher).unwrap().compatible);
+
+    file.write(&[0x13, 0x00, 0x00, 0xE0], later(2));
+    poll(&mut watcher);
+    assert!(!poll(&mut watcher).unwrap().compatible);
+
+    // Compared with the last one loaded, not the first.
+    file.write(&[0x12, 0x02, 0x00, 0xE0], later(3));
+    poll(&mut watcher);
+    assert!(poll(&mut watcher).unwrap().compatible);
+  }
+}
@@ -0,0 +1,138 @@
+// Golden image tests: ROMs run for a number of frames, with keys pressed
+// and released along the way, and their display has to end up exactly like
+// the text image in tests/golden. Run them with C8RS_BLESS=1 to write the
+// images again after a change that's meant to alter them.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use c8rs::TIMER_HZ;
+use c8rs::c8::{Chip8, Config, Quirks};
+use c8rs::framebuffer::Framebuffer;
+use c8rs::octo;
+use c8rs::timing::FixedClock;
+
+const BLESS: &str = "C8RS_BLESS";
+
+// A key going down or up at the start of a frame.
+struct Input {
+  frame: u32,
+  key: usize,
+  pressed: bool
+}
+
+fn press(frame: u32, key: usize) -> Input {
+  Input { frame, key, pressed: true }
+}
+
+fn release(frame: u32, key: usize) -> Input {
+  Input { frame, key, pressed: false }
+}
+
+struct Golden<'a> {
+  name: &'a str,
+  rom: Vec<u8>,
+  quirks: Quirks,
+  frames: u32,
+  inputs: Vec<Input>
+}
+
+impl Golden<'_> {
+  // Runs the ROM the way the emulator does at its default speed, and
+  // returns the display after the last frame.
+  fn run(&self) -> Framebuffer {
+    let mut chip8 = Chip8::with_config(Config { quirks: self.quirks, seed: Some(0), ..Config::default() });
+    chip8.load_bytes(&self.rom);
+
+    let mut keys = [false; 16];
+    let mut clock = FixedClock::default();
+    for frame in 0..self.frames {
+      for input in self.inputs.iter().filter(|input| input.frame == frame) {
+        keys[input.key] = input.pressed;
+      }
+
+      loop {
+        let (frames, timer_ticks) = clock.run(chip8.fde_loop(&keys) as u64, TIMER_HZ);
+        for _ in 0..timer_ticks {
+          chip8.update_timers();
+        }
+        if frames > 0 {
+          break;
+        }
+      }
+      chip8.vblank();
+    }
+    *chip8.get_display()
+  }
+
+  fn check(&self) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.txt", self.name));
+    let actual = self.run().to_text();
+
+    if env::var_os(BLESS).is_some() {
+      fs::write(&path, &actual).unwrap();
+      return;
+    }
+    let expected = fs::read_to_string(&path)
+      .unwrap_or_else(|e| panic!("Couldn't read {}: {} (run with {}=1 to create it)", path.display(), e, BLESS));
+    if actual != expected {
+      // The lines that differ, marked, so the picture is still readable.
+      let diff: Vec<String> = expected.lines().zip(actual.lines())
+        .map(|(expected, actual)| {
+          if expected == actual { format!("  {}", actual) } else { format!("- {}\n+ {}", expected, actual) }
+        })
+        .collect();
+      panic!("{} doesn't look like {}:\n{}\n(run with {}=1 if that's expected)", self.name, path.display(), diff.join("\n"), BLESS);
+    }
+  }
+}
+
+fn rom(name: &str) -> Option<Vec<u8>> {
+  let path: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms").join(name);
+  fs::read(path).ok()
+}
+
+#[test]
+fn ibm_logo() {
+  Golden {
+    name: "ibm_logo",
+    rom: rom("ibm_logo.ch8").unwrap(),
+    quirks: Quirks::default(),
+    frames: 10,
+    inputs: vec![]
+  }.check();
+}
+
+// An opcode test in the spirit of corax89's chip8-test-rom, assembled from
+// tests/roms/opcodes.8o. Every test has to show a tick, not a cross.
+#[test]
+fn opcodes() {
+  let source = fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms/opcodes.8o")).unwrap();
+  Golden {
+    name: "opcodes",
+    rom: octo::assemble(&source).unwrap(),
+    quirks: Quirks::default(),
+    frames: 300,
+    inputs: vec![]
+  }.check();
+}
+
+#[test]
+fn keypad() {
+  Golden {
+    name: "keypad",
+    rom: vec![
+      0xF0, 0x0A, // 200: Wait for a key, in V0
+      0xF0, 0x29, // 202: I = the digit for V0
+      0x61, 0x1C, // 204: V1 = 28
+      0x62, 0x0D, // 206: V2 = 13
+      0x00, 0xE0, // 208: Clear the display
+      0xD1, 0x25, // 20A: Draw the digit
+      0x12, 0x00  // 20C: Jump to 200
+    ],
+    quirks: Quirks::default(),
+    frames: 30,
+    inputs: vec![press(5, 0xA), release(10, 0xA), press(20, 0x7), release(25, 0x7)]
+  }.check();
+}